@@ -25,38 +25,107 @@ pub fn system(attr: TokenStream, item: TokenStream) -> TokenStream {
     let func = parse_macro_input!(item as ItemFn);
     let fn_ident = func.sig.ident.clone();
     let mut override_name: Option<String> = None;
+    // #[system(parallel)] spreads leaf blocks over worker threads,
+    // #[system(parallel, min_batch = N)] sets the entities per batch.
+    // #[system(job)] (implied by parallel) lets the `Executor` run the system on a worker,
+    // which needs both component types to be `Sync`.
+    let mut parallel = false;
+    let mut job = false;
+    let mut min_batch: Option<Expr> = None;
     if !attr.is_empty() {
-        let meta = parse_macro_input!(attr as Meta);
-        if let Meta::NameValue(nv) = meta {
-            if nv.path.is_ident("name") {
-                if let Expr::Lit(ExprLit { lit: Lit::Str(s), .. }) = nv.value {
-                    override_name = Some(s.value());
+        let metas = parse_macro_input!(attr with syn::punctuated::Punctuated::<Meta, syn::Token![,]>::parse_terminated);
+        for meta in metas {
+            match meta {
+                Meta::Path(path) if path.is_ident("parallel") => parallel = true,
+                Meta::Path(path) if path.is_ident("job") => job = true,
+                Meta::NameValue(nv) if nv.path.is_ident("name") => {
+                    if let Expr::Lit(ExprLit { lit: Lit::Str(s), .. }) = nv.value {
+                        override_name = Some(s.value());
+                    }
+                }
+                Meta::NameValue(nv) if nv.path.is_ident("min_batch") => min_batch = Some(nv.value),
+                other => {
+                    return syn::Error::new_spanned(other, "unsupported system attribute").to_compile_error().into();
                 }
             }
         }
     }
 
-    let mut arg_types: Vec<syn::Type> = Vec::new();
+    // Parameters are two `&View<T>`s walked as an intersection, and any number of
+    // `&mut EventReader<E>` / `&EventWriter<E>`. Without views the function runs once per run.
+    // Readers are only taken without views: with views the function runs once per leaf run
+    // (or not at all), so one reader can't be split fairly across the calls.
+    let mut views: Vec<syn::Type> = Vec::new();
+    let mut events: Vec<(bool, syn::Type)> = Vec::new();
+    let mut call_args: Vec<proc_macro2::TokenStream> = Vec::new();
+    let mut first_reader: Option<&Type> = None;
     for arg in func.sig.inputs.iter() {
-        if let FnArg::Typed(PatType { ty, .. }) = arg {
-            if let Type::Reference(TypeReference { elem, .. }) = &**ty {
-                if let Type::Path(TypePath { path, .. }) = &**elem {
+        let FnArg::Typed(PatType { ty, .. }) = arg else { continue };
+        let param = match &**ty {
+            Type::Reference(TypeReference { elem, mutability, .. }) => match &**elem {
+                Type::Path(TypePath { path, .. }) => {
                     let last = path.segments.last().expect("empty path");
-                    if last.ident == "View" {
-                        if let PathArguments::AngleBracketed(ab) = &last.arguments {
-                            if let Some(GenericArgument::Type(inner_ty)) = ab.args.first() {
-                                arg_types.push(inner_ty.clone());
-                            }
-                        }
-                    }
+                    let inner = match &last.arguments {
+                        PathArguments::AngleBracketed(ab) => match ab.args.first() {
+                            Some(GenericArgument::Type(inner_ty)) => Some(inner_ty.clone()),
+                            _ => None,
+                        },
+                        _ => None,
+                    };
+                    inner.map(|inner| (last.ident.to_string(), mutability.is_some(), inner))
                 }
+                _ => None,
+            },
+            _ => None,
+        };
+        match param {
+            Some((kind, false, inner)) if kind == "View" => {
+                let view = [format_ident!("a_leaf_view"), format_ident!("b_leaf_view")].get(views.len()).cloned();
+                views.push(inner);
+                call_args.push(quote! { &#view });
+            }
+            Some((kind, true, inner)) if kind == "EventReader" => {
+                let guard = format_ident!("ev{}_guard", events.len());
+                first_reader.get_or_insert(&**ty);
+                events.push((true, inner));
+                call_args.push(quote! { &mut *#guard });
+            }
+            Some((kind, false, inner)) if kind == "EventWriter" => {
+                let field = format_ident!("ev{}", events.len());
+                events.push((false, inner));
+                call_args.push(quote! { &self.#field });
+            }
+            _ => {
+                return syn::Error::new_spanned(ty, "#[system] parameters are &View<T>, &mut EventReader<E> or &EventWriter<E>")
+                    .to_compile_error()
+                    .into();
             }
         }
     }
 
-    assert!(arg_types.len() == 2, "#[system] expects function signature like fn f(a: &View<A>, b: &View<B>)");
-    let ty_a = arg_types.remove(0);
-    let ty_b = arg_types.remove(0);
+    assert!(
+        views.is_empty() || views.len() == 2,
+        "#[system] expects function signature like fn f(a: &View<A>, b: &View<B>)"
+    );
+    if parallel && (views.is_empty() || !events.is_empty()) {
+        return syn::Error::new_spanned(&func.sig, "#[system(parallel)] takes exactly two &View<T> parameters")
+            .to_compile_error()
+            .into();
+    }
+
+    if job && (views.is_empty() || !events.is_empty()) {
+        return syn::Error::new_spanned(&func.sig, "#[system(job)] takes exactly two &View<T> parameters")
+            .to_compile_error()
+            .into();
+    }
+    if let (false, Some(reader)) = (views.is_empty(), first_reader) {
+        return syn::Error::new_spanned(
+            reader,
+            "#[system] with &View<T> parameters runs once per leaf run; read events in a separate system",
+        )
+        .to_compile_error()
+        .into();
+    }
 
     let struct_ident = match override_name {
         Some(n) => format_ident!("{}", n),
@@ -66,25 +135,216 @@ pub fn system(attr: TokenStream, item: TokenStream) -> TokenStream {
         }
     };
 
+    let event_fields: Vec<proc_macro2::TokenStream> = events
+        .iter()
+        .enumerate()
+        .map(|(i, (reader, ty))| {
+            let field = format_ident!("ev{}", i);
+            if *reader {
+                quote! { #field: std::cell::RefCell<crate::world::EventReader<#ty>>, }
+            } else {
+                quote! { #field: crate::world::EventWriter<#ty>, }
+            }
+        })
+        .collect();
+    let event_inits: Vec<proc_macro2::TokenStream> = events
+        .iter()
+        .enumerate()
+        .map(|(i, (reader, ty))| {
+            let field = format_ident!("ev{}", i);
+            if *reader {
+                quote! { #field: std::cell::RefCell::new(world.event_reader::<#ty>()), }
+            } else {
+                quote! { #field: world.event_writer::<#ty>(), }
+            }
+        })
+        .collect();
+    let event_guards: Vec<proc_macro2::TokenStream> = events
+        .iter()
+        .enumerate()
+        .filter(|(_, (reader, _))| *reader)
+        .map(|(i, _)| {
+            let field = format_ident!("ev{}", i);
+            let guard = format_ident!("ev{}_guard", i);
+            quote! { let mut #guard = self.#field.borrow_mut(); }
+        })
+        .collect();
+    let read_events = events.iter().filter(|(reader, _)| *reader).map(|(_, ty)| ty);
+    let write_events = events.iter().filter(|(reader, _)| !*reader).map(|(_, ty)| ty);
+    let access = quote! {
+        // views and event readers read, event writers write; events are keyed by
+        // Events<E> so they never clash with a component of the same type
+        fn reads(&self) -> &'static [std::any::TypeId] {
+            static IDS: std::sync::LazyLock<Vec<std::any::TypeId>> = std::sync::LazyLock::new(|| vec![
+                #(std::any::TypeId::of::<#views>(),)*
+                #(std::any::TypeId::of::<crate::world::Events<#read_events>>(),)*
+            ]);
+            &IDS
+        }
+
+        fn writes(&self) -> &'static [std::any::TypeId] {
+            static IDS: std::sync::LazyLock<Vec<std::any::TypeId>> = std::sync::LazyLock::new(|| vec![
+                #(std::any::TypeId::of::<crate::world::Events<#write_events>>(),)*
+            ]);
+            &IDS
+        }
+    };
+
+    // the struct is generic over the world allocator, so `type_name` would list it
+    let name = quote! {
+        fn name(&self) -> &'static str {
+            concat!(module_path!(), "::", stringify!(#struct_ident))
+        }
+    };
+
+    if views.is_empty() {
+        let expanded = quote! {
+            #func
+
+            pub struct #struct_ident<A: std::alloc::Allocator + Copy + 'static = std::alloc::Global, LeafAlloc: std::alloc::Allocator + Copy + 'static = A> {
+                #(#event_fields)*
+                world: std::marker::PhantomData<fn() -> (A, LeafAlloc)>,
+            }
+
+            impl<A: std::alloc::Allocator + Copy + 'static, LeafAlloc: std::alloc::Allocator + Copy + 'static> #struct_ident<A, LeafAlloc> {
+                pub fn new(world: &mut crate::world::World<A, LeafAlloc>) -> Self {
+                    Self { #(#event_inits)* world: std::marker::PhantomData }
+                }
+            }
+
+            impl<A: std::alloc::Allocator + Copy + 'static, LeafAlloc: std::alloc::Allocator + Copy + 'static> crate::system::system::System for #struct_ident<A, LeafAlloc> {}
+
+            impl<A: std::alloc::Allocator + Copy + 'static, LeafAlloc: std::alloc::Allocator + Copy + 'static> crate::scheduler::PipelineStage for #struct_ident<A, LeafAlloc> {
+                fn run(&self) {
+                    #(#event_guards)*
+                    #fn_ident(#(#call_args),*);
+                }
+
+                #name
+
+                #access
+            }
+        };
+        return TokenStream::from(expanded);
+    }
+    let ty_a = &views[0];
+    let ty_b = &views[1];
+
+    let body = if parallel {
+        let config = match min_batch {
+            Some(n) => quote! { crate::query::Parallel::default().min_batch(#n) },
+            None => quote! { crate::query::Parallel::default() },
+        };
+        quote! {
+            let mut leaves = Vec::new();
+            for (a_l1_view, b_l1_view) in intersect(a_store.views(), b_store.views()) {
+                for (a_l1_block, b_l1_block) in a_l1_view.as_slice().iter().zip(b_l1_view.as_slice().iter()) {
+                    if instrument {
+                        stats.visit(1, a_l1_block.presence_mask, a_l1_block.presence_mask & b_l1_block.presence_mask);
+                    }
+                    for (a_l2_view, b_l2_view) in intersect(a_l1_block.views(), b_l1_block.views()) {
+                        for (a_leaf, b_leaf) in a_l2_view.as_slice().iter().zip(b_l2_view.as_slice().iter()) {
+                            let mask = a_leaf.presence_mask & b_leaf.presence_mask;
+                            if instrument {
+                                stats.visit(2, mask, mask);
+                                stats.leaf(mask);
+                            }
+                            let shared = (crate::query::par::SharedBlock::leaf(&**a_leaf), crate::query::par::SharedBlock::leaf(&**b_leaf));
+                            leaves.push((shared, mask.count_ones() as usize));
+                        }
+                    }
+                }
+            }
+            #config.run(leaves, |(a_leaf, b_leaf)| {
+                for (a_leaf_view, b_leaf_view) in intersect(a_leaf.get().views(), b_leaf.get().views()) {
+                    #fn_ident(#(#call_args),*);
+                }
+            });
+        }
+    } else {
+        quote! {
+            for (a_l1_view, b_l1_view) in intersect(a_store.views(), b_store.views()) {
+                let a_l1_slice = a_l1_view.as_slice();
+                let b_l1_slice = b_l1_view.as_slice();
+                for (a_l1_block, b_l1_block) in a_l1_slice.iter().zip(b_l1_slice.iter()) {
+                    if instrument {
+                        stats.visit(1, a_l1_block.presence_mask, a_l1_block.presence_mask & b_l1_block.presence_mask);
+                    }
+                    for (a_l2_view, b_l2_view) in intersect(a_l1_block.views(), b_l1_block.views()) {
+                        let a_l2_slice = a_l2_view.as_slice();
+                        let b_l2_slice = b_l2_view.as_slice();
+                        for (a_l2_block, b_l2_block) in a_l2_slice.iter().zip(b_l2_slice.iter()) {
+                            if instrument {
+                                let mask = a_l2_block.presence_mask & b_l2_block.presence_mask;
+                                stats.visit(2, mask, mask);
+                                stats.leaf(mask);
+                            }
+                            for (a_leaf_view, b_leaf_view) in intersect(a_l2_block.views(), b_l2_block.views()) {
+                                #fn_ident(#(#call_args),*);
+                            }
+                        }
+                    }
+                }
+            }
+        }
+    };
+
+    // Opted-in systems only hold storages, which the job reads through `SharedBlock`s
+    // borrowed here; the others keep the default and run on the calling thread.
+    let with_job = if parallel || job {
+        quote! {
+            fn with_job(&self, submit: &mut dyn FnMut(Option<&(dyn Fn() + Sync)>)) {
+                use crate::view::iter::{IterViews, intersect};
+                let a_cell = self.a.borrow();
+                let b_cell = self.b.borrow();
+                let a_root = crate::query::par::SharedBlock::root(&*a_cell);
+                let b_root = crate::query::par::SharedBlock::root(&*b_cell);
+                let instrument = self.instrumentation.borrow().is_enabled();
+                let walked = std::sync::Mutex::new(crate::query::QueryStats::default());
+                submit(Some(&|| {
+                    let a_store = a_root.get();
+                    let b_store = b_root.get();
+                    let mut stats = crate::query::QueryStats::default();
+                    if instrument {
+                        stats.visit(0, a_store.presence_mask, a_store.presence_mask & b_store.presence_mask);
+                    }
+
+                    #body
+
+                    *walked.lock().unwrap() = stats;
+                }));
+                if instrument {
+                    let stats = walked.into_inner().unwrap();
+                    self.instrumentation.borrow_mut().record(crate::scheduler::PipelineStage::name(self), &stats);
+                }
+            }
+        }
+    } else {
+        quote! {}
+    };
+
     let expanded = quote! {
         #func
 
-        pub struct #struct_ident {
-            a: std::rc::Rc<std::cell::RefCell<crate::storage::storage::SparseStorage<#ty_a, std::alloc::Global>>>,
-            b: std::rc::Rc<std::cell::RefCell<crate::storage::storage::SparseStorage<#ty_b, std::alloc::Global>>>,
+        pub struct #struct_ident<A: std::alloc::Allocator + Copy + 'static = std::alloc::Global, LeafAlloc: std::alloc::Allocator + Copy + 'static = A> {
+            a: std::rc::Rc<std::cell::RefCell<crate::storage::storage::SparseStorage<#ty_a, A, LeafAlloc>>>,
+            b: std::rc::Rc<std::cell::RefCell<crate::storage::storage::SparseStorage<#ty_b, A, LeafAlloc>>>,
+            instrumentation: std::rc::Rc<std::cell::RefCell<crate::query::stats::Instrumentation>>,
+            #(#event_fields)*
         }
 
-        impl #struct_ident {
-            pub fn new(world: &mut crate::world::World) -> Self {
+        impl<A: std::alloc::Allocator + Copy + 'static, LeafAlloc: std::alloc::Allocator + Copy + 'static> #struct_ident<A, LeafAlloc> {
+            pub fn new(world: &mut crate::world::World<A, LeafAlloc>) -> Self {
                 let a = world.get::<#ty_a>();
                 let b = world.get::<#ty_b>();
-                Self { a, b }
+                let instrumentation = world.instrumentation();
+                Self { a, b, instrumentation, #(#event_inits)* }
             }
         }
 
-        impl crate::system::system::System for #struct_ident {}
+        impl<A: std::alloc::Allocator + Copy + 'static, LeafAlloc: std::alloc::Allocator + Copy + 'static> crate::system::system::System for #struct_ident<A, LeafAlloc> {}
 
-        impl crate::scheduler::PipelineStage for #struct_ident {
+        impl<A: std::alloc::Allocator + Copy + 'static, LeafAlloc: std::alloc::Allocator + Copy + 'static> crate::scheduler::PipelineStage for #struct_ident<A, LeafAlloc> {
             fn run(&self) {
                 use crate::view::iter::{IterViews, intersect};
                 let a_cell = self.a.borrow();
@@ -92,22 +352,25 @@ pub fn system(attr: TokenStream, item: TokenStream) -> TokenStream {
                 let a_store = &a_cell.root;
                 let b_store = &b_cell.root;
 
-                for (a_l1_view, b_l1_view) in intersect(a_store.views(), b_store.views()) {
-                    let a_l1_slice = a_l1_view.as_slice();
-                    let b_l1_slice = b_l1_view.as_slice();
-                    for (a_l1_block, b_l1_block) in a_l1_slice.iter().zip(b_l1_slice.iter()) {
-                        for (a_l2_view, b_l2_view) in intersect(a_l1_block.views(), b_l1_block.views()) {
-                            let a_l2_slice = a_l2_view.as_slice();
-                            let b_l2_slice = b_l2_view.as_slice();
-                            for (a_l2_block, b_l2_block) in a_l2_slice.iter().zip(b_l2_slice.iter()) {
-                                for (a_leaf_view, b_leaf_view) in intersect(a_l2_block.views(), b_l2_block.views()) {
-                                    #fn_ident(&a_leaf_view, &b_leaf_view);
-                                }
-                            }
-                        }
-                    }
+                // #[system] walks are only counted while the world is instrumented
+                let instrument = self.instrumentation.borrow().is_enabled();
+                let mut stats = crate::query::QueryStats::default();
+                if instrument {
+                    stats.visit(0, a_store.presence_mask, a_store.presence_mask & b_store.presence_mask);
+                }
+
+                #body
+
+                if instrument {
+                    self.instrumentation.borrow_mut().record(crate::scheduler::PipelineStage::name(self), &stats);
                 }
             }
+
+            #with_job
+
+            #name
+
+            #access
         }
     };
 
@@ -130,15 +393,152 @@ pub fn derive_component(_attr: TokenStream, item: TokenStream) -> TokenStream {
     TokenStream::from(expanded)
 }
 
-#[proc_macro_derive(Component)]
+#[proc_macro_derive(Component, attributes(component))]
 pub fn derive_component_trait(input: TokenStream) -> TokenStream {
     let ast = parse_macro_input!(input as DeriveInput);
     let ident = ast.ident;
     let generics = ast.generics;
     let (impl_generics, ty_generics, where_clause) = generics.split_for_impl();
 
+    // #[component(init)] pre-initializes slots with Default::default(),
+    // #[component(init = path)] with path(index).
+    // #[component(on_insert = path, on_replace = path, on_remove = path)] install lifecycle
+    // hooks, each called as path(&mut self, entity, commands).
+    let mut init: Option<proc_macro2::TokenStream> = None;
+    let mut hooks: Vec<(syn::Ident, syn::Path)> = Vec::new();
+    for attr in ast.attrs.iter().filter(|a| a.path().is_ident("component")) {
+        let parsed = attr.parse_nested_meta(|meta| {
+            if meta.path.is_ident("init") {
+                if meta.input.peek(syn::Token![=]) {
+                    let path: syn::Path = meta.value()?.parse()?;
+                    init = Some(quote! { #path(index) });
+                } else {
+                    init = Some(quote! { <Self as ::core::default::Default>::default() });
+                }
+                Ok(())
+            } else if ["on_insert", "on_replace", "on_remove"].iter().any(|hook| meta.path.is_ident(hook)) {
+                let name = meta.path.get_ident().cloned().unwrap();
+                let path: syn::Path = meta.value()?.parse()?;
+                hooks.push((name, path));
+                Ok(())
+            } else {
+                Err(meta.error("unsupported component attribute"))
+            }
+        });
+        if let Err(err) = parsed {
+            return err.to_compile_error().into();
+        }
+    }
+
+    let mut body = match init {
+        Some(value) => quote! {
+            const PREINIT: bool = true;
+
+            #[inline(always)]
+            fn init(index: u32) -> ::core::mem::MaybeUninit<Self> {
+                ::core::mem::MaybeUninit::new(#value)
+            }
+        },
+        None => quote! {},
+    };
+    if !hooks.is_empty() {
+        body.extend(quote! { const HOOKS: bool = true; });
+    }
+    for (name, path) in hooks {
+        body.extend(quote! {
+            fn #name<HookAlloc: ::std::alloc::Allocator + Copy + 'static, HookLeafAlloc: ::std::alloc::Allocator + Copy + 'static>(
+                &mut self,
+                entity: crate::world::Entity,
+                commands: &mut crate::world::Commands<HookAlloc, HookLeafAlloc>,
+            ) {
+                #path(self, entity, commands)
+            }
+        });
+    }
+
+    let expanded = quote! {
+        impl #impl_generics crate::component::Component for #ident #ty_generics #where_clause { #body }
+    };
+    TokenStream::from(expanded)
+}
+
+#[proc_macro_derive(Bundle)]
+pub fn derive_bundle(input: TokenStream) -> TokenStream {
+    let ast = parse_macro_input!(input as DeriveInput);
+    let ident = ast.ident;
+    let fields = match ast.data {
+        syn::Data::Struct(s) => s.fields,
+        _ => {
+            return syn::Error::new_spanned(&ident, "#[derive(Bundle)] supports structs only")
+                .to_compile_error()
+                .into();
+        }
+    };
+    if fields.is_empty() {
+        return syn::Error::new_spanned(&ident, "#[derive(Bundle)] needs at least one field")
+            .to_compile_error()
+            .into();
+    }
+
+    let types: Vec<&syn::Type> = fields.iter().map(|f| &f.ty).collect();
+    let members: Vec<syn::Member> = fields
+        .iter()
+        .enumerate()
+        .map(|(i, f)| match &f.ident {
+            Some(name) => syn::Member::Named(name.clone()),
+            None => syn::Member::Unnamed(i.into()),
+        })
+        .collect();
+    let positions: Vec<syn::Index> = (0..fields.len()).map(syn::Index::from).collect();
+    let tuple = quote! { (#(#types,)*) };
+
     let expanded = quote! {
-        impl #impl_generics crate::component::Component for #ident #ty_generics #where_clause {}
+        impl crate::component::Bundle for #ident {
+            type Storages<A: std::alloc::Allocator + Copy + 'static, LeafAlloc: std::alloc::Allocator + Copy + 'static> = <#tuple as crate::component::Bundle>::Storages<A, LeafAlloc>;
+
+            fn fetch<A: std::alloc::Allocator + Copy + 'static, LeafAlloc: std::alloc::Allocator + Copy + 'static>(world: &mut crate::world::World<A, LeafAlloc>) -> Self::Storages<A, LeafAlloc> {
+                <#tuple as crate::component::Bundle>::fetch(world)
+            }
+
+            fn try_reserve<A: std::alloc::Allocator + Copy + 'static, LeafAlloc: std::alloc::Allocator + Copy + 'static>(storages: &Self::Storages<A, LeafAlloc>, index: u32) -> Result<(), std::alloc::AllocError> {
+                <#tuple as crate::component::Bundle>::try_reserve(storages, index)
+            }
+
+            fn trim<A: std::alloc::Allocator + Copy + 'static, LeafAlloc: std::alloc::Allocator + Copy + 'static>(storages: &Self::Storages<A, LeafAlloc>, index: u32) {
+                <#tuple as crate::component::Bundle>::trim(storages, index)
+            }
+
+            fn write<A: std::alloc::Allocator + Copy + 'static, LeafAlloc: std::alloc::Allocator + Copy + 'static>(self, storages: &Self::Storages<A, LeafAlloc>, index: u32) {
+                <#tuple as crate::component::Bundle>::write((#(self.#members,)*), storages, index)
+            }
+
+            fn reserve_range<A: std::alloc::Allocator + Copy + 'static, LeafAlloc: std::alloc::Allocator + Copy + 'static>(storages: &Self::Storages<A, LeafAlloc>, range: std::ops::Range<u32>) {
+                <#tuple as crate::component::Bundle>::reserve_range(storages, range)
+            }
+
+            fn write_block<A: std::alloc::Allocator + Copy + 'static, LeafAlloc: std::alloc::Allocator + Copy + 'static, I: Iterator<Item = Self>>(
+                storages: &Self::Storages<A, LeafAlloc>,
+                base: u32,
+                slots: std::ops::Range<usize>,
+                items: &mut I,
+            ) -> usize {
+                let mut tuples = items.map(|bundle| (#(bundle.#members,)*));
+                <#tuple as crate::component::Bundle>::write_block(storages, base, slots, &mut tuples)
+            }
+
+            unsafe fn commit<A: std::alloc::Allocator + Copy + 'static, LeafAlloc: std::alloc::Allocator + Copy + 'static>(storages: &Self::Storages<A, LeafAlloc>, base: u32, mask: u128) {
+                unsafe { <#tuple as crate::component::Bundle>::commit(storages, base, mask) }
+            }
+
+            unsafe fn commit_range<A: std::alloc::Allocator + Copy + 'static, LeafAlloc: std::alloc::Allocator + Copy + 'static>(storages: &Self::Storages<A, LeafAlloc>, range: std::ops::Range<u32>) {
+                unsafe { <#tuple as crate::component::Bundle>::commit_range(storages, range) }
+            }
+
+            fn take<A: std::alloc::Allocator + Copy + 'static, LeafAlloc: std::alloc::Allocator + Copy + 'static>(storages: &Self::Storages<A, LeafAlloc>, index: u32) -> Option<Self> {
+                let taken = <#tuple as crate::component::Bundle>::take(storages, index)?;
+                Some(Self { #(#members: taken.#positions,)* })
+            }
+        }
     };
     TokenStream::from(expanded)
 }
@@ -0,0 +1,191 @@
+use std::alloc::{AllocError, Allocator};
+use std::any::TypeId;
+use std::cell::RefCell;
+use std::ops::Range;
+use std::rc::Rc;
+
+use crate::component::Component;
+use crate::storage::storage::SparseStorage;
+use crate::world::World;
+
+/// A group of components inserted and removed together.
+/// Storages are fetched once per operation and presence bits are committed once per
+/// leaf block instead of once per component. Implemented for tuples of components,
+/// single components and `#[derive(Bundle)]` structs.
+pub trait Bundle: Sized + 'static {
+    type Storages<A: Allocator + Copy + 'static, L: Allocator + Copy + 'static>;
+
+    /// Panics if the bundle holds the same component type twice.
+    fn fetch<A: Allocator + Copy + 'static, L: Allocator + Copy + 'static>(world: &mut World<A, L>) -> Self::Storages<A, L>;
+
+    /// Reserve the leaf block covering `index` in every storage.
+    /// On failure nothing stays reserved.
+    fn try_reserve<A: Allocator + Copy + 'static, L: Allocator + Copy + 'static>(storages: &Self::Storages<A, L>, index: u32) -> Result<(), AllocError>;
+
+    /// Undo `try_reserve` for a block that received no commit.
+    fn trim<A: Allocator + Copy + 'static, L: Allocator + Copy + 'static>(storages: &Self::Storages<A, L>, index: u32);
+
+    /// Write every component into its reserved slot, leaving the masks untouched.
+    fn write<A: Allocator + Copy + 'static, L: Allocator + Copy + 'static>(self, storages: &Self::Storages<A, L>, index: u32);
+
+    /// Reserve the leaf blocks covering `range` in every storage.
+    fn reserve_range<A: Allocator + Copy + 'static, L: Allocator + Copy + 'static>(storages: &Self::Storages<A, L>, range: Range<u32>);
+
+    /// Write one item per slot of `slots` in the reserved leaf block containing `base`,
+    /// borrowing each storage and resolving each leaf once for the whole run. Stops early
+    /// if `items` runs out; returns the number of slots written.
+    fn write_block<A: Allocator + Copy + 'static, L: Allocator + Copy + 'static, I: Iterator<Item = Self>>(
+        storages: &Self::Storages<A, L>,
+        base: u32,
+        slots: Range<usize>,
+        items: &mut I,
+    ) -> usize;
+
+    /// Mark `mask` present in the leaf block containing `base`, in every storage.
+    ///
+    /// # Safety
+    /// Every slot in `mask` must have been written by `write`.
+    unsafe fn commit<A: Allocator + Copy + 'static, L: Allocator + Copy + 'static>(storages: &Self::Storages<A, L>, base: u32, mask: u128);
+
+    /// Mark every slot of `range` present in every storage, updating parents once per
+    /// L1 block.
+    ///
+    /// # Safety
+    /// Every slot in `range` must have been written by `write_block`.
+    unsafe fn commit_range<A: Allocator + Copy + 'static, L: Allocator + Copy + 'static>(storages: &Self::Storages<A, L>, range: Range<u32>);
+
+    /// Remove every component at `index` and return them as the bundle. If any of them
+    /// is missing nothing is removed.
+    fn take<A: Allocator + Copy + 'static, L: Allocator + Copy + 'static>(storages: &Self::Storages<A, L>, index: u32) -> Option<Self>;
+}
+
+macro_rules! impl_bundle {
+    ($(($C:ident, $i:tt)),+) => {
+        impl<$($C: Component),+> Bundle for ($($C,)+) {
+            type Storages<A: Allocator + Copy + 'static, L: Allocator + Copy + 'static> = ($(Rc<RefCell<SparseStorage<$C, A, L>>>,)+);
+
+            fn fetch<A: Allocator + Copy + 'static, L: Allocator + Copy + 'static>(world: &mut World<A, L>) -> Self::Storages<A, L> {
+                let ids = [$(TypeId::of::<$C>(),)+];
+                for (i, id) in ids.iter().enumerate() {
+                    assert!(!ids[..i].contains(id), "bundle {} holds a component type twice", std::any::type_name::<Self>());
+                }
+                ($(world.get::<$C>(),)+)
+            }
+
+            fn try_reserve<A: Allocator + Copy + 'static, L: Allocator + Copy + 'static>(storages: &Self::Storages<A, L>, index: u32) -> Result<(), AllocError> {
+                let reserve = || -> Result<(), AllocError> {
+                    $(storages.$i.borrow_mut().try_reserve(index)?;)+
+                    Ok(())
+                };
+                let result = reserve();
+                if result.is_err() {
+                    Self::trim(storages, index);
+                }
+                result
+            }
+
+            fn trim<A: Allocator + Copy + 'static, L: Allocator + Copy + 'static>(storages: &Self::Storages<A, L>, index: u32) {
+                $(storages.$i.borrow_mut().trim(index);)+
+            }
+
+            fn write<A: Allocator + Copy + 'static, L: Allocator + Copy + 'static>(self, storages: &Self::Storages<A, L>, index: u32) {
+                $(storages.$i.borrow_mut().write_slot(index, self.$i);)+
+            }
+
+            fn reserve_range<A: Allocator + Copy + 'static, L: Allocator + Copy + 'static>(storages: &Self::Storages<A, L>, range: Range<u32>) {
+                $(storages.$i.borrow_mut().reserve_range(range.clone());)+
+            }
+
+            fn write_block<A: Allocator + Copy + 'static, L: Allocator + Copy + 'static, I: Iterator<Item = Self>>(
+                storages: &Self::Storages<A, L>,
+                base: u32,
+                slots: Range<usize>,
+                items: &mut I,
+            ) -> usize {
+                let mut guards = ($(storages.$i.borrow_mut(),)+);
+                let leaves = ($(guards.$i.leaf_block_mut(base).expect("write_block on an unreserved block"),)+);
+                let mut written = 0;
+                for slot in slots {
+                    let Some(item) = items.next() else { break };
+                    $(leaves.$i.put(slot, item.$i);)+
+                    written += 1;
+                }
+                written
+            }
+
+            unsafe fn commit<A: Allocator + Copy + 'static, L: Allocator + Copy + 'static>(storages: &Self::Storages<A, L>, base: u32, mask: u128) {
+                $(unsafe { storages.$i.borrow_mut().commit(base, mask) };)+
+            }
+
+            unsafe fn commit_range<A: Allocator + Copy + 'static, L: Allocator + Copy + 'static>(storages: &Self::Storages<A, L>, range: Range<u32>) {
+                $(unsafe { storages.$i.borrow_mut().commit_range(range.clone()) };)+
+            }
+
+            fn take<A: Allocator + Copy + 'static, L: Allocator + Copy + 'static>(storages: &Self::Storages<A, L>, index: u32) -> Option<Self> {
+                // checked up front so a partial bundle stays where it is
+                if $(storages.$i.borrow().get_any(index).is_none())||+ {
+                    return None;
+                }
+                Some(($(storages.$i.borrow_mut().remove(index).unwrap(),)+))
+            }
+        }
+    };
+}
+
+impl_bundle!((C0, 0));
+impl_bundle!((C0, 0), (C1, 1));
+impl_bundle!((C0, 0), (C1, 1), (C2, 2));
+impl_bundle!((C0, 0), (C1, 1), (C2, 2), (C3, 3));
+impl_bundle!((C0, 0), (C1, 1), (C2, 2), (C3, 3), (C4, 4));
+impl_bundle!((C0, 0), (C1, 1), (C2, 2), (C3, 3), (C4, 4), (C5, 5));
+impl_bundle!((C0, 0), (C1, 1), (C2, 2), (C3, 3), (C4, 4), (C5, 5), (C6, 6));
+impl_bundle!((C0, 0), (C1, 1), (C2, 2), (C3, 3), (C4, 4), (C5, 5), (C6, 6), (C7, 7));
+impl_bundle!((C0, 0), (C1, 1), (C2, 2), (C3, 3), (C4, 4), (C5, 5), (C6, 6), (C7, 7), (C8, 8));
+impl_bundle!((C0, 0), (C1, 1), (C2, 2), (C3, 3), (C4, 4), (C5, 5), (C6, 6), (C7, 7), (C8, 8), (C9, 9));
+impl_bundle!((C0, 0), (C1, 1), (C2, 2), (C3, 3), (C4, 4), (C5, 5), (C6, 6), (C7, 7), (C8, 8), (C9, 9), (C10, 10));
+impl_bundle!((C0, 0), (C1, 1), (C2, 2), (C3, 3), (C4, 4), (C5, 5), (C6, 6), (C7, 7), (C8, 8), (C9, 9), (C10, 10), (C11, 11));
+
+impl<T: Component> Bundle for T {
+    type Storages<A: Allocator + Copy + 'static, L: Allocator + Copy + 'static> = <(T,) as Bundle>::Storages<A, L>;
+
+    fn fetch<A: Allocator + Copy + 'static, L: Allocator + Copy + 'static>(world: &mut World<A, L>) -> Self::Storages<A, L> {
+        <(T,)>::fetch(world)
+    }
+
+    fn try_reserve<A: Allocator + Copy + 'static, L: Allocator + Copy + 'static>(storages: &Self::Storages<A, L>, index: u32) -> Result<(), AllocError> {
+        <(T,)>::try_reserve(storages, index)
+    }
+
+    fn trim<A: Allocator + Copy + 'static, L: Allocator + Copy + 'static>(storages: &Self::Storages<A, L>, index: u32) {
+        <(T,)>::trim(storages, index)
+    }
+
+    fn write<A: Allocator + Copy + 'static, L: Allocator + Copy + 'static>(self, storages: &Self::Storages<A, L>, index: u32) {
+        (self,).write(storages, index)
+    }
+
+    fn reserve_range<A: Allocator + Copy + 'static, L: Allocator + Copy + 'static>(storages: &Self::Storages<A, L>, range: Range<u32>) {
+        <(T,)>::reserve_range(storages, range)
+    }
+
+    fn write_block<A: Allocator + Copy + 'static, L: Allocator + Copy + 'static, I: Iterator<Item = Self>>(
+        storages: &Self::Storages<A, L>,
+        base: u32,
+        slots: Range<usize>,
+        items: &mut I,
+    ) -> usize {
+        <(T,)>::write_block(storages, base, slots, &mut items.map(|value| (value,)))
+    }
+
+    unsafe fn commit<A: Allocator + Copy + 'static, L: Allocator + Copy + 'static>(storages: &Self::Storages<A, L>, base: u32, mask: u128) {
+        unsafe { <(T,)>::commit(storages, base, mask) }
+    }
+
+    unsafe fn commit_range<A: Allocator + Copy + 'static, L: Allocator + Copy + 'static>(storages: &Self::Storages<A, L>, range: Range<u32>) {
+        unsafe { <(T,)>::commit_range(storages, range) }
+    }
+
+    fn take<A: Allocator + Copy + 'static, L: Allocator + Copy + 'static>(storages: &Self::Storages<A, L>, index: u32) -> Option<Self> {
+        <(T,)>::take(storages, index).map(|(value,)| value)
+    }
+}
@@ -0,0 +1,44 @@
+use std::alloc::{Allocator, Global};
+use std::cell::RefCell;
+
+use crate::component::Component;
+use crate::world::{Commands, Entity};
+
+/// Where a storage sends the lifecycle hooks of its `T`s. Erases the allocator of the
+/// world whose `Commands` collect the hooks' work, which need not be the storage's own.
+pub trait HookSink<T: Component> {
+    fn on_insert(&self, value: &mut T, entity: Entity);
+    fn on_replace(&self, value: &mut T, entity: Entity);
+    fn on_remove(&self, value: &mut T, entity: Entity);
+}
+
+impl<T: Component, A: Allocator + Copy + 'static, L: Allocator + Copy + 'static> HookSink<T> for RefCell<Commands<A, L>> {
+    fn on_insert(&self, value: &mut T, entity: Entity) {
+        value.on_insert(entity, &mut self.borrow_mut());
+    }
+
+    fn on_replace(&self, value: &mut T, entity: Entity) {
+        value.on_replace(entity, &mut self.borrow_mut());
+    }
+
+    fn on_remove(&self, value: &mut T, entity: Entity) {
+        value.on_remove(entity, &mut self.borrow_mut());
+    }
+}
+
+/// Sink of storages outside a world: hooks still run, their commands are dropped.
+pub(crate) struct Discard;
+
+impl<T: Component> HookSink<T> for Discard {
+    fn on_insert(&self, value: &mut T, entity: Entity) {
+        value.on_insert(entity, &mut Commands::<Global>::new());
+    }
+
+    fn on_replace(&self, value: &mut T, entity: Entity) {
+        value.on_replace(entity, &mut Commands::<Global>::new());
+    }
+
+    fn on_remove(&self, value: &mut T, entity: Entity) {
+        value.on_remove(entity, &mut Commands::<Global>::new());
+    }
+}
@@ -1,10 +1,45 @@
+use std::alloc::Allocator;
 use std::mem::MaybeUninit;
 
+use crate::world::{Commands, Entity};
+
+mod bundle;
+pub(crate) mod hooks;
+
+pub use bundle::Bundle;
+pub use hooks::HookSink;
+
+/// Lifecycle hooks run inside the storage call that caused them, so they cannot touch the
+/// world directly; they queue follow-up work on `commands`, which a world-owned storage
+/// hands to the next `World::flush`. Moving an entity's components, as in defragmentation,
+/// and skipping values of disabled entities run no hooks.
 pub trait Component: Sized + 'static {
+    /// When true, `init` returns an initialized value for every index and storages
+    /// materialize all 128 slots of a leaf block as soon as it is allocated. Such slots
+    /// read as present until removed; inserting over one reports no previous value.
+    /// Despawning (or moving by `defragment`) leaves a slot as fresh as a never-used one.
+    const PREINIT: bool = false;
+
+    /// When false, storages never call the hooks below; set by the derive for
+    /// components declaring any of them.
+    const HOOKS: bool = false;
+
     #[inline(always)]
     fn init(index: u32) -> MaybeUninit<Self>{
         MaybeUninit::uninit()
     }
+
+    /// `self` was stored for `entity`, into an empty slot or over an old value.
+    fn on_insert<A: Allocator + Copy + 'static, L: Allocator + Copy + 'static>(&mut self, _entity: Entity, _commands: &mut Commands<A, L>) {}
+
+    /// `self` has just been overwritten for `entity` and is dropped after this hook; runs
+    /// before `on_insert` of the new value.
+    fn on_replace<A: Allocator + Copy + 'static, L: Allocator + Copy + 'static>(&mut self, _entity: Entity, _commands: &mut Commands<A, L>) {}
+
+    /// `self` leaves the storage: removed, discarded on despawn, or dropped with the
+    /// storage. Only `SparseStorage` runs it; a `SparseBlock` dropped on its own, outside a
+    /// storage, drops its values without this hook.
+    fn on_remove<A: Allocator + Copy + 'static, L: Allocator + Copy + 'static>(&mut self, _entity: Entity, _commands: &mut Commands<A, L>) {}
 }
 
 pub trait Tag: Component { }
@@ -19,5 +54,26 @@ mod tests {
     #[test]
     fn derive_component_impls_trait() {
         let _x = <E as Component>::init(0);
+        const { assert!(!E::PREINIT) };
+    }
+
+    fn seed(index: u32) -> Seed {
+        Seed(index.wrapping_mul(0x9E37_79B9))
+    }
+
+    #[derive(ercs_macros::Component)]
+    #[component(init = seed)]
+    struct Seed(u32);
+
+    #[derive(Default, ercs_macros::Component)]
+    #[component(init)]
+    struct Scale(u32);
+
+    #[test]
+    fn derive_component_init_attribute_enables_preinit() {
+        const { assert!(Seed::PREINIT) };
+        assert_eq!(unsafe { Seed::init(3).assume_init() }.0, 3u32.wrapping_mul(0x9E37_79B9));
+        const { assert!(Scale::PREINIT) };
+        assert_eq!(unsafe { Scale::init(3).assume_init() }.0, 0);
     }
 }
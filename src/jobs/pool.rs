@@ -0,0 +1,410 @@
+use std::any::Any;
+use std::cell::Cell;
+use std::collections::VecDeque;
+use std::marker::PhantomData;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Condvar, Mutex, OnceLock};
+use std::thread::{self, JoinHandle};
+
+use bumpalo::Bump;
+
+type Job = Box<dyn FnOnce(&JobContext) + Send + 'static>;
+
+/// What a job gets to see of the thread running it.
+pub struct JobContext<'a> {
+    arena: &'a Bump,
+}
+
+impl JobContext<'_> {
+    /// Bump arena for temporary data. A worker resets its arena after each top-level
+    /// job, so nothing allocated here may outlive the job.
+    pub fn arena(&self) -> &Bump {
+        self.arena
+    }
+}
+
+struct Shared {
+    injector: Mutex<VecDeque<Job>>,
+    deques: Vec<Mutex<VecDeque<Job>>>,
+    queued: AtomicUsize,
+    sleep: Mutex<()>,
+    wake: Condvar,
+    shutdown: AtomicBool,
+}
+
+thread_local! {
+    /// Pool and index of the worker running on this thread, with its arena.
+    static WORKER: Cell<Option<(*const Shared, usize, *const Bump)>> = const { Cell::new(None) };
+    /// Pool installed on this thread by `JobPool::install`.
+    static INSTALLED: Cell<Option<*const Shared>> = const { Cell::new(None) };
+}
+
+impl Shared {
+    fn push(self: &Arc<Self>, job: Job) {
+        match current_worker(self) {
+            Some((index, _)) => self.deques[index].lock().unwrap().push_back(job),
+            None => self.injector.lock().unwrap().push_back(job),
+        }
+        self.queued.fetch_add(1, Ordering::SeqCst);
+        let _guard = self.sleep.lock().unwrap();
+        self.wake.notify_one();
+    }
+
+    /// Own deque newest first, then the injector, then the oldest job of another worker.
+    fn find(&self, own: Option<usize>) -> Option<Job> {
+        let job = own
+            .and_then(|i| self.deques[i].lock().unwrap().pop_back())
+            .or_else(|| self.injector.lock().unwrap().pop_front())
+            .or_else(|| {
+                let start = own.map_or(0, |i| i + 1);
+                (0..self.deques.len())
+                    .map(|k| (start + k) % self.deques.len())
+                    .filter(|&i| Some(i) != own)
+                    .find_map(|i| self.deques[i].lock().unwrap().pop_front())
+            });
+        if job.is_some() {
+            self.queued.fetch_sub(1, Ordering::SeqCst);
+        }
+        job
+    }
+
+    /// Wake every thread parked on `wake`, including callers waiting in `help_until`.
+    /// Taking `sleep` first means a waiter either sees the change or is already parked.
+    fn notify_all(&self) {
+        let _guard = self.sleep.lock().unwrap();
+        self.wake.notify_all();
+    }
+
+    /// Run queued jobs on the calling thread until `done` holds, parking while there are
+    /// none. Whatever makes `done` true must be followed by `notify_all`.
+    fn help_until(&self, done: impl Fn() -> bool) {
+        let local = Bump::new();
+        let arena = match current_worker(self) {
+            // a job waiting from inside the pool: helped jobs share its arena, which is only
+            // reset once the outer job returns
+            Some((_, arena)) => unsafe { &*arena },
+            None => &local,
+        };
+        let worker = current_worker(self).map(|(index, _)| index);
+        while !done() {
+            if let Some(job) = self.find(worker) {
+                job(&JobContext { arena });
+                continue;
+            }
+            let guard = self.sleep.lock().unwrap();
+            if !done() && self.queued.load(Ordering::SeqCst) == 0 {
+                drop(self.wake.wait(guard).unwrap());
+            }
+        }
+    }
+}
+
+fn current_worker(shared: &Shared) -> Option<(usize, *const Bump)> {
+    WORKER.with(|w| w.get()).filter(|(pool, _, _)| std::ptr::eq(*pool, shared)).map(|(_, index, arena)| (index, arena))
+}
+
+fn worker_loop(shared: Arc<Shared>, index: usize) {
+    let mut arena = Bump::new();
+    WORKER.with(|w| w.set(Some((Arc::as_ptr(&shared), index, &arena as *const Bump))));
+    loop {
+        if let Some(job) = shared.find(Some(index)) {
+            job(&JobContext { arena: &arena });
+            arena.reset();
+            continue;
+        }
+        let guard = shared.sleep.lock().unwrap();
+        if shared.shutdown.load(Ordering::SeqCst) {
+            break;
+        }
+        if shared.queued.load(Ordering::SeqCst) == 0 {
+            drop(shared.wake.wait(guard).unwrap());
+        }
+    }
+    WORKER.with(|w| w.set(None));
+}
+
+/// Work-stealing job pool on plain std threads. Each worker keeps a deque of the jobs
+/// it spawned and steals from the others when it runs dry; jobs from outside the pool
+/// go through a shared injector queue. Idle workers park on a condition variable.
+pub struct JobPool {
+    shared: Arc<Shared>,
+    handles: Vec<JoinHandle<()>>,
+}
+
+impl JobPool {
+    pub fn new(threads: usize) -> Self {
+        let threads = threads.max(1);
+        let shared = Arc::new(Shared {
+            injector: Mutex::new(VecDeque::new()),
+            deques: (0..threads).map(|_| Mutex::new(VecDeque::new())).collect(),
+            queued: AtomicUsize::new(0),
+            sleep: Mutex::new(()),
+            wake: Condvar::new(),
+            shutdown: AtomicBool::new(false),
+        });
+        let handles = (0..threads)
+            .map(|index| {
+                let shared = shared.clone();
+                thread::Builder::new()
+                    .name(format!("ercs-worker-{}", index))
+                    .spawn(move || worker_loop(shared, index))
+                    .expect("failed to spawn job pool worker")
+            })
+            .collect();
+        Self { shared, handles }
+    }
+
+    /// Process-wide pool with one worker per available core, used by parallel queries
+    /// and the stage executor unless they are given or run in another pool. Its workers
+    /// are never joined, so tests install an owned pool instead.
+    pub fn global() -> &'static JobPool {
+        static GLOBAL: OnceLock<JobPool> = OnceLock::new();
+        GLOBAL.get_or_init(|| JobPool::new(thread::available_parallelism().map_or(1, |n| n.get())))
+    }
+
+    pub fn threads(&self) -> usize {
+        self.shared.deques.len()
+    }
+
+    /// Run `job` on the pool without waiting for it. A panic in the job is kept for
+    /// `JobHandle::join`.
+    pub fn spawn(&self, job: impl FnOnce(&JobContext) + Send + 'static) -> JobHandle {
+        let result: Arc<Mutex<Option<thread::Result<()>>>> = Arc::new(Mutex::new(None));
+        let (shared, slot) = (self.shared.clone(), result.clone());
+        self.shared.push(Box::new(move |ctx: &JobContext| {
+            let outcome = panic::catch_unwind(AssertUnwindSafe(|| job(ctx)));
+            *slot.lock().unwrap() = Some(outcome);
+            shared.notify_all();
+        }));
+        JobHandle { shared: self.shared.clone(), result }
+    }
+
+    /// Call `f` with a scope whose jobs may borrow from the caller, and return once all of
+    /// them are done. The calling thread runs queued jobs while it waits. The first
+    /// panic of `f` or of a job is resumed after the join.
+    pub fn scope<'s, R>(&self, f: impl FnOnce(&Scope<'s>) -> R) -> R {
+        scope_in(&self.shared, f)
+    }
+
+    /// Run `f` on the calling thread with this pool standing in for `global()`: parallel
+    /// queries and `#[system(parallel)]`s started inside use it.
+    pub fn install<R>(&self, f: impl FnOnce() -> R) -> R {
+        struct Restore(Option<*const Shared>);
+        impl Drop for Restore {
+            fn drop(&mut self) {
+                INSTALLED.with(|p| p.set(self.0));
+            }
+        }
+        let _restore = Restore(INSTALLED.with(|p| p.replace(Some(Arc::as_ptr(&self.shared)))));
+        f()
+    }
+
+    /// `scope` on the pool work started here belongs to: the pool of the worker running
+    /// the caller, else the one installed on this thread, else `global()`.
+    pub(crate) fn scope_current<'s, R>(f: impl FnOnce(&Scope<'s>) -> R) -> R {
+        let current = WORKER.with(|w| w.get()).map(|(pool, _, _)| pool).or_else(|| INSTALLED.with(|p| p.get()));
+        match current {
+            Some(pool) => {
+                // SAFETY: the pointer comes from `Arc::as_ptr` of a pool that stays alive while
+                // this thread runs one of its jobs or is inside its `install`
+                let shared = unsafe {
+                    Arc::increment_strong_count(pool);
+                    Arc::from_raw(pool)
+                };
+                scope_in(&shared, f)
+            }
+            None => Self::global().scope(f),
+        }
+    }
+}
+
+fn scope_in<'s, R>(shared: &Arc<Shared>, f: impl FnOnce(&Scope<'s>) -> R) -> R {
+    let state = Arc::new(ScopeState { pending: Mutex::new(0), panic: Mutex::new(None) });
+    let scope = Scope { shared: shared.clone(), state: state.clone(), _marker: PhantomData };
+    let result = panic::catch_unwind(AssertUnwindSafe(|| f(&scope)));
+    shared.help_until(|| *state.pending.lock().unwrap() == 0);
+
+    if let Some(payload) = state.panic.lock().unwrap().take() {
+        panic::resume_unwind(payload);
+    }
+    result.unwrap_or_else(|payload| panic::resume_unwind(payload))
+}
+
+/// Job started by `JobPool::spawn`.
+pub struct JobHandle {
+    shared: Arc<Shared>,
+    result: Arc<Mutex<Option<thread::Result<()>>>>,
+}
+
+impl JobHandle {
+    pub fn is_finished(&self) -> bool {
+        self.result.lock().unwrap().is_some()
+    }
+
+    /// Wait for the job, running queued jobs meanwhile, and return its panic if it had one.
+    pub fn join(self) -> thread::Result<()> {
+        self.shared.help_until(|| self.is_finished());
+        self.result.lock().unwrap().take().unwrap()
+    }
+}
+
+impl Drop for JobPool {
+    fn drop(&mut self) {
+        {
+            let _guard = self.shared.sleep.lock().unwrap();
+            self.shared.shutdown.store(true, Ordering::SeqCst);
+            self.shared.wake.notify_all();
+        }
+        for handle in self.handles.drain(..) {
+            let _ = handle.join();
+        }
+    }
+}
+
+struct ScopeState {
+    pending: Mutex<usize>,
+    panic: Mutex<Option<Box<dyn Any + Send>>>,
+}
+
+/// Handle for spawning jobs that borrow data living at least as long as `'s`.
+pub struct Scope<'s> {
+    shared: Arc<Shared>,
+    state: Arc<ScopeState>,
+    _marker: PhantomData<fn(&'s ()) -> &'s ()>,
+}
+
+impl<'s> Scope<'s> {
+    pub fn spawn(&self, job: impl FnOnce(&JobContext) + Send + 's) {
+        *self.state.pending.lock().unwrap() += 1;
+        let (shared, state) = (self.shared.clone(), self.state.clone());
+        let job: Box<dyn FnOnce(&JobContext) + Send + 's> = Box::new(move |ctx: &JobContext| {
+            if let Err(payload) = panic::catch_unwind(AssertUnwindSafe(|| job(ctx))) {
+                state.panic.lock().unwrap().get_or_insert(payload);
+            }
+            let mut pending = state.pending.lock().unwrap();
+            *pending -= 1;
+            let finished = *pending == 0;
+            drop(pending);
+            if finished {
+                shared.notify_all();
+            }
+        });
+        // SAFETY: `JobPool::scope` does not return before every job spawned here has run,
+        // so nothing the job borrows for `'s` is gone while it runs
+        let job: Job = unsafe { std::mem::transmute(job) };
+        self.shared.push(job);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn scope_joins_jobs_borrowing_local_data() {
+        let pool = JobPool::new(4);
+        let data: Vec<u64> = (0..10_000).collect();
+        let total = AtomicUsize::new(0);
+        pool.scope(|s| {
+            for chunk in data.chunks(1000) {
+                let total = &total;
+                s.spawn(move |_| {
+                    total.fetch_add(chunk.iter().sum::<u64>() as usize, Ordering::SeqCst);
+                });
+            }
+        });
+        assert_eq!(total.load(Ordering::SeqCst), (0..10_000).sum::<u64>() as usize);
+    }
+
+    #[test]
+    fn nested_scopes_do_not_deadlock_a_single_worker() {
+        let pool = JobPool::new(1);
+        let count = AtomicUsize::new(0);
+        pool.scope(|s| {
+            for _ in 0..4 {
+                s.spawn(|_| {
+                    pool.scope(|inner| {
+                        for _ in 0..4 {
+                            inner.spawn(|_| {
+                                count.fetch_add(1, Ordering::SeqCst);
+                            });
+                        }
+                    });
+                });
+            }
+        });
+        assert_eq!(count.load(Ordering::SeqCst), 16);
+    }
+
+    #[test]
+    fn jobs_get_a_worker_arena() {
+        let pool = JobPool::new(2);
+        let sums = Mutex::new(Vec::new());
+        pool.scope(|s| {
+            for n in 0..8u32 {
+                let sums = &sums;
+                s.spawn(move |ctx| {
+                    let scratch = ctx.arena().alloc_slice_fill_with(100, |i| i as u32 * n);
+                    sums.lock().unwrap().push(scratch.iter().sum::<u32>());
+                });
+            }
+        });
+        let mut sums = sums.into_inner().unwrap();
+        sums.sort();
+        assert_eq!(sums, (0..8).map(|n| 4950 * n).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn panics_are_resumed_after_the_join() {
+        let pool = JobPool::new(2);
+        let finished = AtomicUsize::new(0);
+        let result = panic::catch_unwind(AssertUnwindSafe(|| {
+            pool.scope(|s| {
+                s.spawn(|_| panic!("job failed"));
+                s.spawn(|_| {
+                    finished.fetch_add(1, Ordering::SeqCst);
+                });
+            })
+        }));
+        assert!(result.is_err());
+        assert_eq!(finished.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn standalone_spawn_runs_detached_jobs() {
+        let pool = JobPool::new(2);
+        let (tx, rx) = std::sync::mpsc::channel();
+        for i in 0..10 {
+            let tx = tx.clone();
+            pool.spawn(move |_| tx.send(i).unwrap());
+        }
+        let mut got: Vec<i32> = rx.iter().take(10).collect();
+        got.sort();
+        assert_eq!(got, (0..10).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn spawned_panics_come_back_from_join() {
+        let pool = JobPool::new(1);
+        assert!(pool.spawn(|_| panic!("job failed")).join().is_err());
+        assert!(pool.spawn(|_| {}).join().is_ok());
+    }
+
+    #[test]
+    fn installed_pools_run_scopes_started_inside() {
+        let pool = JobPool::new(2);
+        let ours = Arc::as_ptr(&pool.shared) as usize;
+        let seen = Mutex::new(Vec::new());
+        pool.install(|| {
+            JobPool::scope_current(|s| {
+                for _ in 0..8 {
+                    s.spawn(|_| seen.lock().unwrap().push(WORKER.with(|w| w.get()).map(|(p, _, _)| p as usize)));
+                }
+            })
+        });
+        // helped on the caller (no worker) or run by one of ours, never by the global pool
+        assert!(seen.into_inner().unwrap().iter().all(|p| p.is_none_or(|p| p == ours)));
+        assert!(INSTALLED.with(|p| p.get()).is_none());
+    }
+}
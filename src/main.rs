@@ -5,6 +5,8 @@ mod scheduler;
 mod view;
 mod storage;
 mod world;
+mod query;
+mod jobs;
 mod system;
 mod system_macro;
 mod tick;
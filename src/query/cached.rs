@@ -0,0 +1,121 @@
+use std::alloc::Allocator;
+use std::any::TypeId;
+use std::cell::RefCell;
+use std::rc::Rc;
+
+use crate::query::{Query, Term};
+use crate::storage::validate::BlockPath;
+use crate::world::{Entity, EntitySet, SetIter, World};
+
+/// A query whose matches are kept in an `EntitySet` between runs. The storages of its
+/// `With` and `Without` terms record the indices they change, and `update` only
+/// re-evaluates the leaf blocks those fall in, so iterating a query over mostly static
+/// data does not intersect the storages again.
+///
+/// Queries without a `With` term also depend on spawns and despawns, which no storage
+/// sees; they are rebuilt on every update.
+pub struct CachedQuery {
+    query: Query,
+    matches: EntitySet,
+    dirty: Rc<RefCell<EntitySet>>,
+    watched: Vec<TypeId>,
+    built: bool,
+}
+
+impl CachedQuery {
+    pub fn new(query: Query) -> Self {
+        Self { query, matches: EntitySet::new(), dirty: Rc::default(), watched: Vec::new(), built: false }
+    }
+
+    pub fn query(&self) -> &Query {
+        &self.query
+    }
+
+    /// Bring the cached matches in line with `world`. Storages created since the last
+    /// update are watched from now on and force a full rebuild.
+    pub fn update<A: Allocator + Copy + 'static, L: Allocator + Copy + 'static>(&mut self, world: &World<A, L>) {
+        self.update_with(world, |_, _, _| {});
+    }
+
+    /// `update`, calling `changed(base, old, new)` for every leaf block whose mask changed.
+    pub fn update_with<A, L, F>(&mut self, world: &World<A, L>, mut changed: F)
+    where
+        A: Allocator + Copy + 'static, L: Allocator + Copy + 'static,
+        F: FnMut(u32, u128, u128),
+    {
+        let mut rebuild = !self.built;
+        let mut has_with = false;
+        for term in self.query.terms() {
+            let id = match term {
+                Term::With(id) => {
+                    has_with = true;
+                    *id
+                }
+                Term::Without(id) => *id,
+                _ => continue,
+            };
+            if self.watched.contains(&id) {
+                continue;
+            }
+            match world.storage_dyn(id) {
+                Some(storage) => {
+                    storage.borrow_mut().watch(Rc::downgrade(&self.dirty));
+                    self.watched.push(id);
+                    rebuild = true;
+                }
+                // a missing `With` storage matches nothing until it shows up
+                None => rebuild |= matches!(term, Term::With(_)),
+            }
+        }
+        if rebuild || !has_with {
+            self.dirty.borrow_mut().clear();
+            let old = std::mem::take(&mut self.matches);
+            for (base, mask) in self.query.blocks(world) {
+                self.matches.set_block(base, mask);
+            }
+            let mut bases = old.union(&self.matches);
+            while let Some((base, _)) = bases.next_block() {
+                let path = BlockPath { level: 2, base };
+                let (was, now) = (old.masks(path), self.matches.masks(path));
+                if was != now {
+                    changed(base, was, now);
+                }
+            }
+            self.built = true;
+            return;
+        }
+        let dirty = std::mem::take(&mut *self.dirty.borrow_mut());
+        if dirty.is_empty() {
+            return;
+        }
+        let mut blocks = self.query.blocks(world);
+        for (base, _) in dirty.blocks() {
+            let now = blocks.leaf_mask(base);
+            let was = self.matches.set_block(base, now);
+            if was != now {
+                changed(base, was, now);
+            }
+        }
+    }
+
+    /// Matching entities as `(base, mask)` per leaf block, read from the cache.
+    pub fn blocks<A: Allocator + Copy + 'static, L: Allocator + Copy + 'static>(&mut self, world: &World<A, L>) -> impl Iterator<Item = (u32, u128)> + '_ {
+        self.update(world);
+        self.matches.blocks()
+    }
+
+    pub fn iter<A: Allocator + Copy + 'static, L: Allocator + Copy + 'static>(&mut self, world: &World<A, L>) -> SetIter<'_> {
+        self.update(world);
+        self.matches.iter()
+    }
+
+    pub fn count<A: Allocator + Copy + 'static, L: Allocator + Copy + 'static>(&mut self, world: &World<A, L>) -> usize {
+        self.update(world);
+        self.matches.len()
+    }
+
+    /// Whether `entity` matched at the last update.
+    pub fn contains(&self, entity: Entity) -> bool {
+        self.matches.contains(entity)
+    }
+}
@@ -0,0 +1,11 @@
+mod query;
+pub(crate) mod par;
+mod cached;
+pub mod stats;
+#[cfg(test)]
+mod tests;
+
+pub use query::*;
+pub use par::*;
+pub use cached::*;
+pub use stats::QueryStats;
@@ -0,0 +1,118 @@
+use std::marker::PhantomData;
+use std::sync::Mutex;
+use std::thread;
+
+use std::alloc::Allocator;
+
+use crate::component::Component;
+use crate::jobs::JobPool;
+use crate::storage::block::SparseBlock;
+use crate::storage::storage::SparseStorage;
+
+/// Settings for parallel iteration on the current `JobPool` (see `JobPool::install`).
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Parallel {
+    /// Jobs to submit at most; 1 runs everything on the calling thread.
+    pub threads: usize,
+    /// Batches handed to a worker hold at least this many entities, unless the
+    /// whole query is smaller.
+    pub min_batch: usize,
+}
+
+impl Default for Parallel {
+    fn default() -> Self {
+        Self { threads: thread::available_parallelism().map_or(1, |n| n.get()), min_batch: 1024 }
+    }
+}
+
+impl Parallel {
+    pub fn threads(mut self, threads: usize) -> Self {
+        self.threads = threads.max(1);
+        self
+    }
+
+    pub fn min_batch(mut self, min_batch: usize) -> Self {
+        self.min_batch = min_batch.max(1);
+        self
+    }
+
+    /// Group `units` (one per leaf block, in tree order, with the number of entities
+    /// they cover) into batches of at least `min_batch` entities and run `f` on every
+    /// unit, the batches spread over jobs on the current `JobPool`. Batches are consecutive
+    /// leaf blocks, so each job walks whole neighbouring root/L1 subtrees.
+    pub fn run<J, F>(&self, units: impl IntoIterator<Item = (J, usize)>, f: F)
+    where
+        J: Send,
+        F: Fn(J) + Sync,
+    {
+        let mut batches: Vec<Vec<J>> = Vec::new();
+        let mut current = Vec::new();
+        let mut weight = 0;
+        for (unit, count) in units {
+            current.push(unit);
+            weight += count;
+            if weight >= self.min_batch {
+                batches.push(std::mem::take(&mut current));
+                weight = 0;
+            }
+        }
+        if !current.is_empty() {
+            batches.push(current);
+        }
+
+        let workers = self.threads.min(batches.len());
+        if workers <= 1 {
+            batches.into_iter().flatten().for_each(f);
+            return;
+        }
+        let queue = Mutex::new(batches.into_iter());
+        JobPool::scope_current(|scope| {
+            for _ in 0..workers {
+                scope.spawn(|_| loop {
+                    let Some(batch) = queue.lock().unwrap().next() else { break };
+                    batch.into_iter().for_each(&f);
+                });
+            }
+        });
+    }
+}
+
+/// Leaf data handed to exactly one worker.
+pub(crate) struct LeafPtr<T>(pub *mut T);
+
+// each leaf block is listed once and the storage stays mutably borrowed while workers run
+unsafe impl<T: Send> Send for LeafPtr<T> {}
+
+/// Block `B` of a storage tree of `T`s read by workers: a leaf, or the root of a whole
+/// storage. Workers read masks and values through it, never the allocator handle every
+/// block carries, so it is shareable whenever `T` is, whatever the allocator.
+pub(crate) struct SharedBlock<'a, T, B>(&'a B, PhantomData<fn() -> T>);
+
+impl<'a, T, A> SharedBlock<'a, T, SparseBlock<T, A>> {
+    pub fn leaf(block: &'a SparseBlock<T, A>) -> Self {
+        Self(block, PhantomData)
+    }
+}
+
+impl<'a, T: Component, A: Allocator + Copy, L: Allocator + Copy> SharedBlock<'a, T, SparseBlock<Box<SparseBlock<Box<SparseBlock<T, L>, L>, A>, A>, A>> {
+    pub fn root(storage: &'a SparseStorage<T, A, L>) -> Self {
+        Self(&storage.root, PhantomData)
+    }
+}
+
+impl<'a, T, B> SharedBlock<'a, T, B> {
+    pub fn get(&self) -> &'a B {
+        self.0
+    }
+}
+
+impl<T, B> Clone for SharedBlock<'_, T, B> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<T, B> Copy for SharedBlock<'_, T, B> {}
+
+unsafe impl<T: Sync, B> Send for SharedBlock<'_, T, B> {}
+unsafe impl<T: Sync, B> Sync for SharedBlock<'_, T, B> {}
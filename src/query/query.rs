@@ -0,0 +1,375 @@
+use std::alloc::Allocator;
+use std::any::TypeId;
+use std::cell::RefCell;
+use std::fmt;
+use std::ops::Range;
+use std::rc::Rc;
+
+use crate::component::Component;
+use crate::query::par::{LeafPtr, Parallel};
+use crate::query::stats::QueryStats;
+use crate::storage::storage::{MAX_INDEX, Storage};
+use crate::storage::validate::BlockPath;
+use crate::world::{Entities, Entity, EntitySet, World};
+
+/// One filter of a `Query`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Term {
+    /// The entity has this component.
+    With(TypeId),
+    /// The entity does not have this component, visible or disabled.
+    Without(TypeId),
+    /// Also match disabled entities, whose components sit in the absence masks.
+    IncludeDisabled,
+    /// The entity is in this set.
+    In(Rc<EntitySet>),
+    /// The entity index lies in this range.
+    Range(Range<u32>),
+}
+
+/// Type-erased entity filter evaluated on the storage masks, one leaf block at a time.
+/// Disabled entities are passed over unless the query includes `Term::IncludeDisabled`.
+#[derive(Clone, Debug, Default)]
+pub struct Query {
+    terms: Vec<Term>,
+}
+
+impl Query {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with<T: Component>(mut self) -> Self {
+        self.terms.push(Term::With(TypeId::of::<T>()));
+        self
+    }
+
+    pub fn without<T: Component>(mut self) -> Self {
+        self.terms.push(Term::Without(TypeId::of::<T>()));
+        self
+    }
+
+    pub fn include_disabled(mut self) -> Self {
+        self.terms.push(Term::IncludeDisabled);
+        self
+    }
+
+    /// Only match entities in `set`, e.g. a selection kept without a marker component.
+    pub fn within(mut self, set: Rc<EntitySet>) -> Self {
+        self.terms.push(Term::In(set));
+        self
+    }
+
+    /// Only match entity indices in `range`. Only the root slots and the blocks on the
+    /// range boundaries are masked, everything inside is walked as usual.
+    pub fn in_range(mut self, range: Range<u32>) -> Self {
+        self.terms.push(Term::Range(range));
+        self
+    }
+
+    /// Index range the query is restricted to: the intersection of its `Range` terms.
+    pub fn range(&self) -> Range<u32> {
+        self.terms.iter().fold(0..MAX_INDEX, |acc, term| match term {
+            Term::Range(r) => acc.start.max(r.start)..acc.end.min(r.end),
+            _ => acc,
+        })
+    }
+
+    /// Split the query range at L1 block boundaries (16384 indices), keeping only the
+    /// L1 blocks that can hold matches. Each part can be handed to `in_range` on a
+    /// clone of the query to shard the work, e.g. one part per pool.
+    pub fn partitions<A: Allocator + Copy + 'static, L: Allocator + Copy + 'static>(&self, world: &World<A, L>) -> Vec<Range<u32>> {
+        let range = self.range();
+        let blocks = self.blocks(world);
+        let mut roots = blocks.roots;
+        let mut parts = Vec::new();
+        while roots != 0 {
+            let r = roots.trailing_zeros();
+            roots &= roots - 1;
+            let start = (r << 14).max(range.start);
+            let end = ((r + 1) << 14).min(range.end);
+            parts.push(start..end);
+        }
+        parts
+    }
+
+    pub fn terms(&self) -> &[Term] {
+        &self.terms
+    }
+
+    /// Matching entities as `(base, mask)` per 128-slot leaf block, in index order.
+    /// Storages are borrowed only while their masks are read.
+    ///
+    /// The `With` and `In` terms are intersected smallest first, by their cached counts,
+    /// and a block is only read while the slots still in play are non-zero, so the most
+    /// selective term prunes subtrees before the others are touched. See `explain`.
+    pub fn blocks<'w, A: Allocator + Copy + 'static, L: Allocator + Copy + 'static>(&self, world: &'w World<A, L>) -> Blocks<'w> {
+        let include_disabled = self.terms.contains(&Term::IncludeDisabled);
+        let mut sources = Vec::new();
+        let mut without = Vec::new();
+        let mut missing = false;
+        for term in &self.terms {
+            match term {
+                Term::With(id) => match world.storage_dyn(*id) {
+                    Some(storage) => sources.push(Source::Storage(storage)),
+                    None => missing = true,
+                },
+                Term::Without(id) => without.extend(world.storage_dyn(*id)),
+                Term::In(set) => sources.push(Source::Set(set.clone())),
+                Term::IncludeDisabled | Term::Range(_) => {}
+            }
+        }
+        sources.sort_by_cached_key(Source::estimate);
+        let range = self.range();
+        let mut blocks = Blocks {
+            sources,
+            without,
+            range,
+            include_disabled,
+            entities: world.entities(),
+            roots: 0,
+            r: 0,
+            mids: 0,
+            stats: QueryStats::default(),
+        };
+        if !missing {
+            blocks.roots = blocks.candidates(BlockPath { level: 0, base: 0 });
+        }
+        blocks
+    }
+
+    /// Run the query and describe how: the order the terms are intersected in with their
+    /// size estimates, and the stats of the walk.
+    pub fn explain<A: Allocator + Copy + 'static, L: Allocator + Copy + 'static>(&self, world: &World<A, L>) -> Plan {
+        let mut blocks = self.blocks(world);
+        let mut steps: Vec<String> = blocks.sources.iter().map(|s| format!("{} (~{})", s.describe(), s.estimate())).collect();
+        if blocks.sources.is_empty() {
+            steps.push(format!("all entities (~{})", world.entities().len()));
+        }
+        for term in &self.terms {
+            match term {
+                Term::With(id) if world.storage_dyn(*id).is_none() => steps.insert(0, "with <no storage> (0)".to_string()),
+                Term::Without(id) => match world.storage_dyn(*id) {
+                    Some(storage) => steps.push(format!("without {}", storage.borrow().component_name())),
+                    None => steps.push("without <no storage>".to_string()),
+                },
+                _ => {}
+            }
+        }
+        let range = self.range();
+        if range != (0..MAX_INDEX) {
+            steps.push(format!("in range {:?}", range));
+        }
+        if blocks.include_disabled {
+            steps.push("including disabled".to_string());
+        }
+        blocks.by_ref().for_each(drop);
+        Plan { steps, stats: blocks.stats }
+    }
+
+    pub fn iter<'w, A: Allocator + Copy + 'static, L: Allocator + Copy + 'static>(&self, world: &'w World<A, L>) -> impl Iterator<Item = Entity> + 'w {
+        self.blocks(world).flat_map(|(base, mut mask)| {
+            std::iter::from_fn(move || {
+                if mask == 0 { return None; }
+                let slot = mask.trailing_zeros();
+                mask &= mask - 1;
+                Some(Entity(base | slot))
+            })
+        })
+    }
+
+    pub fn count<A: Allocator + Copy + 'static, L: Allocator + Copy + 'static>(&self, world: &World<A, L>) -> usize {
+        self.blocks(world).map(|(_, mask)| mask.count_ones() as usize).sum()
+    }
+
+    /// Call `f` with each matching entity that has a `T` and mutable access to it, from
+    /// jobs on the global `JobPool`. Matching leaf blocks are collected first, then each
+    /// one goes to exactly one worker, so no two calls ever see the same leaf data.
+    /// The `T` storage stays mutably borrowed until every worker is done.
+    pub fn par_for_each<T, A, L, F>(&self, world: &World<A, L>, parallel: Parallel, f: F)
+    where
+        T: Component + Send,
+        A: Allocator + Copy + 'static, L: Allocator + Copy + 'static,
+        F: Fn(Entity, &mut T) + Sync,
+    {
+        let include_disabled = self.terms.contains(&Term::IncludeDisabled);
+        let blocks: Vec<(u32, u128)> = self.blocks(world).collect();
+        let Some(storage) = world.storage::<T>() else { return };
+        let mut storage = storage.borrow_mut();
+        let mut units = Vec::with_capacity(blocks.len());
+        for (base, mask) in blocks {
+            let Some(leaf) = storage.leaf_block_mut(base) else { continue };
+            let held = if include_disabled { leaf.presence_mask | leaf.absence_mask } else { leaf.presence_mask };
+            let mask = mask & held;
+            if mask != 0 {
+                units.push(((base, mask, LeafPtr(leaf.data.as_mut_ptr())), mask.count_ones() as usize));
+            }
+        }
+        parallel.run(units, |(base, mut mask, leaf)| {
+            while mask != 0 {
+                let slot = mask.trailing_zeros();
+                mask &= mask - 1;
+                // SAFETY: slots in `mask` are initialized and this leaf belongs to this call alone
+                let value = unsafe { (*leaf.0.add(slot as usize)).assume_init_mut() };
+                f(Entity(base | slot), value);
+            }
+        });
+    }
+}
+
+/// A filter that can prune whole subtrees: a storage of a `With` term or an `In` set.
+enum Source {
+    Storage(Rc<RefCell<dyn Storage>>),
+    Set(Rc<EntitySet>),
+}
+
+impl Source {
+    /// Upper bound of the matches this source allows.
+    fn estimate(&self) -> usize {
+        match self {
+            Source::Storage(storage) => storage.borrow().len(),
+            Source::Set(set) => set.len(),
+        }
+    }
+
+    fn describe(&self) -> String {
+        match self {
+            Source::Storage(storage) => format!("with {}", storage.borrow().component_name()),
+            Source::Set(_) => "in entity set".to_string(),
+        }
+    }
+
+    fn mask(&self, path: BlockPath, include_disabled: bool) -> u128 {
+        match self {
+            Source::Storage(storage) => {
+                let (presence, absence) = storage.borrow().masks(path);
+                if include_disabled { presence | absence } else { presence }
+            }
+            Source::Set(set) => set.masks(path),
+        }
+    }
+}
+
+/// How `Query::explain` found its matches.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Plan {
+    /// Terms in evaluation order, the driving one first.
+    pub steps: Vec<String>,
+    pub stats: QueryStats,
+}
+
+impl fmt::Display for Plan {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (i, step) in self.steps.iter().enumerate() {
+            writeln!(f, "{}. {}", i + 1, step)?;
+        }
+        write!(f, "{}", self.stats)
+    }
+}
+
+/// Iterator returned by `Query::blocks`.
+pub struct Blocks<'w> {
+    sources: Vec<Source>,
+    without: Vec<Rc<RefCell<dyn Storage>>>,
+    range: Range<u32>,
+    include_disabled: bool,
+    entities: &'w Entities,
+    roots: u128,
+    r: u32,
+    mids: u128,
+    stats: QueryStats,
+}
+
+impl Blocks<'_> {
+    /// Counters of the walk so far; complete once the iterator is exhausted.
+    pub fn stats(&self) -> &QueryStats {
+        &self.stats
+    }
+
+    /// Child slots of the block at `path` that can contain a match. Sources are read in
+    /// plan order and the rest are skipped as soon as no slot is left.
+    fn candidates(&mut self, path: BlockPath) -> u128 {
+        let level = path.level as usize;
+        let mut mask = self.in_range(path);
+        if self.sources.is_empty() {
+            mask &= self.all_entities(path);
+            self.stats.visit(level, mask, mask);
+            return mask;
+        }
+        let mut driver = None;
+        for source in &self.sources {
+            if mask == 0 { break; }
+            mask &= source.mask(path, self.include_disabled);
+            driver.get_or_insert(mask);
+        }
+        self.stats.visit(level, driver.unwrap_or(0), mask);
+        mask
+    }
+
+    /// Child slots of the block at `path` that overlap the query range.
+    fn in_range(&self, path: BlockPath) -> u128 {
+        let span = 1u32 << (7 * (2 - path.level as u32));
+        let lo = self.range.start.max(path.base);
+        let hi = self.range.end.min(path.base.saturating_add(span * 128));
+        if lo >= hi {
+            return 0;
+        }
+        let first = (lo - path.base) / span;
+        let last = (hi - 1 - path.base) / span;
+        (u128::MAX >> (127 - last)) & (u128::MAX << first)
+    }
+
+    /// Nothing to prune with: every leaf that ever held an entity.
+    fn all_entities(&self, path: BlockPath) -> u128 {
+        match path.level {
+            0 => {
+                let roots = self.entities.leaf_count().div_ceil(128);
+                if roots >= 128 { u128::MAX } else { (1u128 << roots) - 1 }
+            }
+            _ => u128::MAX,
+        }
+    }
+
+    pub(crate) fn leaf_mask(&mut self, base: u32) -> u128 {
+        let path = BlockPath { level: 2, base };
+        let mut mask = self.candidates(path);
+        if !self.sources.iter().any(|s| matches!(s, Source::Storage(_))) {
+            // sets and the entity allocator know nothing of disabled entities or despawns
+            let leaf = base >> 7;
+            let disabled = if self.include_disabled { 0 } else { self.entities.disabled_mask(leaf) };
+            mask &= self.entities.alive_mask(leaf) & !disabled;
+        }
+        for storage in &self.without {
+            if mask == 0 { break; }
+            let (presence, absence) = storage.borrow().masks(path);
+            mask &= !(presence | absence);
+        }
+        mask
+    }
+}
+
+impl Iterator for Blocks<'_> {
+    type Item = (u32, u128);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if self.mids != 0 {
+                let m = self.mids.trailing_zeros();
+                self.mids &= self.mids - 1;
+                let base = (self.r << 14) | (m << 7);
+                let mask = self.leaf_mask(base);
+                if mask != 0 {
+                    self.stats.leaf(mask);
+                    return Some((base, mask));
+                }
+                continue;
+            }
+            if self.roots == 0 {
+                return None;
+            }
+            self.r = self.roots.trailing_zeros();
+            self.roots &= self.roots - 1;
+            self.mids = self.candidates(BlockPath { level: 1, base: self.r << 14 });
+        }
+    }
+}
@@ -0,0 +1,121 @@
+use std::fmt;
+
+use crate::tick::Tick;
+
+/// Counters for one walk over the block hierarchy. Level 0 is the root, 1 the L1
+/// blocks and 2 the 128-slot leaves.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct QueryStats {
+    /// Blocks whose masks were read, per level.
+    pub visited: [u64; 3],
+    /// Blocks linked in the driving term but ruled out by another one, per level.
+    pub pruned: [u64; 3],
+    pub matched: u64,
+    /// Maximal runs of consecutive matches within a leaf.
+    pub runs: u64,
+    /// `run_lengths[i]` counts runs of `2^i ..= 2^(i+1) - 1` matches.
+    pub run_lengths: [u64; 8],
+}
+
+impl QueryStats {
+    /// Count a read of a block at `level`; `driver` are the child slots the driving term
+    /// links and `matched` the ones left after every term.
+    pub fn visit(&mut self, level: usize, driver: u128, matched: u128) {
+        self.visited[level] += 1;
+        if level < 2 {
+            self.pruned[level + 1] += (driver.count_ones() - (driver & matched).count_ones()) as u64;
+        }
+    }
+
+    /// Count the matches of one leaf and their runs.
+    pub fn leaf(&mut self, mut mask: u128) {
+        self.matched += mask.count_ones() as u64;
+        while mask != 0 {
+            mask >>= mask.trailing_zeros();
+            let len = mask.trailing_ones();
+            mask = mask.checked_shr(len).unwrap_or(0);
+            self.runs += 1;
+            self.run_lengths[len.ilog2() as usize] += 1;
+        }
+    }
+
+    pub fn merge(&mut self, other: &QueryStats) {
+        for level in 0..3 {
+            self.visited[level] += other.visited[level];
+            self.pruned[level] += other.pruned[level];
+        }
+        self.matched += other.matched;
+        self.runs += other.runs;
+        for (total, n) in self.run_lengths.iter_mut().zip(other.run_lengths) {
+            *total += n;
+        }
+    }
+
+    /// Average matches per run; low values point at fragmented storage.
+    pub fn mean_run(&self) -> f64 {
+        if self.runs == 0 { 0.0 } else { self.matched as f64 / self.runs as f64 }
+    }
+}
+
+impl fmt::Display for QueryStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "visited {}/{}/{}, pruned {}/{}, {} matched in {} runs (mean {:.1}), run lengths",
+            self.visited[0], self.visited[1], self.visited[2], self.pruned[1], self.pruned[2],
+            self.matched, self.runs, self.mean_run()
+        )?;
+        for (i, n) in self.run_lengths.iter().enumerate() {
+            write!(f, " {}:{}", 1u32 << i, n)?;
+        }
+        Ok(())
+    }
+}
+
+/// Stats of every instrumented system for one tick, see `World::take_stats`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct TickStats {
+    pub tick: Tick,
+    pub systems: Vec<(&'static str, QueryStats)>,
+}
+
+impl fmt::Display for TickStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:?}", self.tick)?;
+        for (name, stats) in &self.systems {
+            write!(f, "\n  {}: {}", name, stats)?;
+        }
+        Ok(())
+    }
+}
+
+/// Stats of a world's instrumented systems, collected per tick; see `World::instrument`.
+/// Off by default, in which case systems skip the counters entirely.
+#[derive(Debug, Default)]
+pub struct Instrumentation {
+    enabled: bool,
+    pending: Vec<(&'static str, QueryStats)>,
+}
+
+impl Instrumentation {
+    pub fn set_enabled(&mut self, enabled: bool) {
+        self.enabled = enabled;
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.enabled
+    }
+
+    /// Add a walk to the current tick's stats of `system`.
+    pub fn record(&mut self, system: &'static str, stats: &QueryStats) {
+        match self.pending.iter_mut().find(|(name, _)| *name == system) {
+            Some((_, total)) => total.merge(stats),
+            None => self.pending.push((system, *stats)),
+        }
+    }
+
+    /// Take the stats recorded since the previous call, in first-run order.
+    pub fn end_tick(&mut self, tick: Tick) -> TickStats {
+        TickStats { tick, systems: std::mem::take(&mut self.pending) }
+    }
+}
@@ -0,0 +1,263 @@
+use std::rc::Rc;
+
+use ercs_macros::Component;
+
+use crate::jobs::JobPool;
+use crate::query::{CachedQuery, Parallel, Query};
+use crate::storage::storage::MAX_INDEX;
+use crate::world::{AllocHint, Entity, EntitySet, World};
+
+#[derive(Component)]
+struct Pos(u32);
+
+#[derive(Component)]
+struct Vel(u32);
+
+#[derive(Component)]
+struct Frozen;
+
+#[test]
+fn with_and_without_terms_filter_entities() {
+    let mut world = World::new();
+    let a = world.spawn((Pos(0), Vel(1)));
+    let b = world.spawn(Pos(1));
+    let c = world.spawn((Pos(2), Vel(2), Frozen));
+    let pool = world.entities_mut().create_pool(40_960..41_088);
+    let far = world.spawn_with(AllocHint::Pool(pool), (Pos(3), Vel(3)));
+
+    let moving: Vec<Entity> = Query::new().with::<Pos>().with::<Vel>().without::<Frozen>().iter(&world).collect();
+    assert_eq!(moving, vec![a, far]);
+    assert_eq!(Query::new().with::<Pos>().count(&world), 4);
+    assert_eq!(Query::new().without::<Vel>().iter(&world).collect::<Vec<_>>(), vec![b]);
+    assert_eq!(Query::new().with::<Pos>().with::<Vel>().iter(&world).collect::<Vec<_>>(), vec![a, c, far]);
+}
+
+#[test]
+fn missing_storage_matches_nothing() {
+    let mut world = World::new();
+    world.spawn(Pos(0));
+    assert_eq!(Query::new().with::<Vel>().count(&world), 0);
+    assert_eq!(Query::new().with::<Pos>().without::<Vel>().count(&world), 1);
+}
+
+#[test]
+fn disabled_entities_are_skipped_unless_included() {
+    let mut world = World::new();
+    let a = world.spawn((Pos(0), Vel(0)));
+    let b = world.spawn((Pos(1), Vel(1)));
+    let lone = world.spawn_empty();
+
+    assert!(world.disable(a));
+    assert!(!world.disable(a));
+    assert!(world.disable(lone));
+    // components inserted while disabled stay hidden too
+    world.insert(a, Frozen);
+
+    assert_eq!(Query::new().with::<Pos>().iter(&world).collect::<Vec<_>>(), vec![b]);
+    assert_eq!(Query::new().iter(&world).collect::<Vec<_>>(), vec![b]);
+    assert_eq!(Query::new().with::<Frozen>().count(&world), 0);
+    assert_eq!(Query::new().include_disabled().iter(&world).collect::<Vec<_>>(), vec![a, b, lone]);
+    assert_eq!(Query::new().with::<Pos>().without::<Frozen>().include_disabled().iter(&world).collect::<Vec<_>>(), vec![b]);
+
+    let pos = world.get::<Pos>();
+    assert!(pos.borrow().get(a.0).is_none());
+    assert_eq!(pos.borrow().get_any(a.0).map(|p| p.0), Some(0));
+    assert!(world.validate().is_empty());
+
+    assert!(world.enable(a));
+    assert_eq!(Query::new().with::<Pos>().with::<Frozen>().iter(&world).collect::<Vec<_>>(), vec![a]);
+    assert!(world.despawn(lone));
+    assert!(!world.entities().is_disabled(lone));
+}
+
+#[test]
+fn disabled_state_survives_defragment() {
+    let mut world = World::new();
+    let all: Vec<Entity> = (0..10).map(|i| world.spawn(Pos(i))).collect();
+    for e in &all[..5] {
+        world.despawn(*e);
+    }
+    world.disable(all[7]);
+    let remap = world.defragment();
+    let moved = remap.get(all[7]);
+    assert!(world.entities().is_disabled(moved));
+    assert_eq!(Query::new().with::<Pos>().count(&world), 4);
+    assert_eq!(world.get::<Pos>().borrow().get_any(moved.0).map(|p| p.0), Some(7));
+    assert!(world.get::<Pos>().borrow().get(moved.0).is_none());
+}
+
+#[test]
+fn entity_set_filters_like_a_component() {
+    let mut world = World::new();
+    let all: Vec<Entity> = (0..300).map(|i| world.spawn(Pos(i))).collect();
+    world.insert(all[7], Vel(0));
+    world.insert(all[200], Vel(0));
+
+    let visible: Rc<EntitySet> = Rc::new([all[7], all[8], all[200], all[299]].into_iter().collect());
+    world.despawn(all[299]);
+    world.disable(all[8]);
+
+    assert_eq!(Query::new().within(visible.clone()).iter(&world).collect::<Vec<_>>(), vec![all[7], all[200]]);
+    assert_eq!(Query::new().with::<Vel>().within(visible.clone()).count(&world), 2);
+    assert_eq!(Query::new().without::<Vel>().within(visible.clone()).include_disabled().iter(&world).collect::<Vec<_>>(), vec![all[8]]);
+    assert_eq!(Query::new().with::<Pos>().within(Rc::new(EntitySet::new())).count(&world), 0);
+}
+
+#[test]
+fn in_range_masks_boundary_blocks() {
+    let mut world = World::new();
+    let all: Vec<Entity> = (0..40_000).map(|i| world.spawn(Pos(i))).collect();
+    world.insert(all[16_000], Vel(0));
+    world.insert(all[16_500], Vel(0));
+
+    let q = Query::new().with::<Pos>().in_range(100..16_390);
+    assert_eq!(q.count(&world), 16_290);
+    assert_eq!(q.iter(&world).next(), Some(all[100]));
+    assert_eq!(q.iter(&world).last(), Some(all[16_389]));
+    assert_eq!(Query::new().in_range(5..7).iter(&world).collect::<Vec<_>>(), vec![all[5], all[6]]);
+    assert_eq!(Query::new().with::<Vel>().in_range(0..16_384).in_range(10_000..MAX_INDEX).count(&world), 1);
+    assert_eq!(Query::new().with::<Pos>().in_range(50..50).count(&world), 0);
+}
+
+#[test]
+fn partitions_split_at_l1_blocks_and_cover_every_match() {
+    let mut world = World::new();
+    let red = world.entities_mut().create_pool(32_768..33_024);
+    let blue = world.entities_mut().create_pool(65_536..65_792);
+    for i in 0..10 {
+        world.spawn_with(AllocHint::Pool(red), Pos(i));
+        world.spawn_with(AllocHint::Pool(blue), (Pos(i), Vel(i)));
+    }
+    world.spawn(Pos(99));
+
+    let q = Query::new().with::<Pos>();
+    let parts = q.partitions(&world);
+    assert_eq!(parts, vec![0..16_384, 32_768..49_152, 65_536..81_920]);
+    let sharded: usize = parts.into_iter().map(|part| q.clone().in_range(part).count(&world)).sum();
+    assert_eq!(sharded, q.count(&world));
+
+    assert_eq!(Query::new().with::<Vel>().in_range(60_000..70_000).partitions(&world), vec![65_536..70_000]);
+}
+
+#[test]
+fn par_for_each_mutates_every_match_once() {
+    let mut world = World::new();
+    let all: Vec<Entity> = world.spawn_batch((0..60_000).map(|i| (Pos(i), Vel(1)))).collect();
+    for e in all.iter().step_by(5) {
+        world.remove::<Vel>(*e);
+    }
+    world.disable(all[1]);
+
+    let q = Query::new().with::<Vel>();
+    JobPool::new(4).install(|| {
+        q.par_for_each::<Pos, _, _, _>(&world, Parallel::default().threads(4).min_batch(500), |entity, pos| {
+            assert_eq!(pos.0, entity.0);
+            pos.0 += 1_000_000;
+        })
+    });
+    let pos = world.get::<Pos>();
+    let pos = pos.borrow();
+    for (i, e) in all.iter().enumerate() {
+        let moved = i % 5 != 0 && i != 1;
+        assert_eq!(pos.get_any(e.0).unwrap().0, i as u32 + if moved { 1_000_000 } else { 0 });
+    }
+}
+
+#[test]
+fn parallel_batches_respect_min_batch() {
+    use std::sync::Mutex;
+    let seen = Mutex::new(Vec::new());
+    JobPool::new(3).install(|| {
+        Parallel::default().threads(3).min_batch(10).run((0..100).map(|i| (i, 4)), |i| seen.lock().unwrap().push(i))
+    });
+    let mut seen = seen.into_inner().unwrap();
+    seen.sort();
+    assert_eq!(seen, (0..100).collect::<Vec<_>>());
+}
+
+#[test]
+fn explain_drives_with_the_smallest_term_and_prunes_its_subtrees() {
+    let mut world = World::new();
+    let mut common = Vec::new();
+    for i in 0..1000 {
+        common.push(world.spawn(Pos(i)));
+    }
+    world.insert(common[3], Vel(0));
+    let pool = world.entities_mut().create_pool(40_960..41_088);
+    world.spawn_with(AllocHint::Pool(pool), Vel(1));
+
+    let query = Query::new().with::<Pos>().with::<Vel>();
+    let plan = query.explain(&world);
+    assert!(plan.steps[0].contains("Vel") && plan.steps[0].ends_with("(~2)"), "{}", plan);
+    assert!(plan.steps[1].contains("Pos") && plan.steps[1].ends_with("(~1000)"), "{}", plan);
+    assert_eq!(plan.stats.matched, 1);
+    // only the root and the one L1 and leaf block shared by both terms are read
+    assert_eq!(plan.stats.visited, [1, 1, 1]);
+    assert_eq!(plan.stats.pruned, [0, 1, 0]);
+    assert_eq!(query.iter(&world).collect::<Vec<_>>(), vec![common[3]]);
+
+    let plan = Query::new().with::<Vel>().without::<Frozen>().in_range(0..128).explain(&world);
+    assert_eq!(plan.steps.len(), 3);
+    assert_eq!(plan.stats.matched, 1);
+    assert!(plan.to_string().contains("1 matched in 1 runs"), "{}", plan);
+}
+
+#[test]
+fn block_stats_count_runs_per_leaf() {
+    let mut world = World::new();
+    world.spawn_batch((0..130).map(Pos));
+    world.remove::<Pos>(Entity(5));
+
+    let mut blocks = Query::new().with::<Pos>().blocks(&world);
+    blocks.by_ref().for_each(drop);
+    let stats = *blocks.stats();
+    assert_eq!(stats.visited, [1, 1, 2]);
+    assert_eq!(stats.matched, 129);
+    // 0..5 and 6..128 in the first leaf, 128..130 in the second
+    assert_eq!(stats.runs, 3);
+    assert_eq!(stats.run_lengths, [0, 1, 1, 0, 0, 0, 1, 0]);
+    assert_eq!(stats.mean_run(), 43.0);
+}
+
+#[test]
+fn cached_query_follows_storage_mutations() {
+    let mut world = World::new();
+    let entities: Vec<Entity> = world.spawn_batch((0..300).map(|i| (Pos(i), Vel(i)))).collect();
+    let query = Query::new().with::<Pos>().with::<Vel>().without::<Frozen>();
+    let mut cached = CachedQuery::new(query.clone());
+    assert_eq!(cached.count(&world), 300);
+
+    world.remove::<Vel>(entities[7]);
+    world.despawn(entities[200]);
+    world.disable(entities[130]);
+    assert_eq!(cached.count(&world), 297);
+    assert!(!cached.contains(entities[130]));
+    // the Frozen storage is created here, after the cache was built
+    world.insert(entities[9], Frozen);
+    let far = world.entities_mut().create_pool(40_960..41_088);
+    let late = world.spawn_with(AllocHint::Pool(far), (Pos(0), Vel(0)));
+
+    let expected: Vec<Entity> = query.iter(&world).collect();
+    assert_eq!(cached.iter(&world).collect::<Vec<_>>(), expected);
+    assert!(cached.contains(late) && !cached.contains(entities[7]));
+
+    world.remove::<Frozen>(entities[9]);
+    world.enable(entities[130]);
+    assert_eq!(cached.count(&world), 299);
+    assert_eq!(cached.blocks(&world).collect::<Vec<_>>(), query.blocks(&world).collect::<Vec<_>>());
+}
+
+#[test]
+fn cached_query_without_storages_rebuilds() {
+    let mut world = World::new();
+    let a = world.spawn_empty();
+    let mut cached = CachedQuery::new(Query::new().with::<Pos>());
+    assert_eq!(cached.count(&world), 0);
+    world.insert(a, Pos(1));
+    assert_eq!(cached.iter(&world).collect::<Vec<_>>(), vec![a]);
+
+    let mut all = CachedQuery::new(Query::new());
+    assert_eq!(all.count(&world), 1);
+    world.spawn_empty();
+    assert_eq!(all.count(&world), 2);
+}
@@ -0,0 +1,211 @@
+use crate::jobs::JobPool;
+use crate::scheduler::PipelineStage;
+
+/// Whether `a` and `b` must not run at the same time: one writes what the other reads
+/// or writes, or one is ordered before the other.
+pub(crate) fn conflicts(a: &dyn PipelineStage, b: &dyn PipelineStage) -> bool {
+    let overlaps = |x: &[std::any::TypeId], y: &[std::any::TypeId]| x.iter().any(|t| y.contains(t));
+    overlaps(a.writes(), b.writes())
+        || overlaps(a.writes(), b.reads())
+        || overlaps(b.writes(), a.reads())
+        || a.before().contains(&b.type_id())
+        || a.after().contains(&b.type_id())
+        || b.before().contains(&a.type_id())
+        || b.after().contains(&a.type_id())
+}
+
+/// Runs stages on a `JobPool`, in waves of stages that do not conflict.
+pub struct Executor<'p> {
+    pool: &'p JobPool,
+}
+
+impl Default for Executor<'static> {
+    fn default() -> Self {
+        Self::new(JobPool::global())
+    }
+}
+
+impl<'p> Executor<'p> {
+    pub fn new(pool: &'p JobPool) -> Self {
+        Self { pool }
+    }
+
+    /// Stage indices grouped into waves. Stages are first ordered by their `before`/`after`
+    /// declarations, keeping list order otherwise; each then goes into the first wave after
+    /// every earlier stage it conflicts with. Panics if the declarations form a cycle.
+    pub fn waves(stages: &[&dyn PipelineStage]) -> Vec<Vec<usize>> {
+        let order = Self::order(stages);
+        let mut wave_of: Vec<usize> = vec![0; stages.len()];
+        let mut waves: Vec<Vec<usize>> = Vec::new();
+        for (n, &i) in order.iter().enumerate() {
+            let wave = order[..n]
+                .iter()
+                .filter(|&&j| conflicts(stages[j], stages[i]))
+                .map(|&j| wave_of[j] + 1)
+                .max()
+                .unwrap_or(0);
+            if waves.len() <= wave {
+                waves.push(Vec::new());
+            }
+            waves[wave].push(i);
+            wave_of[i] = wave;
+        }
+        waves
+    }
+
+    /// Stage indices sorted so every stage comes after the ones it must follow, taking the
+    /// first ready stage in list order each step.
+    fn order(stages: &[&dyn PipelineStage]) -> Vec<usize> {
+        let precedes = |a: &dyn PipelineStage, b: &dyn PipelineStage| {
+            a.before().contains(&b.type_id()) || b.after().contains(&a.type_id())
+        };
+        let mut waiting: Vec<usize> = (0..stages.len())
+            .map(|i| (0..stages.len()).filter(|&j| j != i && precedes(stages[j], stages[i])).count())
+            .collect();
+        let mut done = vec![false; stages.len()];
+        let mut order = Vec::with_capacity(stages.len());
+        while order.len() < stages.len() {
+            let Some(i) = (0..stages.len()).find(|&i| !done[i] && waiting[i] == 0) else {
+                let names: Vec<&str> = (0..stages.len()).filter(|&i| !done[i]).map(|i| stages[i].name()).collect();
+                panic!("stage ordering cycle among {names:?}");
+            };
+            done[i] = true;
+            order.push(i);
+            for j in 0..stages.len() {
+                if j != i && precedes(stages[i], stages[j]) {
+                    waiting[j] -= 1;
+                }
+            }
+        }
+        order
+    }
+
+    /// Run every stage once. Waves run one after another, the stages of a wave as jobs
+    /// on the pool where they hand one out (see `PipelineStage::with_job`), the rest on
+    /// the calling thread meanwhile.
+    pub fn run(&self, stages: &[&dyn PipelineStage]) {
+        for wave in Self::waves(stages) {
+            if let [only] = wave[..] {
+                stages[only].run();
+                continue;
+            }
+            let wave: Vec<&dyn PipelineStage> = wave.into_iter().map(|i| stages[i]).collect();
+            self.run_wave(&wave, Vec::new(), Vec::new());
+        }
+    }
+
+    /// Collect the jobs of `pending` one `with_job` call deeper each, so every stage keeps
+    /// its borrows until the innermost call has joined the whole wave.
+    fn run_wave(&self, pending: &[&dyn PipelineStage], jobs: Vec<&(dyn Fn() + Sync)>, inline: Vec<&dyn PipelineStage>) {
+        let Some((&stage, rest)) = pending.split_first() else {
+            self.pool.scope(|scope| {
+                for job in jobs {
+                    scope.spawn(move |_| job());
+                }
+                inline.iter().for_each(|stage| stage.run());
+            });
+            return;
+        };
+        let mut collected = Some((jobs, inline));
+        stage.with_job(&mut |job| {
+            let Some((mut jobs, mut inline)) = collected.take() else { return };
+            match job {
+                Some(job) => jobs.push(job),
+                None => inline.push(stage),
+            }
+            self.run_wave(rest, jobs, inline);
+        });
+        // a stage that never submitted is skipped, not the ones after it
+        if let Some((jobs, inline)) = collected {
+            self.run_wave(rest, jobs, inline);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::any::TypeId;
+    use std::sync::Mutex;
+
+    struct Pos;
+    struct Vel;
+
+    static LOG: Mutex<Vec<&'static str>> = Mutex::new(Vec::new());
+
+    macro_rules! stage {
+        ($name:ident, reads: [$($r:ty),*], writes: [$($w:ty),*]) => {
+            struct $name;
+            impl PipelineStage for $name {
+                fn run(&self) {
+                    LOG.lock().unwrap().push(stringify!($name));
+                }
+                fn reads(&self) -> &'static [TypeId] {
+                    static IDS: std::sync::LazyLock<Vec<TypeId>> = std::sync::LazyLock::new(|| vec![$(TypeId::of::<$r>()),*]);
+                    &IDS
+                }
+                fn writes(&self) -> &'static [TypeId] {
+                    static IDS: std::sync::LazyLock<Vec<TypeId>> = std::sync::LazyLock::new(|| vec![$(TypeId::of::<$w>()),*]);
+                    &IDS
+                }
+                fn with_job(&self, submit: &mut dyn FnMut(Option<&(dyn Fn() + Sync)>)) {
+                    submit(Some(&|| self.run()));
+                }
+            }
+        };
+    }
+
+    stage!(Integrate, reads: [Vel], writes: [Pos]);
+    stage!(Render, reads: [Pos], writes: []);
+    stage!(Audio, reads: [Vel], writes: []);
+    stage!(Damp, reads: [], writes: [Vel]);
+
+    #[test]
+    fn conflicting_stages_run_in_list_order() {
+        let stages: [&dyn PipelineStage; 4] = [&Integrate, &Render, &Audio, &Damp];
+        assert_eq!(Executor::waves(&stages), vec![vec![0, 2], vec![1, 3]]);
+
+        let pool = JobPool::new(2);
+        Executor::new(&pool).run(&stages);
+        let log = LOG.lock().unwrap();
+        let pos = |name| log.iter().position(|n| *n == name).unwrap();
+        assert_eq!(log.len(), 4);
+        assert!(pos("Integrate") < pos("Render"));
+        assert!(pos("Audio") < pos("Damp") && pos("Integrate") < pos("Damp"));
+    }
+
+    macro_rules! ordered {
+        ($name:ident, before: [$($b:ty),*], after: [$($a:ty),*]) => {
+            struct $name;
+            impl PipelineStage for $name {
+                fn run(&self) {}
+                fn before(&self) -> &'static [TypeId] {
+                    static IDS: std::sync::LazyLock<Vec<TypeId>> = std::sync::LazyLock::new(|| vec![$(TypeId::of::<$b>()),*]);
+                    &IDS
+                }
+                fn after(&self) -> &'static [TypeId] {
+                    static IDS: std::sync::LazyLock<Vec<TypeId>> = std::sync::LazyLock::new(|| vec![$(TypeId::of::<$a>()),*]);
+                    &IDS
+                }
+            }
+        };
+    }
+
+    ordered!(Input, before: [Simulate], after: []);
+    ordered!(Simulate, before: [], after: []);
+    ordered!(Present, before: [], after: [Simulate]);
+    ordered!(Tick, before: [Tock], after: []);
+    ordered!(Tock, before: [Tick], after: []);
+
+    #[test]
+    fn declared_order_beats_list_order() {
+        let stages: [&dyn PipelineStage; 3] = [&Present, &Simulate, &Input];
+        assert_eq!(Executor::waves(&stages), vec![vec![2], vec![1], vec![0]]);
+    }
+
+    #[test]
+    #[should_panic(expected = "stage ordering cycle")]
+    fn ordering_cycles_are_rejected() {
+        Executor::waves(&[&Tick, &Tock]);
+    }
+}
@@ -1,5 +1,9 @@
 use std::any::TypeId;
 
+mod executor;
+
+pub use executor::*;
+
 
 pub trait PipelineGroup: 'static {
     fn name(&self) -> &'static str where Self: 'static { std::any::type_name::<Self>() }
@@ -18,4 +22,8 @@ pub trait PipelineStage: 'static {
     fn after(&self) -> &'static [TypeId] { &[] }
     fn reads(&self) -> &'static [TypeId] { &[] }
     fn writes(&self) -> &'static [TypeId] { &[] }
+    /// Call `submit` exactly once, with a job the `Executor` may run on another thread or
+    /// `None` to have `run` called on the calling thread. Whatever the job needs that is
+    /// not `Sync` (storage borrows, stats) is taken before and released after the call.
+    fn with_job(&self, submit: &mut dyn FnMut(Option<&(dyn Fn() + Sync)>)) { submit(None) }
 }
\ No newline at end of file
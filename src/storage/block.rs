@@ -1,6 +1,6 @@
 use std::mem::MaybeUninit;
 
-use std::alloc::{Allocator, Global};
+use std::alloc::{AllocError, Allocator, Global};
 use std::ops::{Deref, DerefMut};
 use bumpalo::Bump;
 use component::Component;
@@ -8,9 +8,12 @@ use component::Component;
 use crate::component::{Tag};
 use crate::tick::{Tick, TickDelta};
 
+/// `len` counts the visible values below an inner block. On a leaf it is the presence
+/// count last folded into its parents, see `SparseStorage::trim`. Only the storage
+/// writes it, which is why the mask mutators below are crate-private.
 #[derive(Default, Debug, Clone, Copy)]
 #[repr(C)]
-pub struct SparseHeader { pub absence_mask: u128 }
+pub struct SparseHeader { pub(crate) len: u32 }
 
 #[derive(Default, Debug, Clone, Copy)]
 #[repr(C)]
@@ -20,6 +23,14 @@ pub trait MutBlock<T> {
     fn set_all(&mut self, mask: u128);
 }
 
+/// Slot states, at every level of a sparse tree:
+///
+/// - leaf: a presence bit is a visible value, an absence bit a skipped one. Skipped
+///   values are still initialized and owned by the block, they are just not visible.
+/// - inner: a presence bit links a child with at least one visible value below it, an
+///   absence bit links a child whose values below are all skipped.
+///
+/// A slot with neither bit holds nothing; the two masks never overlap.
 #[repr(C)]
 pub struct Block<T, H: Default, A> {
     pub presence_mask: u128,
@@ -30,9 +41,12 @@ pub struct Block<T, H: Default, A> {
     pub data: T,
 }
 
+/// Drops every held value, visible or skipped. Blocks do not know their values are
+/// components, so no `Component::on_remove` runs here; `SparseStorage` runs it for the
+/// values still held before its own blocks are dropped.
 impl<T, A> Drop for SparseBlock<T, A> {
     fn drop(&mut self) {
-        let mut m = self.presence_mask;
+        let mut m = self.presence_mask | self.absence_mask;
         unsafe {
             let ptr = self.data.as_mut_ptr();
             while m != 0 {
@@ -102,6 +116,25 @@ impl<T: Sized, A: Allocator + Copy> DenseBlock<T, A> {
             alloc,
         )
     }
+
+    /// Fallible counterpart of `new_in`: surfaces `AllocError` instead of aborting
+    pub fn try_new_in(capacity: usize, alloc: A) -> Result<Box<Self, A>, AllocError> {
+        let mut data = Vec::new_in(alloc);
+        data.try_reserve_exact(capacity).map_err(|_| AllocError)?;
+        Box::try_new_in(
+            Self {
+                inner: Block {
+                    presence_mask: 0,
+                    absence_mask: 0,
+                    changed_at: Tick::new(0),
+                    header: DenseHeader {},
+                    data,
+                    alloc,
+                },
+            },
+            alloc,
+        )
+    }
 }
 
 impl<T, A: Allocator + Copy> DenseBlock<T, A> {
@@ -116,7 +149,7 @@ impl<T: Sized, A: Allocator + Copy> SparseBlock<T, A> {
             inner: Block {
                 presence_mask: 0,
                 absence_mask: 0,
-                header: SparseHeader { absence_mask: 0 },
+                header: SparseHeader::default(),
                 data: std::array::from_fn(|_| MaybeUninit::uninit()),
                 changed_at: Tick::new(0),
                 alloc
@@ -132,7 +165,7 @@ impl<T: Sized, A: Allocator + Copy> SparseBlock<T, A> {
                 inner: Block {
                     presence_mask: 0,
                     absence_mask: 0,
-                    header: SparseHeader { absence_mask: 0  },
+                    header: SparseHeader::default(),
                     data: std::array::from_fn(|_| MaybeUninit::uninit()),
                     changed_at: Tick::new(0),
                     alloc
@@ -141,33 +174,73 @@ impl<T: Sized, A: Allocator + Copy> SparseBlock<T, A> {
             alloc,
         )
     }
+
+    /// Fallible counterpart of `new_in`: surfaces `AllocError` instead of aborting
+    pub fn try_new_in(alloc: A) -> Result<Box<Self, A>, AllocError> {
+        Box::try_new_in(Self::new(alloc), alloc)
+    }
+}
+
+impl<T, A> SparseBlock<T, A> {
+    /// Store `value` in `slot` without touching the masks, returning the value it replaces,
+    /// visible or skipped.
+    #[inline(always)]
+    pub fn put(&mut self, slot: usize, value: T) -> Option<T> {
+        if self.holds(slot as u32) {
+            return Some(std::mem::replace(unsafe { self.data.get_unchecked_mut(slot).assume_init_mut() }, value));
+        }
+        unsafe { self.data.get_unchecked_mut(slot).write(value); }
+        None
+    }
+
+    /// Bring `header.len` of a leaf up to date with its presence mask and return by how
+    /// much it changed, for the parents' counts.
+    pub(crate) fn sync_len(&mut self) -> i64 {
+        let count = self.presence_mask.count_ones();
+        let delta = count as i64 - self.header.len as i64;
+        self.header.len = count;
+        delta
+    }
+}
+
+impl<T: crate::component::Component, A: Allocator + Copy> SparseBlock<T, A> {
+    /// Materialize the slots in `mask` of an empty block through `Component::init`,
+    /// passing `base + slot` as the global index.
+    pub fn init_all(&mut self, base: u32, mask: u128) {
+        debug_assert!(self.presence_mask == 0 && self.absence_mask == 0, "init_all on a non-empty block");
+        for (i, slot) in self.data.iter_mut().enumerate() {
+            if mask & (1u128 << i) != 0 {
+                *slot = T::init(base + i as u32);
+            }
+        }
+        self.set_all(mask);
+    }
 }
 
 impl<T: Sized> Default for SparseBlock<T, Global> {
     fn default() -> Self { SparseBlock::new(Global) }
 }
 
-impl<U: Sized, A: Allocator + Copy> SparseBlock<Box<SparseBlock<U, A>, A>, A> {
+impl<U: Sized, A: Allocator + Copy, B: Allocator> SparseBlock<Box<SparseBlock<U, B>, B>, A> {
+    /// Reclassify the linked children in `mask` from their own masks: present if they
+    /// have a visible slot, skipped if every slot they hold is skipped. Children that
+    /// hold nothing are freed and unlinked.
     pub fn recompute_all(&mut self, mask: u128) {
-        let old_select = self.presence_mask;
-        let len = self.data.len();
-        let cap = if len > 128 { 128 } else { len };
-        let bounds_mask = if cap == 128 { u128::MAX } else { (1u128 << cap) - 1 };
-        let mut m = mask & bounds_mask;
-        let mut sel: u128 = 0;
-        let mut skip: u128 = 0;
-        
+        let mut m = mask & (self.presence_mask | self.absence_mask);
         while m != 0 {
             let idx = m.trailing_zeros() as usize;
-            let child = unsafe { self.data.get_unchecked(idx).assume_init_ref() };
-            sel |= child.presence_mask;
-            skip |= child.header.absence_mask;
+            let bit = 1u128 << idx;
             m &= m - 1;
+            let child = unsafe { self.data.get_unchecked(idx).assume_init_ref() };
+            if child.presence_mask != 0 {
+                self.set_all(bit);
+            } else if child.absence_mask != 0 {
+                self.skip_all(bit);
+            } else {
+                self.clear_all(bit);
+                unsafe { self.data.get_unchecked_mut(idx).assume_init_drop(); }
+            }
         }
-
-        let eff = sel & !skip;
-        self.header.absence_mask = skip;
-        self.presence_mask = eff;
     }
 }
 
@@ -201,22 +274,32 @@ impl<T, H: Default, A> Block<T, H, A> {
         (self.presence_mask & (1 << index)) != 0
     }
 
+    /// Whether the slot holds anything, visible or skipped.
+    #[inline(always)]
+    pub fn holds(&self, index: u32) -> bool {
+        assert!(index < 128, "index {} out of bounds for presence_mask", index);
+
+        ((self.presence_mask | self.absence_mask) & (1 << index)) != 0
+    }
+
     #[inline(always)]
     pub fn has_any(&self, mask: u128) -> bool {
         (self.presence_mask & mask) != 0
     }
 
-    pub fn set_all(&mut self, mask: u128) {
+    /// Mask mutators leave the counts of a sparse tree stale until the storage trims the
+    /// block, so only the storage and its views call them.
+    pub(crate) fn set_all(&mut self, mask: u128) {
         self.absence_mask &= !mask;
         self.presence_mask |= mask;
     }
 
-    pub fn skip_all(&mut self, mask: u128) {
+    pub(crate) fn skip_all(&mut self, mask: u128) {
         self.presence_mask &= !mask;
         self.absence_mask |= mask;
     }
 
-    pub fn clear_all(&mut self, mask: u128) {
+    pub(crate) fn clear_all(&mut self, mask: u128) {
         self.presence_mask &= !mask;
         self.absence_mask &= !mask;
     }
@@ -252,7 +335,7 @@ mod tests {
 
         b.set_all(0b111);
         assert_eq!(b.presence_mask, 0b111);
-        assert_eq!(b.header.absence_mask, 0);
+        assert_eq!(b.absence_mask, 0);
     }
 
     #[test]
@@ -378,6 +461,49 @@ fn dense_block_set_all_updates_presence_mask_and_returns_changed_count() {
         assert_eq!(DROPS.load(Ordering::SeqCst), 2);
     }
 
+    #[test]
+    fn sparse_block_drop_drops_skipped_elements() {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+        static DROPS: AtomicUsize = AtomicUsize::new(0);
+
+        struct Track;
+        impl Drop for Track { fn drop(&mut self) { DROPS.fetch_add(1, Ordering::SeqCst); } }
+
+        {
+            let mut b = SparseBlock::<Track, Global>::new_in(Global);
+            unsafe { b.data.get_unchecked_mut(1).write(Track); }
+            unsafe { b.data.get_unchecked_mut(2).write(Track); }
+            b.set_all(0b110);
+            b.skip_all(0b100);
+            assert!(b.holds(2) && !b.has(2));
+        }
+
+        assert_eq!(DROPS.load(Ordering::SeqCst), 2);
+    }
+
+    #[test]
+    fn sparse_recompute_classifies_and_frees_children() {
+        let mut page = SparseBlock::<Box<SparseBlock<u32, Global>, Global>, Global>::new_in(Global);
+        for i in 0..3 {
+            let mut c = SparseBlock::<u32, Global>::new_in(Global);
+            unsafe { c.data[0].write(i); }
+            c.set_all(1);
+            page.data[i as usize].write(c);
+        }
+        page.set_all(0b111);
+        unsafe { page.data[1].assume_init_mut() }.skip_all(1);
+        unsafe { page.data[2].assume_init_mut() }.clear_all(1);
+
+        page.recompute_all(u128::MAX);
+        assert_eq!(page.presence_mask, 0b001);
+        assert_eq!(page.absence_mask, 0b010);
+
+        unsafe { page.data[1].assume_init_mut() }.set_all(1);
+        page.recompute_all(0b010);
+        assert_eq!(page.presence_mask, 0b011);
+        assert_eq!(page.absence_mask, 0);
+    }
+
     #[test]
     fn dense_block_with_bumpalo_allocator() {
         let arena = bumpalo::Bump::new();
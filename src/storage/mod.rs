@@ -1,2 +1,6 @@
 pub mod block;
+pub mod pool;
+pub mod roaring;
 pub mod storage;
+pub mod transient;
+pub mod validate;
@@ -0,0 +1,192 @@
+use std::alloc::{AllocError, Allocator, Global, Layout};
+use std::cell::{Cell, RefCell};
+use std::ptr::NonNull;
+
+use crate::storage::block::SparseBlock;
+
+/// Blocks carved out of each chunk requested from `Global`.
+pub const DEFAULT_BLOCKS_PER_CHUNK: usize = 64;
+
+struct FreeBlock {
+    next: Option<NonNull<FreeBlock>>,
+}
+
+/// Fixed-size block pool allocator.
+/// Requests that fit the pool's block layout are served from an intrusive free list
+/// refilled in chunks; anything larger or more aligned falls through to `Global`.
+/// Use `&BlockPool` as the allocator handle, or a `PoolHandle` for a pool owned by a
+/// `World`.
+pub struct BlockPool {
+    block: Layout,
+    per_chunk: usize,
+    free: Cell<Option<NonNull<FreeBlock>>>,
+    chunks: RefCell<Vec<NonNull<u8>>>,
+}
+
+impl BlockPool {
+    pub fn new(layout: Layout, per_chunk: usize) -> Self {
+        assert!(per_chunk > 0, "BlockPool needs at least one block per chunk");
+        let block = Layout::from_size_align(
+            layout.size().max(size_of::<FreeBlock>()),
+            layout.align().max(align_of::<FreeBlock>()),
+        )
+        .expect("invalid block layout")
+        .pad_to_align();
+        Self { block, per_chunk, free: Cell::new(None), chunks: RefCell::new(Vec::new()) }
+    }
+
+    /// Pool sized for one block type, e.g. `SparseBlock<T, A>` leaves.
+    pub fn for_block<B>() -> Self {
+        Self::new(Layout::new::<B>(), DEFAULT_BLOCKS_PER_CHUNK)
+    }
+
+    /// Pool sized for the 128-slot leaf blocks holding `T`.
+    pub fn for_sparse_leaf<T, A>() -> Self {
+        Self::for_block::<SparseBlock<T, A>>()
+    }
+
+    /// Pool sized for the inner blocks of a storage tree: 128 boxed children.
+    /// Their size does not depend on the component type.
+    pub fn for_sparse_inner<A: Allocator>() -> Self {
+        Self::for_block::<SparseBlock<Box<SparseBlock<(), A>, A>, A>>()
+    }
+
+    pub fn block_layout(&self) -> Layout {
+        self.block
+    }
+
+    /// Number of chunks requested from `Global` so far.
+    pub fn chunks(&self) -> usize {
+        self.chunks.borrow().len()
+    }
+
+    #[inline(always)]
+    fn fits(&self, layout: Layout) -> bool {
+        layout.size() <= self.block.size() && layout.align() <= self.block.align()
+    }
+
+    fn refill(&self) -> Result<(), AllocError> {
+        let chunk_layout = Layout::from_size_align(self.block.size() * self.per_chunk, self.block.align())
+            .map_err(|_| AllocError)?;
+        let mut chunks = self.chunks.borrow_mut();
+        chunks.try_reserve(1).map_err(|_| AllocError)?;
+        let chunk = Global.allocate(chunk_layout)?.cast::<u8>();
+        chunks.push(chunk);
+        for i in (0..self.per_chunk).rev() {
+            let node = unsafe { chunk.add(i * self.block.size()) }.cast::<FreeBlock>();
+            unsafe { node.write(FreeBlock { next: self.free.get() }); }
+            self.free.set(Some(node));
+        }
+        Ok(())
+    }
+}
+
+unsafe impl Allocator for BlockPool {
+    fn allocate(&self, layout: Layout) -> Result<NonNull<[u8]>, AllocError> {
+        if !self.fits(layout) {
+            return Global.allocate(layout);
+        }
+        if self.free.get().is_none() {
+            self.refill()?;
+        }
+        let node = self.free.get().ok_or(AllocError)?;
+        self.free.set(unsafe { node.as_ref() }.next);
+        Ok(NonNull::slice_from_raw_parts(node.cast::<u8>(), self.block.size()))
+    }
+
+    unsafe fn deallocate(&self, ptr: NonNull<u8>, layout: Layout) {
+        if !self.fits(layout) {
+            return unsafe { Global.deallocate(ptr, layout) };
+        }
+        let node = ptr.cast::<FreeBlock>();
+        unsafe { node.write(FreeBlock { next: self.free.get() }); }
+        self.free.set(Some(node));
+    }
+}
+
+/// Copyable handle to a `BlockPool` owned by a `World`, see `World::with_pools` and
+/// `World::own_pool`. The world frees the pool when it is dropped, unless one of its
+/// storages is still alive.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct PoolHandle(NonNull<BlockPool>);
+
+impl PoolHandle {
+    pub(crate) fn new(pool: BlockPool) -> Self {
+        Self(NonNull::from(Box::leak(Box::new(pool))))
+    }
+
+    /// # Safety
+    /// No block from the pool may be in use and the handle must not be used again.
+    pub(crate) unsafe fn free(self) {
+        drop(unsafe { Box::from_raw(self.0.as_ptr()) });
+    }
+}
+
+impl std::ops::Deref for PoolHandle {
+    type Target = BlockPool;
+
+    fn deref(&self) -> &BlockPool {
+        unsafe { self.0.as_ref() }
+    }
+}
+
+unsafe impl Allocator for PoolHandle {
+    fn allocate(&self, layout: Layout) -> Result<NonNull<[u8]>, AllocError> {
+        (**self).allocate(layout)
+    }
+
+    unsafe fn deallocate(&self, ptr: NonNull<u8>, layout: Layout) {
+        unsafe { (**self).deallocate(ptr, layout) }
+    }
+}
+
+impl Drop for BlockPool {
+    fn drop(&mut self) {
+        let chunk_layout = Layout::from_size_align(self.block.size() * self.per_chunk, self.block.align()).unwrap();
+        for chunk in self.chunks.get_mut().drain(..) {
+            unsafe { Global.deallocate(chunk, chunk_layout) };
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn freed_blocks_are_reused_before_refilling() {
+        let pool = BlockPool::new(Layout::new::<[u64; 4]>(), 2);
+        let a = pool.allocate(Layout::new::<[u64; 4]>()).unwrap();
+        let b = pool.allocate(Layout::new::<[u64; 4]>()).unwrap();
+        assert_eq!(pool.chunks(), 1);
+        unsafe { pool.deallocate(a.cast(), Layout::new::<[u64; 4]>()); }
+        let c = pool.allocate(Layout::new::<[u64; 4]>()).unwrap();
+        assert_eq!(c.cast::<u8>(), a.cast::<u8>());
+        assert_eq!(pool.chunks(), 1);
+        let _d = pool.allocate(Layout::new::<u8>()).unwrap();
+        assert_eq!(pool.chunks(), 2);
+        unsafe {
+            pool.deallocate(b.cast(), Layout::new::<[u64; 4]>());
+            pool.deallocate(c.cast(), Layout::new::<[u64; 4]>());
+        }
+    }
+
+    #[test]
+    fn oversized_requests_fall_back_to_global() {
+        let pool = BlockPool::new(Layout::new::<u64>(), 4);
+        let big = Layout::new::<[u64; 64]>();
+        let p = pool.allocate(big).unwrap();
+        assert_eq!(pool.chunks(), 0);
+        unsafe { pool.deallocate(p.cast(), big); }
+    }
+
+    #[test]
+    fn sparse_leaf_pool_serves_boxed_leaf_blocks() {
+        let pool = BlockPool::for_sparse_leaf::<u32, &BlockPool>();
+        let blocks: Vec<_> = (0..DEFAULT_BLOCKS_PER_CHUNK + 1).map(|_| SparseBlock::<u32, &BlockPool>::new_in(&pool)).collect();
+        assert_eq!(pool.chunks(), 2);
+        drop(blocks);
+        let _again = SparseBlock::<u32, &BlockPool>::new_in(&pool);
+        assert_eq!(pool.chunks(), 2);
+    }
+}
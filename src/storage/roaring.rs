@@ -0,0 +1,373 @@
+//! Roaring bitmap portable serialization (https://github.com/RoaringBitmap/RoaringFormatSpec)
+//! for streams of `(base, mask)` leaf blocks, as produced by storages, entity sets and queries.
+
+use std::fmt;
+
+use crate::storage::storage::MAX_INDEX;
+
+const SERIAL_COOKIE_NO_RUNCONTAINER: u32 = 12346;
+const SERIAL_COOKIE: u16 = 12347;
+const NO_OFFSET_THRESHOLD: usize = 4;
+const MAX_ARRAY_CARDINALITY: usize = 4096;
+const BITMAP_BYTES: usize = 8192;
+/// Leaf blocks per 2^16-value Roaring container.
+const LEAVES_PER_CONTAINER: usize = 512;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum RoaringError {
+    /// The input ends before the data its headers describe.
+    Truncated,
+    /// Neither of the two portable cookies.
+    Cookie(u32),
+    /// Container keys are not strictly increasing.
+    KeyOrder(u16),
+    /// A value does not fit the entity index space.
+    OutOfRange(u32),
+}
+
+impl fmt::Display for RoaringError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RoaringError::Truncated => write!(f, "roaring data is truncated"),
+            RoaringError::Cookie(cookie) => write!(f, "unknown roaring cookie {:#x}", cookie),
+            RoaringError::KeyOrder(key) => write!(f, "roaring container key {} out of order", key),
+            RoaringError::OutOfRange(value) => write!(f, "roaring value {} exceeds the entity index space", value),
+        }
+    }
+}
+
+impl std::error::Error for RoaringError {}
+
+enum Kind {
+    Array,
+    Bitmap,
+    Run(Vec<(u16, u16)>),
+}
+
+/// Leaf masks of one container, indexed by leaf offset within it.
+struct Container {
+    key: u16,
+    leaves: Vec<(usize, u128)>,
+}
+
+impl Container {
+    fn cardinality(&self) -> usize {
+        self.leaves.iter().map(|(_, mask)| mask.count_ones() as usize).sum()
+    }
+
+    /// Maximal runs as `(start, length - 1)`, merged across leaf boundaries.
+    fn runs(&self) -> Vec<(u16, u16)> {
+        let mut runs: Vec<(u16, u16)> = Vec::new();
+        for &(leaf, mut mask) in &self.leaves {
+            while mask != 0 {
+                let start = mask.trailing_zeros();
+                let len = (mask >> start).trailing_ones();
+                mask &= if len == 128 { 0 } else { !(((1u128 << len) - 1) << start) };
+                let value = (leaf as u32 * 128 + start) as u16;
+                match runs.last_mut() {
+                    Some((s, l)) if *s as u32 + *l as u32 + 1 == value as u32 => *l += len as u16,
+                    _ => runs.push((value, len as u16 - 1)),
+                }
+            }
+        }
+        runs
+    }
+
+    /// Smallest encoding, preferring runs as `runOptimize` does.
+    fn kind(&self) -> Kind {
+        let card = self.cardinality();
+        let runs = self.runs();
+        let plain = if card <= MAX_ARRAY_CARDINALITY { 2 * card } else { BITMAP_BYTES };
+        if 2 + 4 * runs.len() < plain {
+            Kind::Run(runs)
+        } else if card <= MAX_ARRAY_CARDINALITY {
+            Kind::Array
+        } else {
+            Kind::Bitmap
+        }
+    }
+}
+
+/// Serialize ascending, non-overlapping `(base, mask)` leaf blocks.
+pub fn encode(blocks: impl IntoIterator<Item = (u32, u128)>) -> Vec<u8> {
+    let mut containers: Vec<Container> = Vec::new();
+    for (base, mask) in blocks {
+        if mask == 0 { continue; }
+        let key = (base >> 16) as u16;
+        let leaf = ((base & 0xffff) >> 7) as usize;
+        match containers.last_mut() {
+            Some(c) if c.key == key => c.leaves.push((leaf, mask)),
+            _ => containers.push(Container { key, leaves: vec![(leaf, mask)] }),
+        }
+    }
+    let kinds: Vec<Kind> = containers.iter().map(Container::kind).collect();
+    let has_runs = kinds.iter().any(|k| matches!(k, Kind::Run(_)));
+    let size = containers.len();
+
+    let mut out = Vec::new();
+    if has_runs {
+        out.extend_from_slice(&(SERIAL_COOKIE as u32 | ((size as u32 - 1) << 16)).to_le_bytes());
+        let mut bitset = vec![0u8; size.div_ceil(8)];
+        for (i, kind) in kinds.iter().enumerate() {
+            if matches!(kind, Kind::Run(_)) {
+                bitset[i / 8] |= 1 << (i % 8);
+            }
+        }
+        out.extend_from_slice(&bitset);
+    } else {
+        out.extend_from_slice(&SERIAL_COOKIE_NO_RUNCONTAINER.to_le_bytes());
+        out.extend_from_slice(&(size as u32).to_le_bytes());
+    }
+    for c in &containers {
+        out.extend_from_slice(&c.key.to_le_bytes());
+        out.extend_from_slice(&((c.cardinality() - 1) as u16).to_le_bytes());
+    }
+
+    let mut bodies = Vec::with_capacity(size);
+    for (c, kind) in containers.iter().zip(&kinds) {
+        let mut body = Vec::new();
+        match kind {
+            Kind::Array => {
+                for &(leaf, mut mask) in &c.leaves {
+                    while mask != 0 {
+                        body.extend_from_slice(&((leaf as u32 * 128 + mask.trailing_zeros()) as u16).to_le_bytes());
+                        mask &= mask - 1;
+                    }
+                }
+            }
+            Kind::Bitmap => {
+                let mut words = [0u64; LEAVES_PER_CONTAINER * 2];
+                for &(leaf, mask) in &c.leaves {
+                    words[leaf * 2] = mask as u64;
+                    words[leaf * 2 + 1] = (mask >> 64) as u64;
+                }
+                for word in words {
+                    body.extend_from_slice(&word.to_le_bytes());
+                }
+            }
+            Kind::Run(runs) => {
+                body.extend_from_slice(&(runs.len() as u16).to_le_bytes());
+                for &(start, len) in runs {
+                    body.extend_from_slice(&start.to_le_bytes());
+                    body.extend_from_slice(&len.to_le_bytes());
+                }
+            }
+        }
+        bodies.push(body);
+    }
+
+    if !has_runs || size >= NO_OFFSET_THRESHOLD {
+        let mut offset = out.len() + 4 * size;
+        for body in &bodies {
+            out.extend_from_slice(&(offset as u32).to_le_bytes());
+            offset += body.len();
+        }
+    }
+    for body in bodies {
+        out.extend_from_slice(&body);
+    }
+    out
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, n: usize) -> Result<&'a [u8], RoaringError> {
+        let end = self.pos.checked_add(n).filter(|&end| end <= self.bytes.len()).ok_or(RoaringError::Truncated)?;
+        let slice = &self.bytes[self.pos..end];
+        self.pos = end;
+        Ok(slice)
+    }
+
+    fn u16(&mut self) -> Result<u16, RoaringError> {
+        Ok(u16::from_le_bytes(self.take(2)?.try_into().unwrap()))
+    }
+
+    fn u32(&mut self) -> Result<u32, RoaringError> {
+        Ok(u32::from_le_bytes(self.take(4)?.try_into().unwrap()))
+    }
+
+    fn u64(&mut self) -> Result<u64, RoaringError> {
+        Ok(u64::from_le_bytes(self.take(8)?.try_into().unwrap()))
+    }
+}
+
+/// Deserialize into ascending `(base, mask)` leaf blocks. Offset headers are skipped,
+/// containers are read back to back as the format lays them out.
+pub fn decode(bytes: &[u8]) -> Result<Vec<(u32, u128)>, RoaringError> {
+    let mut r = Reader { bytes, pos: 0 };
+    let cookie = r.u32()?;
+    let (size, run_bitset) = if cookie == SERIAL_COOKIE_NO_RUNCONTAINER {
+        (r.u32()? as usize, None)
+    } else if cookie & 0xffff == SERIAL_COOKIE as u32 {
+        let size = (cookie >> 16) as usize + 1;
+        (size, Some(r.take(size.div_ceil(8))?))
+    } else {
+        return Err(RoaringError::Cookie(cookie));
+    };
+
+    let mut headers = Vec::with_capacity(size.min(bytes.len() / 4));
+    for _ in 0..size {
+        headers.push((r.u16()?, r.u16()? as usize + 1));
+    }
+    if run_bitset.is_none() || size >= NO_OFFSET_THRESHOLD {
+        r.take(4 * size)?;
+    }
+
+    let mut blocks: Vec<(u32, u128)> = Vec::new();
+    let mut prev_key = None;
+    for (i, &(key, card)) in headers.iter().enumerate() {
+        if prev_key.is_some_and(|prev| key <= prev) {
+            return Err(RoaringError::KeyOrder(key));
+        }
+        prev_key = Some(key);
+        let high = (key as u32) << 16;
+        let mut leaves = vec![0u128; LEAVES_PER_CONTAINER];
+        let is_run = run_bitset.is_some_and(|bits| bits[i / 8] & (1 << (i % 8)) != 0);
+        if is_run {
+            for _ in 0..r.u16()? {
+                let start = r.u16()? as usize;
+                let len = r.u16()? as usize + 1;
+                if start + len > 1 << 16 {
+                    return Err(RoaringError::OutOfRange(high + (start + len - 1) as u32));
+                }
+                for value in start..start + len {
+                    leaves[value >> 7] |= 1u128 << (value & 127);
+                }
+            }
+        } else if card <= MAX_ARRAY_CARDINALITY {
+            for _ in 0..card {
+                let value = r.u16()? as usize;
+                leaves[value >> 7] |= 1u128 << (value & 127);
+            }
+        } else {
+            for leaf in leaves.iter_mut() {
+                *leaf = r.u64()? as u128 | ((r.u64()? as u128) << 64);
+            }
+        }
+        for (leaf, mask) in leaves.into_iter().enumerate() {
+            if mask == 0 { continue; }
+            let base = high | ((leaf as u32) << 7);
+            if base >= MAX_INDEX {
+                return Err(RoaringError::OutOfRange(base | mask.trailing_zeros()));
+            }
+            blocks.push((base, mask));
+        }
+    }
+    Ok(blocks)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn le16(out: &mut Vec<u8>, v: u16) {
+        out.extend_from_slice(&v.to_le_bytes());
+    }
+
+    fn le32(out: &mut Vec<u8>, v: u32) {
+        out.extend_from_slice(&v.to_le_bytes());
+    }
+
+    fn blocks_of(values: impl IntoIterator<Item = u32>) -> Vec<(u32, u128)> {
+        let mut blocks: Vec<(u32, u128)> = Vec::new();
+        for v in values {
+            match blocks.last_mut() {
+                Some((base, mask)) if *base == v & !127 => *mask |= 1u128 << (v & 127),
+                _ => blocks.push((v & !127, 1u128 << (v & 127))),
+            }
+        }
+        blocks
+    }
+
+    #[test]
+    fn array_containers_use_the_no_run_cookie_and_offsets() {
+        // {1, 5, 65536 + 7}: two array containers, offsets always present without runs
+        let mut expected = Vec::new();
+        le32(&mut expected, 12346);
+        le32(&mut expected, 2);
+        le16(&mut expected, 0);
+        le16(&mut expected, 1);
+        le16(&mut expected, 1);
+        le16(&mut expected, 0);
+        le32(&mut expected, 24);
+        le32(&mut expected, 28);
+        le16(&mut expected, 1);
+        le16(&mut expected, 5);
+        le16(&mut expected, 7);
+
+        let blocks = blocks_of([1, 5, 65536 + 7]);
+        assert_eq!(encode(blocks.clone()), expected);
+        assert_eq!(decode(&expected).unwrap(), blocks);
+    }
+
+    #[test]
+    fn run_containers_set_the_run_bitset_and_skip_offsets_below_threshold() {
+        // [10, 300) as one run crossing leaf boundaries, plus the array {70000}
+        let mut expected = Vec::new();
+        le32(&mut expected, 12347 | (1 << 16));
+        expected.push(0b01);
+        le16(&mut expected, 0);
+        le16(&mut expected, 289);
+        le16(&mut expected, 1);
+        le16(&mut expected, 0);
+        le16(&mut expected, 1);
+        le16(&mut expected, 10);
+        le16(&mut expected, 289);
+        le16(&mut expected, (70000 - 65536) as u16);
+
+        let blocks = blocks_of((10..300).chain([70000]));
+        assert_eq!(encode(blocks.clone()), expected);
+        assert_eq!(decode(&expected).unwrap(), blocks);
+    }
+
+    #[test]
+    fn dense_scattered_values_use_bitmap_containers() {
+        let values: Vec<u32> = (0..65536).step_by(2).collect();
+        let bytes = encode(blocks_of(values.iter().copied()));
+        assert_eq!(&bytes[..8], &[0x3a, 0x30, 0, 0, 1, 0, 0, 0]);
+        assert_eq!(&bytes[8..12], &[0, 0, 0xff, 0x7f]);
+        assert_eq!(bytes.len(), 16 + 8192);
+        assert_eq!(&bytes[16..24], &0x5555_5555_5555_5555u64.to_le_bytes());
+        assert_eq!(decode(&bytes).unwrap(), blocks_of(values));
+    }
+
+    /// The spec's sample files, copied byte for byte from RoaringFormatSpec `testdata/`
+    /// (the same values serialized with and without `runOptimize`):
+    /// bitmapwithruns.bin     48056 B, sha256 1f1909bfdd354fa2f0694fe88b8076833ca5383ad9fc3f68f2709c84a2ab70e3
+    /// bitmapwithoutruns.bin  72616 B, sha256 d719ae2e0150a362ef7cf51c361527585891f01460b1a92bcfb6a7257282a442
+    const WITH_RUNS: &[u8] = include_bytes!("testdata/bitmapwithruns.bin");
+    const WITHOUT_RUNS: &[u8] = include_bytes!("testdata/bitmapwithoutruns.bin");
+
+    #[test]
+    fn spec_sample_files_decode_and_encode_byte_exact() {
+        let values = (0..100_000).step_by(1000).chain((100_000..200_000).map(|k| 3 * k)).chain(700_000..800_000);
+        let blocks = blocks_of(values);
+        assert_eq!(decode(WITH_RUNS).unwrap(), blocks);
+        assert_eq!(decode(WITHOUT_RUNS).unwrap(), blocks);
+        // the encoder always picks the smallest container like `runOptimize`, so only the
+        // run-optimized file can be reproduced
+        assert_eq!(encode(blocks), WITH_RUNS);
+    }
+
+    #[test]
+    fn malformed_input_is_rejected() {
+        assert_eq!(decode(&[1, 2]), Err(RoaringError::Truncated));
+        assert_eq!(decode(&[0, 0, 0, 0]), Err(RoaringError::Cookie(0)));
+        let mut bytes = encode(blocks_of([1]));
+        bytes.pop();
+        assert_eq!(decode(&bytes), Err(RoaringError::Truncated));
+
+        let mut high = Vec::new();
+        le32(&mut high, 12346);
+        le32(&mut high, 1);
+        le16(&mut high, 40);
+        le16(&mut high, 0);
+        le32(&mut high, 16);
+        le16(&mut high, 3);
+        assert_eq!(decode(&high), Err(RoaringError::OutOfRange((40 << 16) | 3)));
+    }
+}
@@ -1,18 +1,156 @@
 use std::any::{Any, TypeId};
-use std::alloc::Allocator;
+use std::alloc::{AllocError, Allocator, Layout, handle_alloc_error};
+use std::ops::Range;
 use std::collections::HashMap;
-use std::rc::Rc;
+use std::rc::{Rc, Weak};
 use std::cell::RefCell;
 use bumpalo::Bump;
-use crate::component::Component;
+use crate::component::{Component, HookSink};
+use crate::component::hooks::Discard;
 use crate::storage::block::{DenseBlock, SparseBlock};
+use crate::storage::validate::{BlockPath, Violation};
+use crate::view::View;
+use crate::world::{Entity, EntitySet};
 
+/// Number of entity indices addressable by a three-level storage tree (128^3).
+pub const MAX_INDEX: u32 = 1 << 21;
+
+/// Split an entity index into root, L1 and leaf slot coordinates.
+#[inline(always)]
+pub fn split_index(index: u32) -> (usize, usize, usize) {
+    debug_assert!(index < MAX_INDEX, "index {} out of range for storage tree", index);
+    (((index >> 14) & 127) as usize, ((index >> 7) & 127) as usize, (index & 127) as usize)
+}
+
+/// Set bits of `mask` from lowest to highest.
+fn bits(mut mask: u128) -> impl Iterator<Item = u32> {
+    std::iter::from_fn(move || {
+        if mask == 0 { return None; }
+        let bit = mask.trailing_zeros();
+        mask &= mask - 1;
+        Some(bit)
+    })
+}
+
+/// Slots `from..to` of a leaf block, `to <= 128`.
+fn slot_mask(from: u32, to: u32) -> u128 {
+    let below = |n: u32| if n >= 128 { u128::MAX } else { (1u128 << n) - 1 };
+    below(to) & !below(from)
+}
+
+/// Type-erased operations the world applies to every storage, whatever its component type.
 pub trait Storage {
+    /// Drop the value at `index` if there is one, as its entity goes away; returns whether
+    /// it was present. The slot is left as a never-used index would be, so a `PREINIT`
+    /// component holds its `init` value again for the next entity there.
+    fn discard(&mut self, index: u32) -> bool;
+
+    /// Move the value at `from` to `to`, if there is one, keeping it skipped if it was.
+    /// No hooks run: the entity keeps its value, only its index changes. A value already
+    /// at `to` is dropped, and `from` is left as `discard` leaves a slot.
+    fn relocate(&mut self, from: u32, to: u32);
+
+    /// Check every tree invariant; see `SparseStorage::validate`.
+    fn validate(&self) -> Vec<Violation>;
+
+    /// See `SparseStorage::skip`.
+    fn skip(&mut self, index: u32) -> bool;
+
+    /// See `SparseStorage::unskip`.
+    fn unskip(&mut self, index: u32) -> bool;
 
+    /// `(presence_mask, absence_mask)` of the block at `path`, zero if it is not allocated.
+    fn masks(&self, path: BlockPath) -> (u128, u128);
+
+    /// Number of visible values; see `SparseStorage::len`.
+    fn len(&self) -> usize;
+
+    fn component_name(&self) -> &'static str;
+
+    /// Record every index this storage changes from now on into `dirty`, until it is dropped.
+    fn watch(&mut self, dirty: Weak<RefCell<EntitySet>>);
+
+    fn name(&self) -> &'static str {
+        std::any::type_name::<Self>()
+    }
 }
 
-impl<T: Component, A: Allocator + Copy + Default> Storage for SparseStorage<T, A> {
+impl<T: Component, A: Allocator + Copy, L: Allocator + Copy> Storage for SparseStorage<T, A, L> {
+    fn discard(&mut self, index: u32) -> bool {
+        let removed = self.remove(index).is_some();
+        if T::PREINIT {
+            self.unsettle(index);
+        }
+        removed
+    }
+
+    fn relocate(&mut self, from: u32, to: u32) {
+        let Some((leaf, l)) = self.leaf_mut(from) else { return };
+        if !leaf.holds(l as u32) { return; }
+        let skipped = !leaf.has(l as u32);
+        leaf.clear_all(1u128 << l);
+        let value = unsafe { leaf.data.get_unchecked(l).assume_init_read() };
+        self.trim(from);
+        if T::PREINIT {
+            self.unsettle(from);
+        }
+
+        let leaf = match self.try_leaf_mut(to) {
+            Ok(leaf) => leaf,
+            Err(AllocError) => handle_alloc_error(Layout::new::<SparseBlock<T, L>>()),
+        };
+        let t = (to & 127) as usize;
+        // whatever `to` held is stale, or the `init` value of a `PREINIT` slot
+        drop(leaf.put(t, value));
+        if skipped {
+            leaf.skip_all(1u128 << t);
+        } else {
+            leaf.set_all(1u128 << t);
+        }
+        if T::PREINIT {
+            self.settle(to, 1u128 << t);
+        }
+        self.trim(to);
+    }
+
+    fn validate(&self) -> Vec<Violation> {
+        SparseStorage::validate(self)
+    }
+
+    fn skip(&mut self, index: u32) -> bool {
+        SparseStorage::skip(self, index)
+    }
+
+    fn len(&self) -> usize {
+        SparseStorage::len(self)
+    }
+
+    fn component_name(&self) -> &'static str {
+        std::any::type_name::<T>()
+    }
+
+    fn watch(&mut self, dirty: Weak<RefCell<EntitySet>>) {
+        self.watchers.push(dirty);
+    }
+
+    fn unskip(&mut self, index: u32) -> bool {
+        SparseStorage::unskip(self, index)
+    }
 
+    fn masks(&self, path: BlockPath) -> (u128, u128) {
+        let (r, m, _) = split_index(path.base);
+        if path.level == 0 {
+            return (self.root.presence_mask, self.root.absence_mask);
+        }
+        if !self.root.holds(r as u32) { return (0, 0); }
+        let mid = unsafe { self.root.data.get_unchecked(r).assume_init_ref() };
+        if path.level == 1 {
+            return (mid.presence_mask, mid.absence_mask);
+        }
+        if !mid.holds(m as u32) { return (0, 0); }
+        let leaf = unsafe { mid.data.get_unchecked(m).assume_init_ref() };
+        (leaf.presence_mask, leaf.absence_mask)
+    }
 }
 
 pub struct DenseStorage<T: Component, A: Allocator + Copy> {
@@ -20,14 +158,524 @@ pub struct DenseStorage<T: Component, A: Allocator + Copy> {
     pub alloc: A
 }
 
-pub struct SparseStorage<T: Component, A: Allocator + Copy + Default> {
-    pub root: SparseBlock<Box<SparseBlock<Box<SparseBlock<T, A>, A>, A>, A>, A>,
-    pub alloc: A
+/// `alloc` serves the root and L1 blocks, `leaf_alloc` the 128-slot leaf blocks holding `T`;
+/// the two may be of different types, e.g. a bump arena for the inner blocks and a
+/// `BlockPool` for the leaves.
+/// `watchers` are told of every index `trim` runs for, see `Storage::watch`, and
+/// `hooks` receives the component lifecycle hooks, see `set_hooks`.
+///
+/// For `PREINIT` components, `settled` holds the indices that were inserted or removed
+/// at least once. Materializing a leaf initializes only the other slots, so removed
+/// values stay removed after their leaf is freed, and inserting over a slot that still
+/// holds its `init` value reports no previous value.
+pub struct SparseStorage<T: Component, A: Allocator + Copy, L: Allocator + Copy = A> {
+    pub(crate) root: SparseBlock<Box<SparseBlock<Box<SparseBlock<T, L>, L>, A>, A>, A>,
+    pub alloc: A,
+    pub leaf_alloc: L,
+    watchers: Vec<Weak<RefCell<EntitySet>>>,
+    hooks: Rc<dyn HookSink<T>>,
+    settled: EntitySet,
 }
 
-impl<T: Component, A: Allocator + Copy + Default>  SparseStorage<T, A> {
+impl<T: Component, A: Allocator + Copy>  SparseStorage<T, A> {
     pub fn new(alloc: A) -> Self {
-        Self { root: SparseBlock::new(alloc), alloc }
+        Self::new_in(alloc, alloc)
+    }
+}
+
+impl<T: Component, A: Allocator + Copy, L: Allocator + Copy>  SparseStorage<T, A, L> {
+    /// Storage whose inner blocks and leaf blocks come from different allocators.
+    pub fn new_in(alloc: A, leaf_alloc: L) -> Self {
+        Self { root: SparseBlock::new(alloc), alloc, leaf_alloc, watchers: Vec::new(), hooks: Rc::new(Discard), settled: EntitySet::new() }
+    }
+
+    /// Send lifecycle hooks to `hooks`, usually the world's command buffer. Without a
+    /// sink the hooks still run but whatever they queue is dropped.
+    pub fn set_hooks(&mut self, hooks: Rc<dyn HookSink<T>>) {
+        self.hooks = hooks;
+    }
+
+    pub fn contains(&self, index: u32) -> bool {
+        self.leaf(index).is_some_and(|(leaf, l)| leaf.has(l as u32))
+    }
+
+    pub fn get(&self, index: u32) -> Option<&T> {
+        let (leaf, l) = self.leaf(index)?;
+        if !leaf.has(l as u32) { return None; }
+        Some(unsafe { leaf.data.get_unchecked(l).assume_init_ref() })
+    }
+
+    pub fn get_mut(&mut self, index: u32) -> Option<&mut T> {
+        let (leaf, l) = self.leaf_mut(index)?;
+        if !leaf.has(l as u32) { return None; }
+        Some(unsafe { leaf.data.get_unchecked_mut(l).assume_init_mut() })
+    }
+
+    /// Like `get`, but also returns skipped values.
+    pub fn get_any(&self, index: u32) -> Option<&T> {
+        let (leaf, l) = self.leaf(index)?;
+        if !leaf.holds(l as u32) { return None; }
+        Some(unsafe { leaf.data.get_unchecked(l).assume_init_ref() })
+    }
+
+    /// Like `get_mut`, but also returns skipped values.
+    pub fn get_any_mut(&mut self, index: u32) -> Option<&mut T> {
+        let (leaf, l) = self.leaf_mut(index)?;
+        if !leaf.holds(l as u32) { return None; }
+        Some(unsafe { leaf.data.get_unchecked_mut(l).assume_init_mut() })
+    }
+
+    /// Insert or overwrite the value at `index`, returning the previous one.
+    /// Aborts through `handle_alloc_error` if a block cannot be allocated.
+    pub fn insert(&mut self, index: u32, value: T) -> Option<T> {
+        match self.try_insert(index, value) {
+            Ok(prev) => prev,
+            Err(AllocError) => handle_alloc_error(Layout::new::<SparseBlock<T, L>>()),
+        }
+    }
+
+    /// Insert or overwrite the value at `index`, returning the previous one.
+    /// A skipped value is replaced as well and the slot becomes visible again.
+    /// Missing blocks are allocated before any mask is touched, so on `AllocError`
+    /// the tree is left exactly as it was and `value` is dropped.
+    pub fn try_insert(&mut self, index: u32, value: T) -> Result<Option<T>, AllocError> {
+        let leaf = self.try_leaf_mut(index)?;
+        let l = (index & 127) as usize;
+        let mut prev = leaf.put(l, value);
+        leaf.set_all(1u128 << l);
+        if T::PREINIT && self.settle(index, 1u128 << l) & (1u128 << l) == 0 {
+            prev = None;
+        }
+        self.trim(index);
+        if T::HOOKS {
+            if let Some(old) = &mut prev {
+                self.sink().on_replace(old, Entity(index));
+            }
+            self.run_hook(index, |sink, value, entity| sink.on_insert(value, entity));
+        }
+        Ok(prev)
+    }
+
+    /// Make sure the leaf block covering `index` exists. Together with `write_slot`
+    /// and `commit` this lets callers batch presence updates per leaf block;
+    /// `trim` undoes a reservation that is not followed by a commit.
+    pub fn try_reserve(&mut self, index: u32) -> Result<(), AllocError> {
+        self.try_leaf_mut(index).map(|_| ())
+    }
+
+    /// Bring the parent bits and counts on the path to `index` in line with the leaf
+    /// below them, freeing the leaf and L1 blocks if they hold nothing.
+    pub fn trim(&mut self, index: u32) {
+        self.touch(index);
+        let (r, m, _) = split_index(index);
+        if !self.root.holds(r as u32) { return; }
+        let mid = unsafe { self.root.data.get_unchecked_mut(r).assume_init_mut() };
+        let mut delta = 0;
+        if mid.holds(m as u32) {
+            delta = unsafe { mid.data.get_unchecked_mut(m).assume_init_mut() }.sync_len();
+            mid.header.len = (mid.header.len as i64 + delta) as u32;
+        }
+        mid.recompute_all(1u128 << m);
+        self.root.header.len = (self.root.header.len as i64 + delta) as u32;
+        self.root.recompute_all(1u128 << r);
+    }
+
+    /// Mark `index` dirty for every live watcher, dropping the ones that are gone.
+    fn touch(&mut self, index: u32) {
+        if self.watchers.is_empty() { return; }
+        self.watchers.retain(|dirty| match dirty.upgrade() {
+            Some(dirty) => {
+                dirty.borrow_mut().insert(Entity(index));
+                true
+            }
+            None => false,
+        });
+    }
+
+    /// Number of visible values, from the count cached in the root.
+    pub fn len(&self) -> usize {
+        self.root.header.len as usize
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Index of the `k`-th visible value in index order, found by descending through
+    /// the cached subtree counts.
+    pub fn nth(&self, mut k: usize) -> Option<u32> {
+        if k >= self.len() { return None; }
+        let (r, mid) = bits(self.root.presence_mask)
+            .map(|r| (r, unsafe { self.root.data.get_unchecked(r as usize).assume_init_ref() }))
+            .find(|(_, mid)| {
+                let len = mid.header.len as usize;
+                if k < len { return true; }
+                k -= len;
+                false
+            })?;
+        let (m, leaf) = bits(mid.presence_mask)
+            .map(|m| (m, unsafe { mid.data.get_unchecked(m as usize).assume_init_ref() }))
+            .find(|(_, leaf)| {
+                let len = leaf.header.len as usize;
+                if k < len { return true; }
+                k -= len;
+                false
+            })?;
+        let l = bits(leaf.presence_mask).nth(k)?;
+        Some((r << 14) | (m << 7) | l)
+    }
+
+    /// Number of visible values at indices below `index`.
+    pub fn rank(&self, index: u32) -> usize {
+        let (r, m, l) = split_index(index);
+        let below = |slot: usize| if slot == 0 { 0 } else { u128::MAX >> (128 - slot) };
+        let mut rank: usize = bits(self.root.presence_mask & below(r))
+            .map(|r| unsafe { self.root.data.get_unchecked(r as usize).assume_init_ref() }.header.len as usize)
+            .sum();
+        if !self.root.has(r as u32) { return rank; }
+        let mid = unsafe { self.root.data.get_unchecked(r).assume_init_ref() };
+        rank += bits(mid.presence_mask & below(m))
+            .map(|m| unsafe { mid.data.get_unchecked(m as usize).assume_init_ref() }.header.len as usize)
+            .sum::<usize>();
+        if !mid.has(m as u32) { return rank; }
+        let leaf = unsafe { mid.data.get_unchecked(m).assume_init_ref() };
+        rank + (leaf.presence_mask & below(l)).count_ones() as usize
+    }
+
+    /// Pick a visible value uniformly at random. `random` must be uniformly distributed
+    /// over `u64`; it is mapped onto `0..len()` by a widening multiply.
+    pub fn sample(&self, random: u64) -> Option<u32> {
+        let k = ((random as u128 * self.len() as u128) >> 64) as usize;
+        self.nth(k)
+    }
+
+    /// Write `value` into the reserved slot at `index` without touching any mask.
+    /// An existing value is replaced and returned.
+    pub fn write_slot(&mut self, index: u32, value: T) -> Option<T> {
+        let (leaf, l) = self.leaf_mut(index).expect("write_slot on an unreserved block");
+        let mut prev = leaf.put(l, value);
+        if T::PREINIT && self.settle(index, 1u128 << l) & (1u128 << l) == 0 {
+            prev = None;
+        }
+        if let (true, Some(old)) = (T::HOOKS, &mut prev) {
+            self.sink().on_replace(old, Entity(index));
+        }
+        prev
+    }
+
+    /// Reserve the leaf blocks covering `range`. Each L1 block gets a single mask update
+    /// for all leaves newly allocated under it.
+    pub fn reserve_range(&mut self, range: Range<u32>) {
+        let mut base = range.start & !127;
+        while base < range.end {
+            let (r, _, _) = split_index(base);
+            let l1_end = (((base >> 14) + 1) << 14).min(range.end);
+            if !self.root.holds(r as u32) {
+                let mid = SparseBlock::new_in(self.alloc);
+                unsafe { self.root.data.get_unchecked_mut(r).write(mid); }
+                self.root.set_all(1u128 << r);
+            }
+            let mid = unsafe { self.root.data.get_unchecked_mut(r).assume_init_mut() };
+            let mut added = 0u128;
+            while base < l1_end {
+                let m = split_index(base).1;
+                if !mid.holds(m as u32) {
+                    let mut leaf = SparseBlock::new_in(self.leaf_alloc);
+                    if T::PREINIT {
+                        leaf.init_all(base, !self.settled.masks(BlockPath { level: 2, base }));
+                    }
+                    unsafe { mid.data.get_unchecked_mut(m).write(leaf); }
+                    added |= 1u128 << m;
+                }
+                base += 128;
+            }
+            mid.set_all(added);
+        }
+    }
+
+    /// Leaf block covering `index`, if it has been allocated. Callers write values through
+    /// it; its masks change only by `commit`, which keeps the counts.
+    pub(crate) fn leaf_block_mut(&mut self, index: u32) -> Option<&mut SparseBlock<T, L>> {
+        self.leaf_mut(index).map(|(leaf, _)| leaf)
+    }
+
+    /// Mark the slots in `mask` of the leaf block containing `base` as present.
+    ///
+    /// # Safety
+    /// Every slot in `mask` must have been written by `write_slot`.
+    pub unsafe fn commit(&mut self, base: u32, mask: u128) {
+        let (leaf, _) = self.leaf_mut(base).expect("commit on an unreserved block");
+        leaf.set_all(mask);
+        if T::PREINIT {
+            self.settle(base, mask);
+        }
+        self.trim(base);
+        if T::HOOKS {
+            for l in bits(mask) {
+                self.run_hook(base | l, |sink, value, entity| sink.on_insert(value, entity));
+            }
+        }
+    }
+
+    /// Mark every slot of `range` present, with one parent and count update per L1 block
+    /// for all the leaves under it.
+    ///
+    /// # Safety
+    /// Every slot in `range` must have been written into a reserved leaf block.
+    pub unsafe fn commit_range(&mut self, range: Range<u32>) {
+        let mut index = range.start;
+        while index < range.end {
+            let first = index;
+            let (r, _, _) = split_index(index);
+            let l1_end = (((index >> 14) + 1) << 14).min(range.end);
+            assert!(self.root.holds(r as u32), "commit_range on an unreserved block");
+            let mid = unsafe { self.root.data.get_unchecked_mut(r).assume_init_mut() };
+            let (mut leaves, mut delta) = (0u128, 0i64);
+            while index < l1_end {
+                let base = index & !127;
+                let end = (base + 128).min(l1_end);
+                let m = split_index(base).1;
+                assert!(mid.holds(m as u32), "commit_range on an unreserved block");
+                let leaf = unsafe { mid.data.get_unchecked_mut(m).assume_init_mut() };
+                leaf.set_all(slot_mask(index - base, end - base));
+                delta += leaf.sync_len();
+                leaves |= 1u128 << m;
+                index = end;
+            }
+            mid.header.len = (mid.header.len as i64 + delta) as u32;
+            mid.recompute_all(leaves);
+            self.root.header.len = (self.root.header.len as i64 + delta) as u32;
+            self.root.recompute_all(1u128 << r);
+
+            let mut base = first & !127;
+            while base < l1_end {
+                self.touch(base);
+                if T::PREINIT {
+                    self.settle(base, slot_mask(first.max(base) - base, (base + 128).min(l1_end) - base));
+                }
+                base += 128;
+            }
+            if T::HOOKS {
+                for i in first..l1_end {
+                    self.run_hook(i, |sink, value, entity| sink.on_insert(value, entity));
+                }
+            }
+        }
+    }
+
+    /// Hide the value at `index` from `get` and `views` without dropping it.
+    /// Returns false if there is no visible value.
+    pub fn skip(&mut self, index: u32) -> bool {
+        let Some((leaf, l)) = self.leaf_mut(index) else { return false };
+        if !leaf.has(l as u32) { return false; }
+        leaf.skip_all(1u128 << l);
+        self.trim(index);
+        true
+    }
+
+    /// Make a skipped value visible again. Returns false if `index` was not skipped.
+    pub fn unskip(&mut self, index: u32) -> bool {
+        if !self.is_skipped(index) { return false; }
+        let (leaf, l) = self.leaf_mut(index).unwrap();
+        leaf.set_all(1u128 << l);
+        self.trim(index);
+        true
+    }
+
+    pub fn is_skipped(&self, index: u32) -> bool {
+        self.leaf(index).is_some_and(|(leaf, l)| leaf.holds(l as u32) && !leaf.has(l as u32))
+    }
+
+    /// Leaf blocks with visible values as `(base, presence_mask)`, in index order.
+    pub fn blocks(&self) -> impl Iterator<Item = (u32, u128)> + '_ {
+        bits(self.root.presence_mask)
+            .map(|r| (r, unsafe { self.root.data.get_unchecked(r as usize).assume_init_ref() }))
+            .flat_map(|(r, mid)| {
+                bits(mid.presence_mask).map(move |m| {
+                    let leaf = unsafe { mid.data.get_unchecked(m as usize).assume_init_ref() };
+                    ((r << 14) | (m << 7), leaf.presence_mask)
+                })
+            })
+    }
+
+    /// Serialize the visible indices in the Roaring bitmap portable format.
+    pub fn to_roaring(&self) -> Vec<u8> {
+        crate::storage::roaring::encode(self.blocks())
+    }
+
+    /// Runs of visible values with the index of their first slot, in index order.
+    /// Subtrees whose values are all skipped are passed over without being visited.
+    pub fn views(&self) -> impl Iterator<Item = (u32, View<'_, T>)> + '_ {
+        self.root
+            .views_complement()
+            .with_offsets()
+            .flat_map(|(r0, mids)| mids.as_slice().iter().enumerate().map(move |(k, mid)| ((r0 + k) as u32, &**mid)))
+            .flat_map(|(r, mid)| {
+                mid.views_complement().with_offsets().flat_map(move |(m0, leaves)| {
+                    leaves.as_slice().iter().enumerate().map(move |(k, leaf)| ((r << 14) | (((m0 + k) as u32) << 7), &**leaf))
+                })
+            })
+            .flat_map(|(base, leaf)| leaf.views_complement().with_offsets().map(move |(l, run)| (base + l as u32, run)))
+    }
+
+    /// Like `get_mut`, but for `Component::PREINIT` components a missing leaf block is
+    /// allocated and materialized first, so every index reads as present until removed.
+    pub fn get_or_init_mut(&mut self, index: u32) -> Option<&mut T> {
+        if T::PREINIT && !self.has_leaf(index) {
+            if self.try_leaf_mut(index).is_err() {
+                handle_alloc_error(Layout::new::<SparseBlock<T, L>>());
+            }
+            self.trim(index);
+        }
+        self.get_mut(index)
+    }
+
+    /// Return the leaf block covering `index`, allocating missing blocks on the way down.
+    /// New leaves of `PREINIT` components have their unsettled slots materialized through
+    /// `Component::init`.
+    fn try_leaf_mut(&mut self, index: u32) -> Result<&mut SparseBlock<T, L>, AllocError> {
+        let (r, m, _) = split_index(index);
+        let has_mid = self.root.holds(r as u32);
+        let has_leaf = has_mid && unsafe { self.root.data.get_unchecked(r).assume_init_ref() }.holds(m as u32);
+
+        let new_mid = if has_mid { None } else { Some(SparseBlock::try_new_in(self.alloc)?) };
+        let new_leaf = if has_leaf { None } else { Some(SparseBlock::try_new_in(self.leaf_alloc)?) };
+
+        if let Some(mid) = new_mid {
+            unsafe { self.root.data.get_unchecked_mut(r).write(mid); }
+            self.root.set_all(1u128 << r);
+        }
+        let mid = unsafe { self.root.data.get_unchecked_mut(r).assume_init_mut() };
+        if let Some(mut leaf) = new_leaf {
+            if T::PREINIT {
+                let base = index & !127;
+                leaf.init_all(base, !self.settled.masks(BlockPath { level: 2, base }));
+            }
+            unsafe { mid.data.get_unchecked_mut(m).write(leaf); }
+            mid.set_all(1u128 << m);
+        }
+        Ok(unsafe { mid.data.get_unchecked_mut(m).assume_init_mut() })
+    }
+
+    fn leaf_mut(&mut self, index: u32) -> Option<(&mut SparseBlock<T, L>, usize)> {
+        let (r, m, l) = split_index(index);
+        if !self.root.holds(r as u32) { return None; }
+        let mid = unsafe { self.root.data.get_unchecked_mut(r).assume_init_mut() };
+        if !mid.holds(m as u32) { return None; }
+        Some((unsafe { mid.data.get_unchecked_mut(m).assume_init_mut() }, l))
+    }
+
+    fn has_leaf(&self, index: u32) -> bool {
+        self.leaf(index).is_some()
+    }
+
+    /// Remove the value at `index`, visible or skipped. Blocks left empty are freed
+    /// and their parent bits cleared.
+    pub fn remove(&mut self, index: u32) -> Option<T> {
+        let (leaf, l) = self.leaf_mut(index)?;
+        if !leaf.holds(l as u32) { return None; }
+        leaf.clear_all(1u128 << l);
+        let mut value = unsafe { leaf.data.get_unchecked(l).assume_init_read() };
+        if T::PREINIT {
+            self.settle(index, 1u128 << l);
+        }
+        self.trim(index);
+        if T::HOOKS {
+            self.sink().on_remove(&mut value, Entity(index));
+        }
+        Some(value)
+    }
+
+    /// Empty the storage in O(1) for components without drop glue: the block tree is
+    /// forgotten instead of freed. This leaks unless the blocks live in an arena that is
+    /// reset afterwards, as with `TickArena`.
+    pub(crate) fn forget_blocks(&mut self) {
+        self.remove_hooks();
+        if !self.watchers.is_empty() {
+            let mut bases = Vec::new();
+            for r in bits(self.root.presence_mask | self.root.absence_mask) {
+                let mid = unsafe { self.root.data.get_unchecked(r as usize).assume_init_ref() };
+                bases.extend(bits(mid.presence_mask | mid.absence_mask).map(|m| (r << 14) | (m << 7)));
+            }
+            bases.into_iter().for_each(|base| self.touch(base));
+        }
+        self.settled.clear();
+        let root = std::mem::replace(&mut self.root, SparseBlock::new(self.alloc));
+        if std::mem::needs_drop::<T>() {
+            drop(root);
+        } else {
+            std::mem::forget(root);
+        }
+    }
+
+    /// Add `mask` of the leaf containing `index` to `settled`; returns the bits settled before.
+    fn settle(&mut self, index: u32, mask: u128) -> u128 {
+        let base = index & !127;
+        let old = self.settled.masks(BlockPath { level: 2, base });
+        self.settled.set_block(base, old | mask);
+        old
+    }
+
+    /// Drop `index` from `settled`. A materialized leaf gets the slot's `init` value back
+    /// right away, otherwise the slot is initialized along with the rest of its leaf.
+    fn unsettle(&mut self, index: u32) {
+        let base = index & !127;
+        let l = (index & 127) as usize;
+        let old = self.settled.masks(BlockPath { level: 2, base });
+        self.settled.set_block(base, old & !(1u128 << l));
+        let Some((leaf, _)) = self.leaf_mut(index) else { return };
+        if leaf.holds(l as u32) {
+            return;
+        }
+        *unsafe { leaf.data.get_unchecked_mut(l) } = T::init(index);
+        leaf.set_all(1u128 << l);
+        self.trim(index);
+    }
+
+    fn sink(&self) -> Rc<dyn HookSink<T>> {
+        self.hooks.clone()
+    }
+
+    /// Call `hook` on the value at `index`, visible or skipped.
+    fn run_hook(&mut self, index: u32, hook: impl FnOnce(&dyn HookSink<T>, &mut T, Entity)) {
+        let sink = self.sink();
+        if let Some(value) = self.get_any_mut(index) {
+            hook(&*sink, value, Entity(index));
+        }
+    }
+
+    /// Run `on_remove` for every value still held, before the blocks are dropped or forgotten.
+    fn remove_hooks(&mut self) {
+        if !T::HOOKS { return; }
+        let sink = self.sink();
+        let mut indices = Vec::new();
+        for r in bits(self.root.presence_mask | self.root.absence_mask) {
+            let mid = unsafe { self.root.data.get_unchecked(r as usize).assume_init_ref() };
+            for m in bits(mid.presence_mask | mid.absence_mask) {
+                let leaf = unsafe { mid.data.get_unchecked(m as usize).assume_init_ref() };
+                let base = (r << 14) | (m << 7);
+                indices.extend(bits(leaf.presence_mask | leaf.absence_mask).map(|l| base | l));
+            }
+        }
+        for index in indices {
+            if let Some(value) = self.get_any_mut(index) {
+                sink.on_remove(value, Entity(index));
+            }
+        }
+    }
+
+    fn leaf(&self, index: u32) -> Option<(&SparseBlock<T, L>, usize)> {
+        let (r, m, l) = split_index(index);
+        if !self.root.holds(r as u32) { return None; }
+        let mid = unsafe { self.root.data.get_unchecked(r).assume_init_ref() };
+        if !mid.holds(m as u32) { return None; }
+        Some((unsafe { mid.data.get_unchecked(m).assume_init_ref() }, l))
+    }
+}
+
+impl<T: Component, A: Allocator + Copy, L: Allocator + Copy> Drop for SparseStorage<T, A, L> {
+    fn drop(&mut self) {
+        self.remove_hooks();
     }
 }
 
@@ -35,4 +683,258 @@ impl<T: Component, A: Allocator + Copy + Default> Default for SparseStorage<T, A
     fn default() -> Self { Self::new(A::default()) }
 }
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::alloc::Global;
+    use std::cell::Cell;
+    use std::ptr::NonNull;
+
+    #[derive(Debug, PartialEq)]
+    struct Pos(u32);
+    impl Component for Pos {}
+
+    thread_local! {
+        static BUDGET: Cell<usize> = const { Cell::new(usize::MAX) };
+    }
+
+    /// Global-backed allocator that fails once the thread-local block budget is spent.
+    #[derive(Default, Clone, Copy)]
+    struct Capped;
+
+    unsafe impl Allocator for Capped {
+        fn allocate(&self, layout: Layout) -> Result<NonNull<[u8]>, AllocError> {
+            BUDGET.with(|b| {
+                if b.get() == 0 { return Err(AllocError); }
+                b.set(b.get() - 1);
+                Global.allocate(layout)
+            })
+        }
+
+        unsafe fn deallocate(&self, ptr: NonNull<u8>, layout: Layout) {
+            unsafe { Global.deallocate(ptr, layout) }
+        }
+    }
+
+    #[test]
+    fn insert_get_remove_roundtrip() {
+        let mut s = SparseStorage::<Pos, Global>::default();
+        assert_eq!(s.insert(5, Pos(1)), None);
+        assert_eq!(s.insert(5, Pos(2)), Some(Pos(1)));
+        assert_eq!(s.get(5), Some(&Pos(2)));
+        s.get_mut(5).unwrap().0 = 3;
+        assert_eq!(s.remove(5), Some(Pos(3)));
+        assert!(!s.contains(5));
+        assert_eq!(s.remove(5), None);
+    }
+
+    #[test]
+    fn insert_sets_parent_bits_at_every_level() {
+        let mut s = SparseStorage::<Pos, Global>::default();
+        let index = (3 << 14) | (7 << 7) | 9;
+        s.insert(index, Pos(0));
+        assert_eq!(split_index(index), (3, 7, 9));
+        assert_eq!(s.root.presence_mask, 1 << 3);
+        let mid = unsafe { s.root.data[3].assume_init_ref() };
+        assert_eq!(mid.presence_mask, 1 << 7);
+        let leaf = unsafe { mid.data[7].assume_init_ref() };
+        assert_eq!(leaf.presence_mask, 1 << 9);
+    }
+
+    #[test]
+    fn remove_frees_emptied_blocks() {
+        let mut s = SparseStorage::<Pos, Global>::default();
+        s.insert(1, Pos(1));
+        s.insert(200, Pos(2));
+        s.remove(1);
+        assert_eq!(s.root.presence_mask, 1);
+        s.remove(200);
+        assert_eq!(s.root.presence_mask, 0);
+    }
+
+    #[test]
+    fn try_insert_surfaces_alloc_error_and_leaves_masks_untouched() {
+        let mut s = SparseStorage::<Pos, Capped>::default();
+        BUDGET.with(|b| b.set(1));
+        assert_eq!(s.try_insert(0, Pos(0)), Err(AllocError));
+        assert_eq!(s.root.presence_mask, 0);
+        assert_eq!(s.root.absence_mask, 0);
 
+        BUDGET.with(|b| b.set(2));
+        assert_eq!(s.try_insert(0, Pos(0)), Ok(None));
+        // the leaf exists now, so a neighbour needs no allocation at all
+        assert_eq!(s.try_insert(1, Pos(1)), Ok(None));
+        assert_eq!(s.try_insert(128, Pos(2)), Err(AllocError));
+        let mid = unsafe { s.root.data[0].assume_init_ref() };
+        assert_eq!(mid.presence_mask, 1);
+        BUDGET.with(|b| b.set(usize::MAX));
+    }
+
+    #[test]
+    fn inner_blocks_from_a_bump_and_leaves_from_a_pool() {
+        use crate::storage::pool::BlockPool;
+
+        let bump = bumpalo::Bump::new();
+        let pool = BlockPool::for_sparse_leaf::<Pos, &BlockPool>();
+        let mut s = SparseStorage::<Pos, &bumpalo::Bump, &BlockPool>::new_in(&bump, &pool);
+        s.insert(0, Pos(0));
+        s.insert(1 << 14, Pos(1));
+        assert_eq!(pool.chunks(), 1);
+        assert!(bump.allocated_bytes() >= 2 * size_of::<SparseBlock<Box<SparseBlock<Pos, &BlockPool>, &BlockPool>, &bumpalo::Bump>>());
+        assert_eq!(s.get(1 << 14), Some(&Pos(1)));
+        s.remove(0);
+        assert!(s.validate().is_empty());
+    }
+
+    #[test]
+    fn skip_propagates_absence_to_every_level() {
+        let mut s = SparseStorage::<Pos, Global>::default();
+        s.insert(3, Pos(3));
+        s.insert(200, Pos(200));
+        s.insert(20_000, Pos(1));
+
+        assert!(s.skip(3));
+        assert!(!s.skip(3));
+        assert!(s.get(3).is_none() && s.is_skipped(3));
+        let mid = unsafe { s.root.data[0].assume_init_ref() };
+        assert_eq!((mid.presence_mask, mid.absence_mask), (0b10, 0b01));
+
+        assert!(s.skip(200));
+        let mid = unsafe { s.root.data[0].assume_init_ref() };
+        assert_eq!((mid.presence_mask, mid.absence_mask), (0, 0b11));
+        assert_eq!((s.root.presence_mask, s.root.absence_mask), (0b10, 0b01));
+        assert!(s.validate().is_empty());
+
+        let visible: Vec<(u32, usize)> = s.views().map(|(i, v)| (i, v.len())).collect();
+        assert_eq!(visible, vec![(20_000, 1)]);
+
+        assert!(s.unskip(200));
+        assert_eq!((s.root.presence_mask, s.root.absence_mask), (0b11, 0));
+        assert_eq!(s.get(200), Some(&Pos(200)));
+        assert_eq!(s.remove(3), Some(Pos(3)));
+        assert!(s.validate().is_empty());
+    }
+
+    #[test]
+    fn views_walk_runs_across_blocks() {
+        let mut s = SparseStorage::<Pos, Global>::default();
+        for i in (120..140).chain(16_380..16_390) {
+            s.insert(i, Pos(i));
+        }
+        s.skip(130);
+        let runs: Vec<(u32, Vec<u32>)> = s.views().map(|(i, v)| (i, v.as_slice().iter().map(|p| p.0).collect())).collect();
+        let starts: Vec<(u32, usize)> = runs.iter().map(|(i, v)| (*i, v.len())).collect();
+        assert_eq!(starts, vec![(120, 8), (128, 2), (131, 9), (16_380, 4), (16_384, 6)]);
+        assert!(runs.iter().all(|(i, v)| v[0] == *i));
+    }
+
+    #[test]
+    fn presence_exports_to_roaring() {
+        let mut s = SparseStorage::<Pos, Global>::default();
+        for i in (0..1000).chain(70_000..70_010) {
+            s.insert(i, Pos(i));
+        }
+        s.skip(500);
+        let set = crate::world::EntitySet::from_roaring(&s.to_roaring()).unwrap();
+        assert_eq!(set.len(), 1009);
+        assert!(!set.contains(crate::world::Entity(500)));
+        assert!(set.contains(crate::world::Entity(70_009)));
+    }
+
+    #[test]
+    fn counts_support_len_nth_rank_and_sample() {
+        let mut s = SparseStorage::<Pos, Global>::default();
+        let indices: Vec<u32> = (0..300).map(|i| i * 97).chain([40_000, 2_000_000]).collect();
+        for &i in &indices {
+            s.insert(i, Pos(i));
+        }
+        s.insert(97, Pos(0));
+        s.remove(194);
+        s.skip(291);
+        let visible: Vec<u32> = indices.iter().copied().filter(|&i| i != 194 && i != 291).collect();
+
+        assert_eq!(s.len(), visible.len());
+        for (k, &i) in visible.iter().enumerate() {
+            assert_eq!(s.nth(k), Some(i));
+            assert_eq!(s.rank(i), k);
+        }
+        assert_eq!(s.nth(visible.len()), None);
+        assert_eq!(s.rank(MAX_INDEX - 1), visible.len());
+        assert_eq!(s.rank(2_000_000), visible.len() - 1);
+        assert_eq!(s.rank(195), 2);
+
+        assert_eq!(s.sample(0), Some(0));
+        assert_eq!(s.sample(u64::MAX), Some(2_000_000));
+        assert!(s.validate().is_empty());
+
+        s.unskip(291);
+        assert_eq!(s.len(), visible.len() + 1);
+        for &i in &visible {
+            s.remove(i);
+        }
+        assert_eq!(s.len(), 1);
+        assert_eq!(s.nth(0), Some(291));
+    }
+
+    #[derive(ercs_macros::Component)]
+    #[component(init = Seed::from_index)]
+    struct Seed(u32);
+
+    impl Seed {
+        fn from_index(index: u32) -> Self { Seed(index * 2) }
+    }
+
+    #[test]
+    fn preinit_components_materialize_whole_leaf_blocks() {
+        let mut s = SparseStorage::<Seed, Global>::default();
+        assert!(s.get(300).is_none());
+        assert_eq!(s.get_or_init_mut(300).map(|v| v.0), Some(600));
+        // the rest of the leaf covering 256..384 exists without any insert
+        assert_eq!(s.get(256).map(|v| v.0), Some(512));
+        assert_eq!(s.get(383).map(|v| v.0), Some(766));
+        assert!(s.get(384).is_none());
+        assert_eq!(s.len(), 128);
+
+        // a slot holding only its init value has no previous value to report
+        assert_eq!(s.insert(1000, Seed(1)).map(|v| v.0), None);
+        assert_eq!(s.insert(1000, Seed(2)).map(|v| v.0), Some(1));
+        assert_eq!(s.get(999).map(|v| v.0), Some(1998));
+        assert_eq!(s.remove(999).map(|v| v.0), Some(1998));
+        assert!(s.get(999).is_none());
+    }
+
+    #[test]
+    fn relocate_moves_values_over_preinit_slots() {
+        let mut s = SparseStorage::<Seed, Global>::default();
+        s.insert(300, Seed(1));
+        s.insert(301, Seed(2));
+        s.skip(301);
+        // 5 holds its init value
+        Storage::relocate(&mut s, 300, 5);
+        Storage::relocate(&mut s, 301, 6);
+        assert_eq!(s.get(5).map(|v| v.0), Some(1));
+        assert!(s.is_skipped(6));
+        // the vacated slots are fresh again
+        assert_eq!(s.get(300).map(|v| v.0), Some(600));
+        assert_eq!(s.get(301).map(|v| v.0), Some(602));
+        assert_eq!(s.insert(5, Seed(3)).map(|v| v.0), Some(1));
+        assert!(s.validate().is_empty());
+    }
+
+    #[test]
+    fn preinit_removed_slots_stay_removed() {
+        let mut s = SparseStorage::<Seed, Global>::default();
+        s.get_or_init_mut(5);
+        for i in 0..128 {
+            s.remove(i);
+        }
+        assert!(s.is_empty());
+        assert_eq!(s.root.presence_mask, 0);
+        // the leaf is materialized again without the removed slots
+        assert!(s.get_or_init_mut(5).is_none());
+        s.insert(7, Seed(1));
+        assert_eq!(s.len(), 1);
+        assert_eq!(s.remove(7).map(|v| v.0), Some(1));
+        assert!(s.validate().is_empty());
+    }
+}
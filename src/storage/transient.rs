@@ -0,0 +1,82 @@
+use std::alloc::{AllocError, Allocator, Layout};
+use std::cell::RefCell;
+use std::ptr::NonNull;
+use std::rc::Rc;
+
+use bumpalo::Bump;
+
+use crate::component::Component;
+use crate::storage::storage::SparseStorage;
+
+/// Allocator handle into a world's per-tick `Bump`.
+/// Deallocation is a no-op: memory is reclaimed in bulk when the world resets the arena.
+#[derive(Clone, Copy)]
+pub struct TickArena {
+    bump: NonNull<Bump>,
+}
+
+impl TickArena {
+    /// # Safety
+    /// `bump` must stay valid for as long as any block allocated through the handle
+    /// is reachable, and must only be reset once all of those blocks are unreachable.
+    pub unsafe fn new(bump: NonNull<Bump>) -> Self {
+        Self { bump }
+    }
+}
+
+unsafe impl Allocator for TickArena {
+    #[inline(always)]
+    fn allocate(&self, layout: Layout) -> Result<NonNull<[u8]>, AllocError> {
+        unsafe { self.bump.as_ref() }.allocate(layout)
+    }
+
+    #[inline(always)]
+    unsafe fn deallocate(&self, _ptr: NonNull<u8>, _layout: Layout) {}
+}
+
+/// Storage whose blocks live in the per-tick arena and are dropped wholesale at the end of every tick.
+pub type TransientStorage<T> = SparseStorage<T, TickArena>;
+
+/// Type-erased handle the world keeps for every transient storage.
+pub trait Transient {
+    /// Empty the storage ahead of an arena reset.
+    fn release(&self);
+    /// True if the storage is still referenced outside the world.
+    fn is_shared(&self) -> bool;
+}
+
+impl<T: Component> Transient for Rc<RefCell<TransientStorage<T>>> {
+    fn release(&self) {
+        self.borrow_mut().forget_blocks();
+    }
+
+    fn is_shared(&self) -> bool {
+        Rc::strong_count(self) > 1
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct Contact(u32);
+    impl Component for Contact {}
+
+    #[test]
+    fn transient_storage_allocates_from_arena() {
+        let mut bump = Box::new(Bump::new());
+        let arena = unsafe { TickArena::new(NonNull::from(&mut *bump)) };
+        let mut s = TransientStorage::<Contact>::new(arena);
+        s.insert(3, Contact(7));
+        assert!(bump.allocated_bytes() > 0);
+        assert_eq!(s.get(3).map(|c| c.0), Some(7));
+
+        s.forget_blocks();
+        assert!(!s.contains(3));
+        bump.reset();
+
+        s.insert(130, Contact(1));
+        assert_eq!(s.get(130).map(|c| c.0), Some(1));
+        s.forget_blocks();
+    }
+}
@@ -0,0 +1,179 @@
+use std::alloc::Allocator;
+use std::fmt;
+
+use crate::component::Component;
+use crate::storage::block::SparseBlock;
+use crate::storage::storage::SparseStorage;
+
+/// Location of a block in a storage tree: level 0 is the root, 2 a leaf.
+/// `base` is the first entity index the block covers.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct BlockPath {
+    pub level: u8,
+    pub base: u32,
+}
+
+/// A broken storage tree invariant.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum Violation {
+    /// `presence_mask & absence_mask != 0`.
+    Overlap { path: BlockPath, mask: u128 },
+    /// A parent bit points at a child block that holds nothing.
+    EmptyChild { path: BlockPath, slot: u8 },
+    /// A parent bit says present where the child has no visible slot, or skipped where it has one.
+    Misclassified { path: BlockPath, slot: u8, skipped: bool },
+    /// The cached `header.len` differs from the visible values below the block.
+    Count { path: BlockPath, cached: u32, actual: u32 },
+}
+
+/// A `Violation` together with the storage it was found in, as reported by `World::validate`.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct StorageViolation {
+    pub storage: &'static str,
+    pub violation: Violation,
+}
+
+impl fmt::Display for StorageViolation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {}", self.storage, self.violation)
+    }
+}
+
+impl fmt::Display for Violation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Violation::Overlap { path, mask } => {
+                write!(f, "level {} block at {}: presence and absence overlap in {:#x}", path.level, path.base, mask)
+            }
+            Violation::EmptyChild { path, slot } => {
+                write!(f, "level {} block at {}: child {} is linked but empty", path.level, path.base, slot)
+            }
+            Violation::Misclassified { path, slot, skipped } => write!(
+                f,
+                "level {} block at {}: child {} is marked {} but {} visible values",
+                path.level,
+                path.base,
+                slot,
+                if *skipped { "skipped" } else { "present" },
+                if *skipped { "has" } else { "has no" }
+            ),
+            Violation::Count { path, cached, actual } => {
+                write!(f, "level {} block at {}: cached len {} but {} visible values", path.level, path.base, cached, actual)
+            }
+        }
+    }
+}
+
+fn check_block<T, A>(block: &SparseBlock<T, A>, path: BlockPath, out: &mut Vec<Violation>) {
+    let overlap = block.presence_mask & block.absence_mask;
+    if overlap != 0 {
+        out.push(Violation::Overlap { path, mask: overlap });
+    }
+}
+
+/// Children linked through an inner block, with their slot.
+fn children<U, A, B: Allocator>(block: &SparseBlock<Box<SparseBlock<U, B>, B>, A>) -> impl Iterator<Item = (u32, &SparseBlock<U, B>)> {
+    let mut m = block.presence_mask | block.absence_mask;
+    std::iter::from_fn(move || {
+        if m == 0 { return None; }
+        let slot = m.trailing_zeros();
+        m &= m - 1;
+        Some((slot, &**unsafe { block.data.get_unchecked(slot as usize).assume_init_ref() }))
+    })
+}
+
+fn check_count(path: BlockPath, cached: u32, actual: u32, out: &mut Vec<Violation>) {
+    if cached != actual {
+        out.push(Violation::Count { path, cached, actual });
+    }
+}
+
+fn check_link<U, A, B: Allocator>(
+    parent: &SparseBlock<Box<SparseBlock<U, B>, B>, A>,
+    path: BlockPath,
+    slot: u32,
+    child: &SparseBlock<U, B>,
+    out: &mut Vec<Violation>,
+) {
+    if child.presence_mask | child.absence_mask == 0 {
+        out.push(Violation::EmptyChild { path, slot: slot as u8 });
+    } else if parent.has(slot) != (child.presence_mask != 0) {
+        out.push(Violation::Misclassified { path, slot: slot as u8, skipped: !parent.has(slot) });
+    }
+}
+
+impl<T: Component, A: Allocator + Copy, L: Allocator + Copy> SparseStorage<T, A, L> {
+    /// Walk the whole tree and report every broken invariant.
+    pub fn validate(&self) -> Vec<Violation> {
+        let mut out = Vec::new();
+        let root_path = BlockPath { level: 0, base: 0 };
+        check_block(&self.root, root_path, &mut out);
+        let mut total = 0;
+        for (r, mid) in children(&self.root) {
+            let mid_path = BlockPath { level: 1, base: r << 14 };
+            check_link(&self.root, root_path, r, mid, &mut out);
+            check_block(mid, mid_path, &mut out);
+            let mut mid_total = 0;
+            for (m, leaf) in children(mid) {
+                let leaf_path = BlockPath { level: 2, base: mid_path.base | (m << 7) };
+                check_link(mid, mid_path, m, leaf, &mut out);
+                check_block(leaf, leaf_path, &mut out);
+                check_count(leaf_path, leaf.header.len, leaf.count() as u32, &mut out);
+                mid_total += leaf.count() as u32;
+            }
+            check_count(mid_path, mid.header.len, mid_total, &mut out);
+            total += mid_total;
+        }
+        check_count(root_path, self.root.header.len, total, &mut out);
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::alloc::Global;
+
+    struct Hp(u32);
+    impl Component for Hp {}
+
+    #[test]
+    fn healthy_tree_has_no_violations() {
+        let mut s = SparseStorage::<Hp, Global>::default();
+        for i in (0..40_000).step_by(7) {
+            s.insert(i, Hp(i));
+        }
+        for i in (0..40_000).step_by(14) {
+            s.remove(i);
+        }
+        assert!(s.validate().is_empty());
+    }
+
+    #[test]
+    fn reports_overlap_empty_children_and_misclassified_parents() {
+        let mut s = SparseStorage::<Hp, Global>::default();
+        s.insert(300, Hp(0));
+        s.insert(301, Hp(1));
+        s.try_reserve(20_000).unwrap();
+        s.skip(301);
+        {
+            let mid = unsafe { s.root.data[0].assume_init_mut() };
+            let leaf = unsafe { mid.data[2].assume_init_mut() };
+            leaf.skip_all(1 << 44);
+        }
+        s.root.absence_mask = 1;
+        s.root.header.len += 1;
+
+        let violations = s.validate();
+        assert!(violations.contains(&Violation::Overlap { path: BlockPath { level: 0, base: 0 }, mask: 1 }));
+        assert!(violations.contains(&Violation::Misclassified { path: BlockPath { level: 1, base: 0 }, slot: 2, skipped: false }));
+        assert!(violations.contains(&Violation::EmptyChild { path: BlockPath { level: 1, base: 16384 }, slot: 28 }));
+        assert!(violations.contains(&Violation::Count { path: BlockPath { level: 2, base: 256 }, cached: 1, actual: 0 }));
+        assert!(violations.contains(&Violation::Count { path: BlockPath { level: 0, base: 0 }, cached: 2, actual: 0 }));
+        s.root.absence_mask = 0;
+        s.root.header.len -= 1;
+        s.trim(300);
+        s.trim(20_000);
+        assert!(s.validate().is_empty());
+    }
+}
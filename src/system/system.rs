@@ -10,6 +10,7 @@ mod tests {
     use ercs_macros::Component;
     use crate::storage::storage::SparseStorage;
     use crate::view::View;
+    use crate::world::{EventReader, EventWriter};
     use crate::run_system;
     use crate::view::iter::IterViews;
 
@@ -37,4 +38,255 @@ mod tests {
         assert_eq!(a.len(), b.len());
         COUNT2.fetch_add(a.len(), Ordering::SeqCst);
     }
+
+    static COUNT3: AtomicUsize = AtomicUsize::new(0);
+
+    #[system(parallel, min_batch = 1)]
+    fn count_parallel(a: &View<C>, b: &View<D>) {
+        COUNT3.fetch_add(a.len().min(b.len()), Ordering::SeqCst);
+    }
+
+    #[test]
+    fn systems_run_on_worlds_with_custom_allocators() {
+        use crate::scheduler::PipelineStage;
+        use crate::storage::pool::{BlockPool, PoolHandle};
+        let mut world = crate::world::World::with_pools(
+            BlockPool::for_sparse_inner::<PoolHandle>(),
+            BlockPool::for_sparse_leaf::<C, PoolHandle>(),
+        );
+        world.spawn_batch((0..1000u32).map(|i| (C(i), D(i))));
+        MyIter2System::new(&mut world).run();
+        let count = CountParallelSystem::new(&mut world);
+        crate::jobs::JobPool::new(2).install(|| count.run());
+        assert_eq!(COUNT2.load(Ordering::SeqCst), 1000);
+        assert_eq!(COUNT3.load(Ordering::SeqCst), 1000);
+    }
+
+    #[derive(Component)]
+    struct K(u32);
+    #[derive(Component)]
+    struct L(u32);
+
+    static K_SUM: AtomicUsize = AtomicUsize::new(0);
+    static L_SUM: AtomicUsize = AtomicUsize::new(0);
+
+    #[system(job)]
+    fn sum_k(a: &View<K>, b: &View<L>) {
+        K_SUM.fetch_add(a.as_slice().iter().map(|k| k.0 as usize).sum(), Ordering::SeqCst);
+        assert_eq!(a.len(), b.len());
+    }
+
+    #[system(parallel, min_batch = 128)]
+    fn sum_l(a: &View<K>, b: &View<L>) {
+        L_SUM.fetch_add(b.as_slice().iter().map(|l| l.0 as usize).sum(), Ordering::SeqCst);
+        assert_eq!(a.len(), b.len());
+    }
+
+    #[test]
+    fn executor_runs_systems_as_jobs() {
+        use crate::jobs::JobPool;
+        use crate::scheduler::Executor;
+        let mut world = crate::world::World::new();
+        world.spawn_batch((0..1000u32).map(|i| (K(i), L(2 * i))));
+        let sum_k = SumKSystem::new(&mut world);
+        let sum_l = SumLSystem::new(&mut world);
+        let stages: [&dyn PipelineStage; 2] = [&sum_k, &sum_l];
+        assert_eq!(Executor::waves(&stages), vec![vec![0, 1]]);
+
+        world.instrument(true);
+        let pool = JobPool::new(2);
+        Executor::new(&pool).run(&stages);
+        assert_eq!(K_SUM.load(Ordering::SeqCst), 999 * 1000 / 2);
+        assert_eq!(L_SUM.load(Ordering::SeqCst), 999 * 1000);
+        // stats of the jobs are recorded back on the calling thread
+        let report = world.take_stats();
+        assert_eq!(report.systems.len(), 2);
+        assert!(report.systems.iter().all(|(_, stats)| stats.matched == 1000));
+    }
+
+    #[derive(Component)]
+    struct Hits(std::cell::Cell<u32>);
+
+    #[system]
+    fn bump_hits(a: &View<Hits>, b: &View<K>) {
+        for (hits, k) in a.as_slice().iter().zip(b.as_slice()) {
+            hits.0.set(hits.0.get() + k.0);
+        }
+    }
+
+    static KL_COUNT: AtomicUsize = AtomicUsize::new(0);
+
+    #[system(job)]
+    fn count_kl(a: &View<K>, b: &View<L>) {
+        KL_COUNT.fetch_add(a.len().min(b.len()), Ordering::SeqCst);
+    }
+
+    #[test]
+    fn plain_systems_take_non_sync_components() {
+        use crate::jobs::JobPool;
+        use crate::scheduler::Executor;
+        let mut world = crate::world::World::new();
+        let e = world.spawn((Hits(std::cell::Cell::new(1)), K(2)));
+        world.spawn((K(0), L(0)));
+        let bump = BumpHitsSystem::new(&mut world);
+        let count = CountKlSystem::new(&mut world);
+        // no job to hand out, so it runs on the calling thread next to the other one
+        Executor::new(&JobPool::new(1)).run(&[&bump, &count]);
+        assert_eq!(world.get::<Hits>().borrow().get(e.0).unwrap().0.get(), 3);
+        assert_eq!(KL_COUNT.load(Ordering::SeqCst), 1);
+    }
+
+    #[derive(Component)]
+    struct E(u32);
+    #[derive(Component)]
+    struct F(u32);
+
+    static SUM: AtomicUsize = AtomicUsize::new(0);
+
+    #[system(parallel, min_batch = 64)]
+    fn sum_pairs(a: &View<E>, b: &View<F>) {
+        for (e, f) in a.as_slice().iter().zip(b.as_slice()) {
+            SUM.fetch_add((e.0 * f.0) as usize, Ordering::SeqCst);
+        }
+    }
+
+    #[derive(Component)]
+    struct G(u32);
+    #[derive(Component)]
+    struct H(u32);
+
+    #[system]
+    fn scale(a: &View<G>, b: &View<H>) {
+        assert_eq!(a.len(), b.len());
+    }
+
+    #[test]
+    fn instrumented_system_reports_per_tick() {
+        use crate::scheduler::PipelineStage;
+        let mut world = crate::world::World::new();
+        world.spawn_batch((0..300u32).map(|i| (G(i), H(i))));
+        for i in (0..300u32).step_by(10) {
+            world.remove::<H>(crate::world::Entity(i));
+        }
+        let system = ScaleSystem::new(&mut world);
+        let mut other = crate::world::World::new();
+        other.spawn((G(0), H(0)));
+        let elsewhere = ScaleSystem::new(&mut other);
+        world.instrument(true);
+        system.run();
+        system.run();
+        // stats stay with the world the system was built for
+        other.instrument(true);
+        elsewhere.run();
+        world.instrument(false);
+
+        let report = world.take_stats();
+        assert_eq!(report.tick, world.tick());
+        assert_eq!(report.systems.len(), 1);
+        let (name, scale) = report.systems[0];
+        assert!(name.ends_with("::ScaleSystem"));
+        assert_eq!(scale.visited, [2, 2, 6]);
+        assert_eq!(scale.matched, 540);
+        assert_eq!(scale.run_lengths.iter().sum::<u64>(), scale.runs);
+        assert!(report.to_string().starts_with("Tick(0)"));
+        assert_eq!(other.take_stats().systems[0].1.matched, 1);
+
+        world.end_tick();
+        system.run();
+        assert!(world.take_stats().systems.is_empty());
+    }
+
+    struct Hit(u32);
+
+    #[system]
+    fn detect_hits(a: &View<G>, b: &View<H>, hits: &EventWriter<Hit>) {
+        for (g, h) in a.as_slice().iter().zip(b.as_slice()) {
+            if g.0 == h.0 {
+                hits.send(Hit(g.0));
+            }
+        }
+    }
+
+    static HITS: AtomicUsize = AtomicUsize::new(0);
+
+    #[system]
+    fn count_hits(hits: &mut EventReader<Hit>) {
+        HITS.fetch_add(hits.read().iter().map(|hit| hit.0 as usize).sum(), Ordering::SeqCst);
+    }
+
+    #[test]
+    fn event_systems_communicate_across_ticks() {
+        use std::any::TypeId;
+        use crate::scheduler::{conflicts, PipelineStage};
+        use crate::world::Events;
+        let mut world = crate::world::World::new();
+        world.spawn_batch((0..10u32).map(|i| (G(i), H(if i % 2 == 0 { i } else { 0 }))));
+        let detect = DetectHitsSystem::new(&mut world);
+        let count = CountHitsSystem::new(&mut world);
+        assert_eq!(detect.writes(), [TypeId::of::<Events<Hit>>()]);
+        assert_eq!(detect.reads(), [TypeId::of::<G>(), TypeId::of::<H>()]);
+        assert_eq!(count.reads(), [TypeId::of::<Events<Hit>>()]);
+        assert!(conflicts(&detect, &count));
+
+        // the reader runs first in tick 0 and still sees that tick's hits in tick 1
+        count.run();
+        detect.run();
+        world.end_tick();
+        assert_eq!(HITS.load(Ordering::SeqCst), 0);
+        count.run();
+        assert_eq!(HITS.load(Ordering::SeqCst), 2 + 4 + 6 + 8);
+        count.run();
+        world.end_tick();
+        count.run();
+        assert_eq!(HITS.load(Ordering::SeqCst), 20);
+        assert!(world.events::<Hit>().borrow().is_empty());
+    }
+
+    struct Crossing(u32);
+
+    #[system]
+    fn detect_crossings(a: &View<G>, b: &View<H>, crossings: &EventWriter<Crossing>) {
+        for (g, h) in a.as_slice().iter().zip(b.as_slice()) {
+            if g.0 == h.0 {
+                crossings.send(Crossing(g.0));
+            }
+        }
+    }
+
+    static CROSSINGS: AtomicUsize = AtomicUsize::new(0);
+
+    #[system]
+    fn count_crossings(crossings: &mut EventReader<Crossing>) {
+        CROSSINGS.fetch_add(crossings.read().iter().count(), Ordering::SeqCst);
+    }
+
+    #[test]
+    fn events_from_every_leaf_are_read_once() {
+        use crate::scheduler::PipelineStage;
+        let mut world = crate::world::World::new();
+        // one crossing in each of four leaf blocks
+        world.spawn_batch((0..512u32).map(|i| (G(i), H(if i % 128 == 5 { i } else { u32::MAX }))));
+        let detect = DetectCrossingsSystem::new(&mut world);
+        let count = CountCrossingsSystem::new(&mut world);
+        detect.run();
+        count.run();
+        count.run();
+        assert_eq!(CROSSINGS.load(Ordering::SeqCst), 4);
+        let sent: Vec<u32> = world.event_reader::<Crossing>().read().iter().map(|c| c.0).collect();
+        assert_eq!(sent, [5, 133, 261, 389]);
+    }
+
+    #[test]
+    fn parallel_system_visits_every_intersecting_entity() {
+        use crate::scheduler::PipelineStage;
+        let mut world = crate::world::World::new();
+        world.spawn_batch((0..50_000u32).map(|i| (E(i % 7), F(2))));
+        for i in (0..50_000u32).step_by(3) {
+            world.remove::<F>(crate::world::Entity(i));
+        }
+        let expected: usize = (0..50_000u32).filter(|i| i % 3 != 0).map(|i| (i % 7 * 2) as usize).sum();
+        let sum = SumPairsSystem::new(&mut world);
+        crate::jobs::JobPool::new(4).install(|| sum.run());
+        assert_eq!(SUM.load(Ordering::SeqCst), expected);
+    }
 }
@@ -1,22 +1,45 @@
 use crate::view::View;
 use crate::storage::block::{DenseBlock, SparseBlock};
 use std::alloc::Allocator;
+use std::mem::MaybeUninit;
 
+/// Runs of the slots in `mask`, every one of which must be initialized; slots outside
+/// it are never read, nor referenced as `T`.
 pub struct RunsIter<'a, T> {
-    data: &'a [T],
+    data: &'a [MaybeUninit<T>; 128],
     mask: u128,
 }
 
-impl<'a, T> Iterator for RunsIter<'a, T> {
-    type Item = View<'a, T>;
-    fn next(&mut self) -> Option<Self::Item> {
+/// The initialized slots `start..end` of a block.
+///
+/// # Safety
+/// Every slot in the range must be initialized.
+#[inline(always)]
+unsafe fn init_run<T>(data: &[MaybeUninit<T>; 128], start: usize, end: usize) -> &[T] {
+    unsafe { std::slice::from_raw_parts(data[start..end].as_ptr().cast::<T>(), end - start) }
+}
+
+impl<'a, T> RunsIter<'a, T> {
+    /// Next run together with the slot it starts at.
+    pub fn next_at(&mut self) -> Option<(usize, View<'a, T>)> {
         if self.mask == 0 { return None; }
         let start = self.mask.trailing_zeros() as usize;
         let run = (self.mask >> (start as u32)).trailing_ones() as usize;
         let end = start + run;
         let range_mask = if run == 128 { u128::MAX } else { ((1u128 << run) - 1) << start };
         self.mask &= !range_mask;
-        Some(View::new(&self.data[start..end]))
+        Some((start, View::new(unsafe { init_run(self.data, start, end) })))
+    }
+
+    pub fn with_offsets(mut self) -> impl Iterator<Item = (usize, View<'a, T>)> {
+        std::iter::from_fn(move || self.next_at())
+    }
+}
+
+impl<'a, T> Iterator for RunsIter<'a, T> {
+    type Item = View<'a, T>;
+    fn next(&mut self) -> Option<Self::Item> {
+        self.next_at().map(|(_, view)| view)
     }
 }
 
@@ -42,8 +65,8 @@ impl<'a, T> Iterator for DenseRunsIter<'a, T> {
 }
 
 pub struct IntersectRuns<'a, 'b, T, U> {
-    data_a: &'a [T],
-    data_b: &'b [U],
+    data_a: &'a [MaybeUninit<T>; 128],
+    data_b: &'b [MaybeUninit<U>; 128],
     mask: u128,
 }
 
@@ -57,7 +80,7 @@ impl<'a, 'b, T, U> Iterator for IntersectRuns<'a, 'b, T, U> {
         let end = start + run;
         let range_mask = if run == 128 { u128::MAX } else { ((1u128 << run) - 1) << start };
         self.mask &= !range_mask;
-        Some((View::new(&self.data_a[start..end]), View::new(&self.data_b[start..end])))
+        Some(unsafe { (View::new(init_run(self.data_a, start, end)), View::new(init_run(self.data_b, start, end))) })
     }
 }
 
@@ -127,17 +150,15 @@ impl<'a, T, A: Allocator> DenseBlock<T, A> {
 
 impl<'a, T, A> IterViews<'a, T> for SparseBlock<T, A> {
     fn views(&'a self) -> RunsIter<'a, T> {
-        let data_t: &[T] = unsafe { std::slice::from_raw_parts(self.data.as_ptr() as *const T, 128) };
-        let mask = self.presence_mask;
-        RunsIter { data: data_t, mask }
+        RunsIter { data: &self.data, mask: self.presence_mask }
     }
 }
 
 impl<'a, T, A> SparseBlock<T, A> {
+    /// Runs of slots that are present and not skipped. On inner blocks these are the
+    /// children with at least one visible value below them.
     pub fn views_complement(&'a self) -> RunsIter<'a, T> {
-        let data_t: &[T] = unsafe { std::slice::from_raw_parts(self.data.as_ptr() as *const T, 128) };
-        let effective = (self.presence_mask & !self.absence_mask);
-        RunsIter { data: data_t, mask: effective }
+        RunsIter { data: &self.data, mask: self.presence_mask & !self.absence_mask }
     }
 }
 
@@ -11,17 +11,19 @@ pub struct ViewMut<'a, T> {
 }
 
 impl<'a, T> ViewMut<'a, T> {
-    pub fn clear_all(&mut self){
+    /// These change the block's masks but not the counts of its storage; the storage
+    /// trims the block afterwards.
+    pub(crate) fn clear_all(&mut self){
         if let Some(block) = self.block.as_mut() {
             block.clear_all(self.mask);
         }
     }
-    pub fn set_all(&mut self){
+    pub(crate) fn set_all(&mut self){
         if let Some(block) = self.block.as_mut() {
             block.set_all(self.mask);
         }
     }
-    pub fn skip_all(&mut self){
+    pub(crate) fn skip_all(&mut self){
         if let Some(block) = self.block.as_mut() {
             block.skip_all(self.mask);
         }
@@ -0,0 +1,70 @@
+use std::alloc::{Allocator, Global};
+use std::collections::VecDeque;
+
+use crate::component::{Bundle, Component};
+use crate::world::entity::Entity;
+use crate::world::world::World;
+
+type Command<A, L> = Box<dyn FnOnce(&mut World<A, L>)>;
+
+/// Deferred world mutations, applied in push order by `World::flush`.
+pub struct Commands<A: Allocator + Copy + 'static = Global, L: Allocator + Copy + 'static = A> {
+    queue: VecDeque<Command<A, L>>,
+}
+
+impl<A: Allocator + Copy + 'static, L: Allocator + Copy + 'static> Default for Commands<A, L> {
+    fn default() -> Self {
+        Self { queue: VecDeque::new() }
+    }
+}
+
+impl<A: Allocator + Copy + 'static, L: Allocator + Copy + 'static> Commands<A, L> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn len(&self) -> usize {
+        self.queue.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.queue.is_empty()
+    }
+
+    pub fn push(&mut self, command: impl FnOnce(&mut World<A, L>) + 'static) {
+        self.queue.push_back(Box::new(command));
+    }
+
+    /// Spawn on flush; the entity is only known once the command ran.
+    pub fn spawn<B: Bundle>(&mut self, bundle: B) {
+        self.push(move |world| {
+            world.spawn(bundle);
+        });
+    }
+
+    pub fn despawn(&mut self, entity: Entity) {
+        self.push(move |world| {
+            world.despawn(entity);
+        });
+    }
+
+    pub fn insert<T: Component>(&mut self, entity: Entity, value: T) {
+        self.push(move |world| {
+            world.insert(entity, value);
+        });
+    }
+
+    pub fn remove<T: Component>(&mut self, entity: Entity) {
+        self.push(move |world| {
+            world.remove::<T>(entity);
+        });
+    }
+
+    pub(crate) fn pop(&mut self) -> Option<Command<A, L>> {
+        self.queue.pop_front()
+    }
+
+    pub(crate) fn append(&mut self, other: &mut Self) {
+        self.queue.append(&mut other.queue);
+    }
+}
@@ -0,0 +1,632 @@
+use std::collections::{BTreeSet, HashMap};
+use std::fmt;
+use std::ops::Range;
+
+use crate::storage::storage::MAX_INDEX;
+
+/// Entity handle: an index into every component storage tree.
+#[derive(Copy, Clone, Eq, PartialEq, Ord, PartialOrd, Hash)]
+pub struct Entity(pub u32);
+
+impl Entity {
+    /// Return the raw index.
+    pub fn index(self) -> u32 {
+        self.0
+    }
+}
+
+impl fmt::Debug for Entity {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Entity({})", self.0)
+    }
+}
+
+/// Entities spawned together on consecutive indices, see `World::spawn_batch`.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct EntityRange(pub Range<u32>);
+
+impl Iterator for EntityRange {
+    type Item = Entity;
+
+    fn next(&mut self) -> Option<Entity> {
+        self.0.next().map(Entity)
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.0.size_hint()
+    }
+}
+
+impl DoubleEndedIterator for EntityRange {
+    fn next_back(&mut self) -> Option<Entity> {
+        self.0.next_back().map(Entity)
+    }
+}
+
+impl ExactSizeIterator for EntityRange {}
+
+/// Bits `from..to` of a leaf word.
+fn slots(from: u32, to: u32) -> u128 {
+    let below = |n: u32| if n >= 128 { u128::MAX } else { (1u128 << n) - 1 };
+    below(to) & !below(from)
+}
+
+/// How freed indices are handed out again.
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
+pub enum AllocPolicy {
+    /// Most recently freed index first.
+    #[default]
+    Lifo,
+    /// Lowest free slot of the fullest partially-used leaf block first, so live
+    /// entities pack into long runs and sparse leaf blocks drain.
+    Compacting,
+}
+
+/// Fixed index range reserved for a group of related entities.
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Hash)]
+pub struct PoolId(pub u32);
+
+/// Where a new entity should be placed.
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
+pub enum AllocHint {
+    #[default]
+    Any,
+    /// Same leaf block as the given entity if possible, else the same L1 block.
+    Near(Entity),
+    /// Inside the index range of a pool.
+    Pool(PoolId),
+}
+
+/// Old to new index mapping produced by `Entities::defragment`.
+/// Only moved entities are recorded, sorted by their old index.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct EntityRemap {
+    moves: Vec<(Entity, Entity)>,
+}
+
+impl EntityRemap {
+    /// New handle for `entity`; entities that did not move map to themselves.
+    pub fn get(&self, entity: Entity) -> Entity {
+        match self.moves.binary_search_by_key(&entity, |&(old, _)| old) {
+            Ok(i) => self.moves[i].1,
+            Err(_) => entity,
+        }
+    }
+
+    pub fn moves(&self) -> &[(Entity, Entity)] {
+        &self.moves
+    }
+
+    pub fn len(&self) -> usize {
+        self.moves.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.moves.is_empty()
+    }
+}
+
+/// Index range allocations are drawn from: the global region or one pool.
+struct Region {
+    range: Range<u32>,
+    /// Indices at or above `next` have never been handed out.
+    next: u32,
+    /// Freed indices in LIFO order; entries allocated through other paths are skipped lazily.
+    stack: Vec<u32>,
+    /// (free slots, leaf) for every leaf with free slots below `next`; fullest leaf first.
+    by_free: BTreeSet<(u32, u32)>,
+    free_count: HashMap<u32, u32>,
+}
+
+impl Region {
+    fn new(range: Range<u32>) -> Self {
+        Self { next: range.start, range, stack: Vec::new(), by_free: BTreeSet::new(), free_count: HashMap::new() }
+    }
+
+    /// Slots of `leaf` handed out before and not alive now.
+    fn free_bits(&self, leaf: u32, alive: u128) -> u128 {
+        let base = leaf << 7;
+        let below = if self.next >= base + 128 {
+            u128::MAX
+        } else if self.next <= base {
+            0
+        } else {
+            (1u128 << (self.next - base)) - 1
+        };
+        !alive & below
+    }
+
+    fn refresh(&mut self, leaf: u32, alive: u128) {
+        let count = self.free_bits(leaf, alive).count_ones();
+        if let Some(old) = self.free_count.remove(&leaf) {
+            self.by_free.remove(&(old, leaf));
+        }
+        if count > 0 {
+            self.by_free.insert((count, leaf));
+            self.free_count.insert(leaf, count);
+        }
+    }
+}
+
+/// Entity index allocator with per-leaf-block liveness tracking, so new entities can
+/// be placed where they extend existing runs instead of fragmenting the storage trees.
+pub struct Entities {
+    policy: AllocPolicy,
+    alive: Vec<u128>,
+    disabled: Vec<u128>,
+    len: u32,
+    global: Region,
+    pools: Vec<Region>,
+}
+
+impl Default for Entities {
+    fn default() -> Self {
+        Self::with_policy(AllocPolicy::default())
+    }
+}
+
+impl Entities {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_policy(policy: AllocPolicy) -> Self {
+        Self { policy, alive: Vec::new(), disabled: Vec::new(), len: 0, global: Region::new(0..MAX_INDEX), pools: Vec::new() }
+    }
+
+    pub fn policy(&self) -> AllocPolicy {
+        self.policy
+    }
+
+    pub fn set_policy(&mut self, policy: AllocPolicy) {
+        self.policy = policy;
+    }
+
+    /// Number of live entities.
+    pub fn len(&self) -> u32 {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn is_alive(&self, entity: Entity) -> bool {
+        self.alive.get((entity.0 >> 7) as usize).is_some_and(|bits| bits & (1u128 << (entity.0 & 127)) != 0)
+    }
+
+    /// Mark a live entity disabled. Returns false if it is dead or disabled already.
+    pub fn disable(&mut self, entity: Entity) -> bool {
+        if !self.is_alive(entity) || self.is_disabled(entity) {
+            return false;
+        }
+        let leaf = (entity.0 >> 7) as usize;
+        if self.disabled.len() <= leaf {
+            self.disabled.resize(leaf + 1, 0);
+        }
+        self.disabled[leaf] |= 1u128 << (entity.0 & 127);
+        true
+    }
+
+    /// Returns false if the entity was not disabled.
+    pub fn enable(&mut self, entity: Entity) -> bool {
+        if !self.is_disabled(entity) {
+            return false;
+        }
+        self.disabled[(entity.0 >> 7) as usize] &= !(1u128 << (entity.0 & 127));
+        true
+    }
+
+    pub fn is_disabled(&self, entity: Entity) -> bool {
+        self.disabled_mask(entity.0 >> 7) & (1u128 << (entity.0 & 127)) != 0
+    }
+
+    /// Number of 128-slot leaf blocks that ever held a live entity.
+    pub fn leaf_count(&self) -> u32 {
+        self.alive.len() as u32
+    }
+
+    /// Live slots of leaf block `leaf`.
+    pub fn alive_mask(&self, leaf: u32) -> u128 {
+        self.alive_bits(leaf)
+    }
+
+    /// Disabled slots of leaf block `leaf`.
+    pub fn disabled_mask(&self, leaf: u32) -> u128 {
+        self.disabled.get(leaf as usize).copied().unwrap_or(0)
+    }
+
+    /// Live entities in ascending index order.
+    pub fn iter(&self) -> impl Iterator<Item = Entity> + '_ {
+        self.alive.iter().enumerate().flat_map(|(leaf, &bits)| {
+            let mut m = bits;
+            std::iter::from_fn(move || {
+                if m == 0 { return None; }
+                let slot = m.trailing_zeros();
+                m &= m - 1;
+                Some(Entity(((leaf as u32) << 7) | slot))
+            })
+        })
+    }
+
+    /// Reserve `range` for a pool. The range must be aligned to 128-slot leaf blocks,
+    /// must not overlap another pool and must not contain indices handed out already.
+    pub fn create_pool(&mut self, range: Range<u32>) -> PoolId {
+        assert!(range.start % 128 == 0 && range.end % 128 == 0, "pool range must be leaf aligned");
+        assert!(range.start < range.end && range.end <= MAX_INDEX, "invalid pool range {:?}", range);
+        assert!(
+            self.pools.iter().all(|p| range.end <= p.range.start || p.range.end <= range.start),
+            "pool range {:?} overlaps another pool",
+            range
+        );
+        assert!(self.global.next <= range.start, "pool range {:?} contains allocated indices", range);
+        self.pools.push(Region::new(range));
+        PoolId(self.pools.len() as u32 - 1)
+    }
+
+    pub fn pool_range(&self, pool: PoolId) -> Range<u32> {
+        self.pools[pool.0 as usize].range.clone()
+    }
+
+    pub fn alloc(&mut self) -> Entity {
+        self.alloc_with(AllocHint::Any)
+    }
+
+    pub fn alloc_with(&mut self, hint: AllocHint) -> Entity {
+        let index = match hint {
+            AllocHint::Any => self.pick(None),
+            AllocHint::Pool(pool) => self.pick(Some(pool.0 as usize)),
+            AllocHint::Near(entity) => self.pick_near(entity.0),
+        };
+        self.mark(index, true);
+        Entity(index)
+    }
+
+    /// Reserve `count` fresh indices starting on a 128-slot leaf block boundary.
+    /// Indices skipped by the alignment stay free for later allocations.
+    pub fn alloc_aligned(&mut self, count: u32) -> Range<u32> {
+        let old_next = self.global.next;
+        let mut start = old_next.next_multiple_of(128);
+        while let Some(pool) = self.pools.iter().find(|p| start < p.range.end && p.range.start < start + count) {
+            start = pool.range.end;
+        }
+        assert!(start + count <= MAX_INDEX, "entity index space exhausted");
+        self.global.next = start + count;
+        let mut index = start;
+        while index < start + count {
+            let end = (index + 128).min(start + count);
+            self.mark_block(index >> 7, slots(0, end - index), true);
+            index = end;
+        }
+        // leaves the alignment jumped over now have free slots below `next`
+        let mut leaf = old_next >> 7;
+        while leaf < start >> 7 {
+            if self.owner(leaf << 7).is_none() {
+                let alive = self.alive_bits(leaf);
+                self.global.refresh(leaf, alive);
+            }
+            leaf += 1;
+        }
+        start..start + count
+    }
+
+    /// Pack live entities of every region (the global range and each pool) into a dense
+    /// prefix of that region, in ascending index order. Every new index is lower than or
+    /// equal to the old one, so moves applied in `moves()` order never clobber a live slot.
+    pub fn defragment(&mut self) -> EntityRemap {
+        let live: Vec<Entity> = self.iter().collect();
+        let mut moves = Vec::new();
+        let mut cursors: Vec<u32> = self.pools.iter().map(|p| p.range.start).collect();
+        let mut global_cursor = 0u32;
+        let mut placed = Vec::with_capacity(live.len());
+        for old in live {
+            let disabled = self.is_disabled(old);
+            let new = match self.owner(old.0) {
+                Some(p) => {
+                    cursors[p] += 1;
+                    cursors[p] - 1
+                }
+                None => {
+                    while let Some(pool) = self.pools.iter().find(|p| p.range.contains(&global_cursor)) {
+                        global_cursor = pool.range.end;
+                    }
+                    global_cursor += 1;
+                    global_cursor - 1
+                }
+            };
+            if new != old.0 {
+                moves.push((old, Entity(new)));
+            }
+            placed.push((new, disabled));
+        }
+
+        self.alive.clear();
+        self.disabled.clear();
+        self.len = 0;
+        self.global = Region::new(0..MAX_INDEX);
+        self.global.next = global_cursor;
+        for (pool, cursor) in self.pools.iter_mut().zip(cursors) {
+            *pool = Region::new(pool.range.clone());
+            pool.next = cursor;
+        }
+        for (index, disabled) in placed {
+            self.mark(index, true);
+            if disabled {
+                self.disable(Entity(index));
+            }
+        }
+        EntityRemap { moves }
+    }
+
+    /// Return an index to the allocator; it is handed out again by a later `alloc`.
+    /// Returns false and changes nothing if `entity` is not alive.
+    pub(crate) fn free(&mut self, entity: Entity) -> bool {
+        if !self.is_alive(entity) {
+            return false;
+        }
+        self.mark(entity.0, false);
+        if self.policy == AllocPolicy::Lifo {
+            self.region_mut(entity.0).stack.push(entity.0);
+        }
+        true
+    }
+
+    /// `free` for a range of live indices, one leaf block at a time.
+    pub(crate) fn free_range(&mut self, range: Range<u32>) {
+        let mut index = range.start;
+        while index < range.end {
+            let end = ((index | 127) + 1).min(range.end);
+            self.mark_block(index >> 7, slots(index & 127, end - (index & !127)), false);
+            index = end;
+        }
+        if self.policy == AllocPolicy::Lifo && !range.is_empty() {
+            self.region_mut(range.start).stack.extend(range.rev());
+        }
+    }
+
+    fn pick(&mut self, pool: Option<usize>) -> u32 {
+        let policy = self.policy;
+        let alive = &self.alive;
+        let region = match pool {
+            Some(p) => &mut self.pools[p],
+            None => &mut self.global,
+        };
+        let mut found = None;
+        if policy == AllocPolicy::Lifo {
+            while let Some(index) = region.stack.pop() {
+                if alive.get((index >> 7) as usize).is_none_or(|bits| bits & (1u128 << (index & 127)) == 0) {
+                    found = Some(index);
+                    break;
+                }
+            }
+        }
+        if found.is_none() {
+            if let Some(&(_, leaf)) = region.by_free.first() {
+                let bits = region.free_bits(leaf, alive.get(leaf as usize).copied().unwrap_or(0));
+                found = Some((leaf << 7) | bits.trailing_zeros());
+            }
+        }
+        match found {
+            Some(index) => index,
+            None => self.fresh(pool),
+        }
+    }
+
+    fn pick_near(&mut self, near: u32) -> u32 {
+        let owner = self.owner(near);
+        let leaf = near >> 7;
+        let slot = near & 127;
+        let alive = self.alive_bits(leaf);
+        let region = self.region(near);
+
+        let bits = region.free_bits(leaf, alive);
+        if bits != 0 {
+            let above = bits >> slot;
+            let below = bits & ((1u128 << slot) - 1);
+            let up = (above != 0).then(|| slot + above.trailing_zeros());
+            let down = (below != 0).then(|| 127 - below.leading_zeros());
+            let pick = match (up, down) {
+                (Some(u), Some(d)) => if u - slot <= slot - d { u } else { d },
+                (Some(u), None) => u,
+                (None, Some(d)) => d,
+                (None, None) => unreachable!(),
+            };
+            return (leaf << 7) | pick;
+        }
+        if region.next >> 7 == leaf && region.next < region.range.end {
+            return self.fresh(owner);
+        }
+
+        let first = leaf & !127;
+        let fullest = (first..first + 128)
+            .filter(|&l| self.owner(l << 7) == owner)
+            .filter_map(|l| {
+                let free = self.region(l << 7).free_bits(l, self.alive_bits(l));
+                (free != 0).then(|| (free.count_ones(), l, free))
+            })
+            .min();
+        if let Some((_, l, free)) = fullest {
+            return (l << 7) | free.trailing_zeros();
+        }
+        self.pick(owner)
+    }
+
+    fn fresh(&mut self, pool: Option<usize>) -> u32 {
+        if pool.is_none() {
+            let mut next = self.global.next;
+            while let Some(p) = self.pools.iter().find(|p| p.range.contains(&next)) {
+                next = p.range.end;
+            }
+            self.global.next = next;
+        }
+        let region = match pool {
+            Some(p) => &mut self.pools[p],
+            None => &mut self.global,
+        };
+        assert!(region.next < region.range.end, "entity range {:?} exhausted", region.range);
+        let index = region.next;
+        region.next += 1;
+        index
+    }
+
+    fn mark(&mut self, index: u32, live: bool) {
+        self.mark_block(index >> 7, 1u128 << (index & 127), live);
+    }
+
+    /// `mark` for the slots in `bits` of `leaf`, which all belong to one region, with a
+    /// single refresh of its bookkeeping.
+    fn mark_block(&mut self, leaf: u32, bits: u128, live: bool) {
+        let l = leaf as usize;
+        if self.alive.len() <= l {
+            self.alive.resize(l + 1, 0);
+        }
+        if live {
+            debug_assert!(self.alive[l] & bits == 0);
+            self.alive[l] |= bits;
+            self.len += bits.count_ones();
+        } else {
+            debug_assert!(self.alive[l] & bits == bits);
+            self.alive[l] &= !bits;
+            if let Some(disabled) = self.disabled.get_mut(l) {
+                *disabled &= !bits;
+            }
+            self.len -= bits.count_ones();
+        }
+        let alive = self.alive[l];
+        self.region_mut((leaf << 7) | bits.trailing_zeros()).refresh(leaf, alive);
+    }
+
+    fn alive_bits(&self, leaf: u32) -> u128 {
+        self.alive.get(leaf as usize).copied().unwrap_or(0)
+    }
+
+    /// Pool owning `index`, `None` for the global region.
+    fn owner(&self, index: u32) -> Option<usize> {
+        self.pools.iter().position(|p| p.range.contains(&index))
+    }
+
+    fn region(&self, index: u32) -> &Region {
+        match self.owner(index) {
+            Some(p) => &self.pools[p],
+            None => &self.global,
+        }
+    }
+
+    fn region_mut(&mut self, index: u32) -> &mut Region {
+        match self.owner(index) {
+            Some(p) => &mut self.pools[p],
+            None => &mut self.global,
+        }
+    }
+
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn lifo_reuses_most_recently_freed_index() {
+        let mut e = Entities::new();
+        let ids: Vec<_> = (0..4).map(|_| e.alloc()).collect();
+        assert!(e.free(ids[1]));
+        assert!(e.free(ids[3]));
+        assert!(!e.free(ids[3]));
+        assert_eq!(e.len(), 2);
+        assert_eq!(e.alloc(), ids[3]);
+        assert_eq!(e.alloc(), ids[1]);
+        assert_eq!(e.alloc(), Entity(4));
+        assert_eq!(e.len(), 5);
+    }
+
+    #[test]
+    fn compacting_fills_fullest_leaf_block_first() {
+        let mut e = Entities::with_policy(AllocPolicy::Compacting);
+        let ids: Vec<_> = (0..384).map(|_| e.alloc()).collect();
+        // leaf 0 keeps 1 hole, leaf 2 gets 3 holes
+        e.free(ids[10]);
+        for i in [256, 300, 301] {
+            e.free(ids[i]);
+        }
+        assert_eq!(e.alloc(), Entity(10));
+        assert_eq!(e.alloc(), Entity(256));
+        assert_eq!(e.alloc(), Entity(300));
+        assert_eq!(e.alloc(), Entity(301));
+        assert_eq!(e.alloc(), Entity(384));
+    }
+
+    #[test]
+    fn near_hint_prefers_same_leaf_then_same_l1_block() {
+        let mut e = Entities::new();
+        for _ in 0..512 {
+            e.alloc();
+        }
+        e.free(Entity(20));
+        e.free(Entity(90));
+        e.free(Entity(400));
+        assert_eq!(e.alloc_with(AllocHint::Near(Entity(80))), Entity(90));
+        assert_eq!(e.alloc_with(AllocHint::Near(Entity(80))), Entity(20));
+        assert_eq!(e.alloc_with(AllocHint::Near(Entity(80))), Entity(400));
+        // a hint on the watermark leaf extends the run of fresh indices
+        assert_eq!(e.alloc(), Entity(512));
+        assert_eq!(e.alloc_with(AllocHint::Near(Entity(512))), Entity(513));
+        assert!(e.is_alive(Entity(513)));
+    }
+
+    #[test]
+    fn pools_allocate_inside_their_range_and_are_skipped_globally() {
+        let mut e = Entities::new();
+        let team = e.create_pool(128..384);
+        for _ in 0..128 {
+            e.alloc();
+        }
+        assert_eq!(e.alloc(), Entity(384));
+        assert_eq!(e.alloc_with(AllocHint::Pool(team)), Entity(128));
+        assert_eq!(e.alloc_with(AllocHint::Pool(team)), Entity(129));
+        e.free(Entity(5));
+        assert_eq!(e.alloc_with(AllocHint::Pool(team)), Entity(130));
+        assert_eq!(e.alloc_with(AllocHint::Near(Entity(129))), Entity(131));
+        assert_eq!(e.alloc(), Entity(5));
+        assert_eq!(e.pool_range(team), 128..384);
+    }
+
+    #[test]
+    fn defragment_packs_each_region_and_reports_moves() {
+        let mut e = Entities::new();
+        let pool = e.create_pool(256..512);
+        for _ in 0..10 {
+            e.alloc();
+        }
+        let pooled = [e.alloc_with(AllocHint::Pool(pool)), e.alloc_with(AllocHint::Pool(pool))];
+        e.alloc_aligned(5);
+        for i in [0, 3, 4] {
+            e.free(Entity(i));
+        }
+        e.free(pooled[0]);
+
+        let remap = e.defragment();
+        assert_eq!(remap.get(Entity(1)), Entity(0));
+        assert_eq!(remap.get(Entity(9)), Entity(6));
+        // the aligned batch at 128..133 closes the gap left by the alignment
+        assert_eq!(remap.get(Entity(128)), Entity(7));
+        assert_eq!(remap.get(Entity(132)), Entity(11));
+        assert_eq!(remap.get(pooled[1]), Entity(256));
+        assert!(remap.moves().windows(2).all(|w| w[0].0 < w[1].0));
+        assert_eq!(e.len(), 13);
+        assert_eq!(e.alloc(), Entity(12));
+        assert_eq!(e.alloc_with(AllocHint::Pool(pool)), Entity(257));
+    }
+
+    #[test]
+    fn alloc_aligned_skips_pools_and_keeps_gaps_free() {
+        let mut e = Entities::new();
+        e.create_pool(128..256);
+        e.alloc();
+        assert_eq!(e.alloc_aligned(200), 256..456);
+        assert_eq!(e.alloc(), Entity(1));
+        assert_eq!(e.iter().take(3).collect::<Vec<_>>(), vec![Entity(0), Entity(1), Entity(256)]);
+        assert_eq!(e.len(), 202);
+        assert_eq!(e.alive_mask(256 >> 7), u128::MAX);
+        assert_eq!(e.alive_mask(384 >> 7), (1u128 << 72) - 1);
+    }
+}
@@ -0,0 +1,177 @@
+use std::cell::{Ref, RefCell};
+use std::rc::Rc;
+
+/// Double-buffered queue of `E`s. Events sent during a tick stay readable through the
+/// next one and are dropped by the `end_tick` after that, so a reader running once per
+/// tick sees every event no matter where in the tick it was sent. Events are numbered
+/// in send order; readers keep the number of the next event they have not seen.
+pub struct Events<E> {
+    previous: Vec<E>,
+    current: Vec<E>,
+    /// Number of the first event in `previous`.
+    previous_start: u64,
+    /// Number of the first event in `current`.
+    current_start: u64,
+}
+
+impl<E> Default for Events<E> {
+    fn default() -> Self {
+        Self { previous: Vec::new(), current: Vec::new(), previous_start: 0, current_start: 0 }
+    }
+}
+
+impl<E> Events<E> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn send(&mut self, event: E) {
+        self.current.push(event);
+    }
+
+    /// Events still buffered, from both ticks.
+    pub fn len(&self) -> usize {
+        self.previous.len() + self.current.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Number the next event sent will get.
+    pub fn next_id(&self) -> u64 {
+        self.current_start + self.current.len() as u64
+    }
+
+    /// Number of the oldest event still buffered.
+    pub fn oldest_id(&self) -> u64 {
+        self.previous_start
+    }
+
+    /// Buffered events numbered `from` or later, oldest first.
+    pub fn iter_from(&self, from: u64) -> impl Iterator<Item = &E> + '_ {
+        let skip_previous = from.saturating_sub(self.previous_start) as usize;
+        let skip_current = from.saturating_sub(self.current_start) as usize;
+        self.previous.iter().skip(skip_previous).chain(self.current.iter().skip(skip_current))
+    }
+
+    /// Drop the events of the previous tick and start a new buffer for this one.
+    pub fn update(&mut self) {
+        self.previous = std::mem::take(&mut self.current);
+        self.previous_start = self.current_start;
+        self.current_start += self.previous.len() as u64;
+    }
+}
+
+/// Type-erased handle the world keeps for every event queue.
+pub trait EventQueue {
+    fn update(&self);
+}
+
+impl<E: 'static> EventQueue for Rc<RefCell<Events<E>>> {
+    fn update(&self) {
+        self.borrow_mut().update();
+    }
+}
+
+/// Sending end of an event queue, see `World::event_writer`.
+pub struct EventWriter<E> {
+    events: Rc<RefCell<Events<E>>>,
+}
+
+impl<E> EventWriter<E> {
+    pub fn new(events: Rc<RefCell<Events<E>>>) -> Self {
+        Self { events }
+    }
+
+    pub fn send(&self, event: E) {
+        self.events.borrow_mut().send(event);
+    }
+}
+
+/// Receiving end of an event queue with its own cursor: every reader sees each event
+/// once, provided it reads at least once per tick. See `World::event_reader`.
+pub struct EventReader<E> {
+    events: Rc<RefCell<Events<E>>>,
+    cursor: u64,
+}
+
+impl<E> EventReader<E> {
+    /// Reader starting at the oldest buffered event.
+    pub fn new(events: Rc<RefCell<Events<E>>>) -> Self {
+        let cursor = events.borrow().oldest_id();
+        Self { events, cursor }
+    }
+
+    /// Events sent since the last `read`. Events dropped before this reader got to
+    /// them are passed over.
+    pub fn read(&mut self) -> ReadEvents<'_, E> {
+        let events = self.events.borrow();
+        let from = self.cursor.max(events.oldest_id());
+        self.cursor = events.next_id();
+        ReadEvents { events, from }
+    }
+}
+
+/// Events returned by `EventReader::read`. Holds the queue borrowed, so writers of the
+/// same event type panic until it is dropped.
+pub struct ReadEvents<'a, E> {
+    events: Ref<'a, Events<E>>,
+    from: u64,
+}
+
+impl<E> ReadEvents<'_, E> {
+    pub fn iter(&self) -> impl Iterator<Item = &E> + '_ {
+        self.events.iter_from(self.from)
+    }
+
+    pub fn len(&self) -> usize {
+        (self.events.next_id() - self.from) as usize
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn events_live_for_two_ticks() {
+        let mut events = Events::new();
+        events.send(1);
+        events.update();
+        events.send(2);
+        assert_eq!(events.iter_from(0).copied().collect::<Vec<_>>(), [1, 2]);
+        events.update();
+        assert_eq!(events.iter_from(0).copied().collect::<Vec<_>>(), [2]);
+        assert_eq!((events.oldest_id(), events.next_id()), (1, 2));
+        events.update();
+        assert!(events.is_empty());
+    }
+
+    #[test]
+    fn readers_see_each_event_once() {
+        let events = Rc::new(RefCell::new(Events::new()));
+        let writer = EventWriter::new(events.clone());
+        let mut early = EventReader::new(events.clone());
+        writer.send("a");
+        writer.send("b");
+        assert_eq!(early.read().iter().copied().collect::<Vec<_>>(), ["a", "b"]);
+        assert!(early.read().is_empty());
+
+        events.update();
+        writer.send("c");
+        let mut late = EventReader::new(events.clone());
+        assert_eq!(early.read().iter().copied().collect::<Vec<_>>(), ["c"]);
+        assert_eq!(late.read().len(), 3);
+
+        // a reader that sleeps through two ticks misses what was dropped meanwhile
+        events.update();
+        events.update();
+        writer.send("d");
+        assert_eq!(early.read().iter().copied().collect::<Vec<_>>(), ["d"]);
+    }
+}
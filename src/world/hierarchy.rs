@@ -0,0 +1,157 @@
+use std::alloc::Allocator;
+use std::collections::VecDeque;
+
+use ercs_macros::Component;
+
+use crate::world::entity::{Entity, EntityRemap};
+use crate::world::set::EntitySet;
+use crate::world::world::World;
+
+/// The entity is a child of this parent. Set through `World::set_parent`, which keeps the
+/// parent's `Children` in step; inserting it directly leaves `Children` stale.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Component)]
+pub struct ChildOf(pub Entity);
+
+/// Reverse of `ChildOf`, in the order the children were attached. Only present while
+/// the list is non-empty.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Component)]
+pub struct Children(Vec<Entity>);
+
+impl Children {
+    pub fn as_slice(&self) -> &[Entity] {
+        &self.0
+    }
+
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+}
+
+/// Visiting order of `World::descendants`.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Traversal {
+    /// Pre-order: each entity comes before its children and after its earlier siblings' subtrees.
+    DepthFirst,
+    /// Level by level, siblings in attach order.
+    BreadthFirst,
+}
+
+impl<A: Allocator + Copy + 'static, L: Allocator + Copy + 'static> World<A, L> {
+    /// Make `child` a child of `parent`, detaching it from its previous parent first.
+    /// Panics if either entity is dead or if `parent` is `child` or one of its descendants.
+    pub fn set_parent(&mut self, child: Entity, parent: Entity) {
+        assert!(self.entities().is_alive(child) && self.entities().is_alive(parent), "set_parent on a dead entity");
+        assert!(
+            child != parent && !self.ancestors(parent).contains(&child),
+            "{:?} cannot become a child of its descendant {:?}",
+            child,
+            parent
+        );
+        self.remove_parent(child);
+        self.insert(child, ChildOf(parent));
+        let attached = match self.get::<Children>().borrow_mut().get_any_mut(parent.0) {
+            Some(list) => {
+                list.0.push(child);
+                true
+            }
+            None => false,
+        };
+        if !attached {
+            self.insert(parent, Children(vec![child]));
+        }
+    }
+
+    /// Detach `child` from its parent; returns the parent it had.
+    pub fn remove_parent(&mut self, child: Entity) -> Option<Entity> {
+        let ChildOf(parent) = self.storage::<ChildOf>()?.borrow_mut().remove(child.0)?;
+        let Some(children) = self.storage::<Children>() else { return Some(parent) };
+        let mut children = children.borrow_mut();
+        if let Some(list) = children.get_any_mut(parent.0) {
+            list.0.retain(|&other| other != child);
+            if list.0.is_empty() {
+                children.remove(parent.0);
+            }
+        }
+        Some(parent)
+    }
+
+    /// Parent of `entity`, also while either of them is disabled.
+    pub fn parent(&self, entity: Entity) -> Option<Entity> {
+        let storage = self.storage::<ChildOf>()?;
+        let parent = storage.borrow().get_any(entity.0).map(|c| c.0);
+        parent
+    }
+
+    pub fn children(&self, entity: Entity) -> Vec<Entity> {
+        self.storage::<Children>()
+            .and_then(|storage| storage.borrow().get_any(entity.0).map(|c| c.0.clone()))
+            .unwrap_or_default()
+    }
+
+    /// Parent, grandparent and so on up to the root.
+    pub fn ancestors(&self, entity: Entity) -> Vec<Entity> {
+        let mut out = Vec::new();
+        let mut current = entity;
+        while let Some(parent) = self.parent(current) {
+            out.push(parent);
+            current = parent;
+        }
+        out
+    }
+
+    /// Every entity below `entity`, not including itself.
+    pub fn descendants(&self, entity: Entity, order: Traversal) -> Vec<Entity> {
+        let Some(storage) = self.storage::<Children>() else { return Vec::new() };
+        let storage = storage.borrow();
+        let children = |e: Entity| storage.get_any(e.0).map_or(&[][..], |c| c.as_slice());
+        let mut out = Vec::new();
+        match order {
+            Traversal::DepthFirst => {
+                let mut stack: Vec<Entity> = children(entity).iter().rev().copied().collect();
+                while let Some(e) = stack.pop() {
+                    out.push(e);
+                    stack.extend(children(e).iter().rev());
+                }
+            }
+            Traversal::BreadthFirst => {
+                let mut queue: VecDeque<Entity> = children(entity).iter().copied().collect();
+                while let Some(e) = queue.pop_front() {
+                    out.push(e);
+                    queue.extend(children(e));
+                }
+            }
+        }
+        out
+    }
+
+    /// Rewrite the `ChildOf` and `Children` values that refer to entities moved by a
+    /// defragmentation. The components themselves have been relocated already.
+    pub(crate) fn remap_hierarchy(&self, remap: &EntityRemap) {
+        let (Some(parents), Some(children)) = (self.storage::<ChildOf>(), self.storage::<Children>()) else { return };
+        let mut parents = parents.borrow_mut();
+        let mut children = children.borrow_mut();
+        // only the moved entities and their relatives can hold a stale handle
+        let mut touched = EntitySet::new();
+        for &(_, new) in remap.moves() {
+            touched.insert(new);
+            if let Some(parent) = parents.get_any(new.0) {
+                touched.insert(remap.get(parent.0));
+            }
+            if let Some(list) = children.get_any(new.0) {
+                touched.extend(list.0.iter().map(|&c| remap.get(c)));
+            }
+        }
+        for entity in touched.iter() {
+            if let Some(parent) = parents.get_any_mut(entity.0) {
+                parent.0 = remap.get(parent.0);
+            }
+            if let Some(list) = children.get_any_mut(entity.0) {
+                list.0.iter_mut().for_each(|c| *c = remap.get(*c));
+            }
+        }
+    }
+}
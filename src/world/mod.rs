@@ -1,4 +1,19 @@
 mod world;
+mod entity;
+mod commands;
+mod set;
+mod observer;
+mod events;
+mod hierarchy;
+mod relation;
+#[cfg(test)]
 mod tests;
 
-pub use world::*;
\ No newline at end of file
+pub use world::*;
+pub use entity::*;
+pub use commands::*;
+pub use set::*;
+pub use observer::*;
+pub use events::*;
+pub use hierarchy::*;
+pub use relation::*;
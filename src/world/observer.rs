@@ -0,0 +1,69 @@
+use std::alloc::{Allocator, Global};
+
+use crate::query::{CachedQuery, Query};
+use crate::world::commands::Commands;
+use crate::world::entity::Entity;
+use crate::world::world::World;
+
+type Callback<A, L> = Box<dyn FnMut(&World<A, L>, &[Entity], &mut Commands<A, L>)>;
+
+/// Returned by `World::observe`, used to remove the observer again.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub struct ObserverId(pub u32);
+
+/// Callbacks for entities that start or stop matching a query, run by `World::flush`
+/// with every entity that changed since the previous flush, in index order. Only the net
+/// change counts: an entity that enters and leaves between two flushes is not reported.
+///
+/// Callbacks see the world after the change and defer their own mutations through the
+/// given `Commands`, which the same flush applies before it reports again. Exited
+/// entities may already be despawned.
+pub struct Observer<A: Allocator + Copy + 'static = Global, L: Allocator + Copy + 'static = A> {
+    cache: CachedQuery,
+    on_enter: Option<Callback<A, L>>,
+    on_exit: Option<Callback<A, L>>,
+}
+
+impl<A: Allocator + Copy + 'static, L: Allocator + Copy + 'static> Observer<A, L> {
+    pub fn new(query: Query) -> Self {
+        Self { cache: CachedQuery::new(query), on_enter: None, on_exit: None }
+    }
+
+    pub fn on_enter(mut self, f: impl FnMut(&World<A, L>, &[Entity], &mut Commands<A, L>) + 'static) -> Self {
+        self.on_enter = Some(Box::new(f));
+        self
+    }
+
+    pub fn on_exit(mut self, f: impl FnMut(&World<A, L>, &[Entity], &mut Commands<A, L>) + 'static) -> Self {
+        self.on_exit = Some(Box::new(f));
+        self
+    }
+
+    /// Take the current matches as the baseline without reporting them.
+    pub(crate) fn prime(&mut self, world: &World<A, L>) {
+        self.cache.update(world);
+    }
+
+    /// Report the entities that entered or left since the last call.
+    pub(crate) fn notify(&mut self, world: &World<A, L>, commands: &mut Commands<A, L>) {
+        let mut entered = Vec::new();
+        let mut exited = Vec::new();
+        self.cache.update_with(world, |base, was, now| {
+            push_bits(&mut entered, base, now & !was);
+            push_bits(&mut exited, base, was & !now);
+        });
+        if let (Some(f), false) = (&mut self.on_enter, entered.is_empty()) {
+            f(world, &entered, commands);
+        }
+        if let (Some(f), false) = (&mut self.on_exit, exited.is_empty()) {
+            f(world, &exited, commands);
+        }
+    }
+}
+
+fn push_bits(out: &mut Vec<Entity>, base: u32, mut mask: u128) {
+    while mask != 0 {
+        out.push(Entity(base | mask.trailing_zeros()));
+        mask &= mask - 1;
+    }
+}
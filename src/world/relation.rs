@@ -0,0 +1,229 @@
+use std::alloc::{Allocator, Global};
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::marker::PhantomData;
+use std::rc::Rc;
+
+use crate::component::Component;
+use crate::storage::storage::SparseStorage;
+use crate::world::entity::{Entity, EntityRemap};
+use crate::world::set::EntitySet;
+use crate::world::world::World;
+
+/// Kind of an entity-to-entity pair, e.g. `struct Likes; impl Relation for Likes {}`.
+pub trait Relation: 'static {}
+
+/// Targets of one source, in the order they were related.
+struct Pairs(Vec<Entity>);
+
+impl Component for Pairs {}
+
+/// Every `(source, R, target)` pair of one relation kind. Sources map to their targets
+/// through a sparse storage; the reverse index maps each target to the set of its
+/// sources, so both directions cost a lookup rather than a scan.
+///
+/// The sets handed out by `sources` and `sources_of` are shared snapshots: the next
+/// change to the relation copies them instead of touching the shared ones.
+pub struct Relations<R: Relation, A: Allocator + Copy = Global, L: Allocator + Copy = A> {
+    forward: SparseStorage<Pairs, A, L>,
+    reverse: HashMap<Entity, Rc<EntitySet>>,
+    sources: Rc<EntitySet>,
+    len: usize,
+    kind: PhantomData<R>,
+}
+
+impl<R: Relation> Default for Relations<R> {
+    fn default() -> Self {
+        Self::new_in(Global, Global)
+    }
+}
+
+impl<R: Relation> Relations<R> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl<R: Relation, A: Allocator + Copy, L: Allocator + Copy> Relations<R, A, L> {
+    /// Relations whose forward storage takes its blocks from the given allocators, as a
+    /// world's storages do.
+    pub fn new_in(alloc: A, leaf_alloc: L) -> Self {
+        Self {
+            forward: SparseStorage::new_in(alloc, leaf_alloc),
+            reverse: HashMap::new(),
+            sources: Rc::default(),
+            len: 0,
+            kind: PhantomData,
+        }
+    }
+
+    /// Number of pairs.
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Returns false if the pair existed already.
+    pub fn insert(&mut self, source: Entity, target: Entity) -> bool {
+        match self.forward.get_any_mut(source.0) {
+            Some(pairs) if pairs.0.contains(&target) => return false,
+            Some(pairs) => pairs.0.push(target),
+            None => {
+                self.forward.insert(source.0, Pairs(vec![target]));
+                Rc::make_mut(&mut self.sources).insert(source);
+            }
+        }
+        Rc::make_mut(self.reverse.entry(target).or_default()).insert(source);
+        self.len += 1;
+        true
+    }
+
+    /// Returns false if there was no such pair.
+    pub fn remove(&mut self, source: Entity, target: Entity) -> bool {
+        let Some(pairs) = self.forward.get_any_mut(source.0) else { return false };
+        let Some(i) = pairs.0.iter().position(|&t| t == target) else { return false };
+        pairs.0.remove(i);
+        if pairs.0.is_empty() {
+            self.forward.remove(source.0);
+            Rc::make_mut(&mut self.sources).remove(source);
+        }
+        let set = self.reverse.get_mut(&target).expect("reverse index out of step");
+        Rc::make_mut(set).remove(source);
+        if set.is_empty() {
+            self.reverse.remove(&target);
+        }
+        self.len -= 1;
+        true
+    }
+
+    pub fn contains(&self, source: Entity, target: Entity) -> bool {
+        self.targets(source).contains(&target)
+    }
+
+    pub fn targets(&self, source: Entity) -> &[Entity] {
+        self.forward.get_any(source.0).map_or(&[], |pairs| &pairs.0)
+    }
+
+    /// Entities related to `target`: "everything that `R`s `target`". Usable as a query
+    /// filter through `Query::within`.
+    pub fn sources_of(&self, target: Entity) -> Rc<EntitySet> {
+        self.reverse.get(&target).cloned().unwrap_or_default()
+    }
+
+    /// Entities with at least one `R` pair, whatever the target.
+    pub fn sources(&self) -> Rc<EntitySet> {
+        self.sources.clone()
+    }
+
+    /// Every `(source, target)` pair, sources in index order.
+    pub fn pairs(&self) -> impl Iterator<Item = (Entity, Entity)> + '_ {
+        self.sources.iter().flat_map(move |source| self.targets(source).iter().map(move |&target| (source, target)))
+    }
+
+    /// Rewrite the pairs with a moved source or target. Targets keep their order, and
+    /// every other pair, along with the sets handed out for it, is left alone.
+    fn remap(&mut self, remap: &EntityRemap) {
+        let moved = |entity: Entity| remap.get(entity) != entity;
+        let mut pairs = Vec::new();
+        let mut stayed = Vec::new();
+        for &(old, _) in remap.moves() {
+            pairs.extend(self.targets(old).iter().map(|&target| (old, target)));
+            for source in self.sources_of(old).iter().filter(|&source| !moved(source)) {
+                pairs.push((source, old));
+                stayed.push(source);
+            }
+        }
+
+        // take every moved list out before putting one back, new indices reuse old ones
+        let lists: Vec<_> = remap
+            .moves()
+            .iter()
+            .filter_map(|&(old, new)| self.forward.remove(old.0).map(|list| (old, new, list)))
+            .collect();
+        if !lists.is_empty() {
+            let sources = Rc::make_mut(&mut self.sources);
+            for &(old, _, _) in &lists {
+                sources.remove(old);
+            }
+            for &(_, new, _) in &lists {
+                sources.insert(new);
+            }
+        }
+        for (_, new, mut list) in lists {
+            list.0.iter_mut().for_each(|target| *target = remap.get(*target));
+            self.forward.insert(new.0, list);
+        }
+        // a source with several moved targets must be rewritten once
+        stayed.sort_unstable();
+        stayed.dedup();
+        for source in stayed {
+            let list = self.forward.get_any_mut(source.0).expect("forward index out of step");
+            list.0.iter_mut().for_each(|target| *target = remap.get(*target));
+        }
+
+        for &(source, target) in &pairs {
+            let set = self.reverse.get_mut(&target).expect("reverse index out of step");
+            Rc::make_mut(set).remove(source);
+            if set.is_empty() {
+                self.reverse.remove(&target);
+            }
+        }
+        for (source, target) in pairs {
+            Rc::make_mut(self.reverse.entry(remap.get(target)).or_default()).insert(remap.get(source));
+        }
+    }
+
+    /// Drop every pair `entity` takes part in, on either side.
+    pub fn forget(&mut self, entity: Entity) {
+        for target in self.targets(entity).to_vec() {
+            self.remove(entity, target);
+        }
+        // collected first so the reverse set is not shared while it shrinks
+        let sources: Vec<Entity> = self.sources_of(entity).iter().collect();
+        for source in sources {
+            self.remove(source, entity);
+        }
+    }
+}
+
+/// Type-erased handle the world keeps for every relation kind.
+pub trait RelationIndex {
+    fn forget(&self, entity: Entity);
+    /// Rewrite the pairs of moved entities after a defragmentation, on either end.
+    fn remap(&self, remap: &EntityRemap);
+}
+
+impl<R: Relation, A: Allocator + Copy, L: Allocator + Copy> RelationIndex for Rc<RefCell<Relations<R, A, L>>> {
+    fn forget(&self, entity: Entity) {
+        self.borrow_mut().forget(entity);
+    }
+
+    fn remap(&self, remap: &EntityRemap) {
+        self.borrow_mut().remap(remap);
+    }
+}
+
+impl<A: Allocator + Copy + 'static, L: Allocator + Copy + 'static> World<A, L> {
+    /// Add the pair `(source, R, target)`; returns false if it existed already.
+    /// Panics if either entity is dead.
+    pub fn relate<R: Relation>(&mut self, source: Entity, target: Entity) -> bool {
+        assert!(self.entities().is_alive(source) && self.entities().is_alive(target), "relate on a dead entity");
+        self.relations::<R>().borrow_mut().insert(source, target)
+    }
+
+    pub fn unrelate<R: Relation>(&mut self, source: Entity, target: Entity) -> bool {
+        self.relations::<R>().borrow_mut().remove(source, target)
+    }
+
+    /// Entities that `R` `target`, as a set for `Query::within`.
+    pub fn related_to<R: Relation>(&self, target: Entity) -> Rc<EntitySet> {
+        self.find_relations::<R>().map(|relations| relations.borrow().sources_of(target)).unwrap_or_default()
+    }
+
+    pub fn targets_of<R: Relation>(&self, source: Entity) -> Vec<Entity> {
+        self.find_relations::<R>().map(|relations| relations.borrow().targets(source).to_vec()).unwrap_or_default()
+    }
+}
@@ -0,0 +1,361 @@
+use std::fmt;
+
+use crate::storage::roaring::{self, RoaringError};
+use crate::storage::storage::{MAX_INDEX, split_index};
+use crate::storage::validate::BlockPath;
+use crate::world::entity::Entity;
+
+/// Leaf masks of one L1 block.
+#[derive(Clone, PartialEq, Eq)]
+struct Mid {
+    mask: u128,
+    leaves: [u128; 128],
+}
+
+/// Set of entities laid out like the mask tree of a `SparseStorage`: a root mask, one
+/// L1 mask per root slot and one leaf mask per 128 entities. Empty blocks are freed, so
+/// a parent bit is set iff something below it is.
+#[derive(Clone, Default)]
+pub struct EntitySet {
+    root: u128,
+    mids: Vec<Option<Box<Mid>>>,
+    len: usize,
+}
+
+impl EntitySet {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn clear(&mut self) {
+        *self = Self::default();
+    }
+
+    /// Returns false if the entity was in the set already.
+    pub fn insert(&mut self, entity: Entity) -> bool {
+        assert!(entity.0 < MAX_INDEX, "{:?} out of range for an EntitySet", entity);
+        let (r, m, l) = split_index(entity.0);
+        if self.mids.len() <= r {
+            self.mids.resize(r + 1, None);
+        }
+        let mid = self.mids[r].get_or_insert_with(|| Box::new(Mid { mask: 0, leaves: [0; 128] }));
+        let bit = 1u128 << l;
+        if mid.leaves[m] & bit != 0 {
+            return false;
+        }
+        mid.leaves[m] |= bit;
+        mid.mask |= 1u128 << m;
+        self.root |= 1u128 << r;
+        self.len += 1;
+        true
+    }
+
+    /// Returns false if the entity was not in the set.
+    pub fn remove(&mut self, entity: Entity) -> bool {
+        if !self.contains(entity) {
+            return false;
+        }
+        let (r, m, l) = split_index(entity.0);
+        let mid = self.mids[r].as_mut().unwrap();
+        mid.leaves[m] &= !(1u128 << l);
+        if mid.leaves[m] == 0 {
+            mid.mask &= !(1u128 << m);
+            if mid.mask == 0 {
+                self.mids[r] = None;
+                self.root &= !(1u128 << r);
+            }
+        }
+        self.len -= 1;
+        true
+    }
+
+    pub fn contains(&self, entity: Entity) -> bool {
+        entity.0 < MAX_INDEX && self.leaf_mask(entity.0 & !127) & (1u128 << (entity.0 & 127)) != 0
+    }
+
+    /// Replace the leaf mask of the block at `base` and return the old one.
+    pub fn set_block(&mut self, base: u32, mask: u128) -> u128 {
+        assert!(base < MAX_INDEX, "block {} out of range for an EntitySet", base);
+        let (r, m, _) = split_index(base);
+        if self.mids.len() <= r {
+            self.mids.resize(r + 1, None);
+        }
+        let mid = self.mids[r].get_or_insert_with(|| Box::new(Mid { mask: 0, leaves: [0; 128] }));
+        let old = std::mem::replace(&mut mid.leaves[m], mask);
+        if mask != 0 {
+            mid.mask |= 1u128 << m;
+        } else {
+            mid.mask &= !(1u128 << m);
+        }
+        if mid.mask == 0 {
+            self.mids[r] = None;
+            self.root &= !(1u128 << r);
+        } else {
+            self.root |= 1u128 << r;
+        }
+        self.len = self.len + mask.count_ones() as usize - old.count_ones() as usize;
+        old
+    }
+
+    /// Mask of the block at `path`: the root, an L1 block or a leaf.
+    pub fn masks(&self, path: BlockPath) -> u128 {
+        match path.level {
+            0 => self.root,
+            1 => self.mid(path.base).map_or(0, |mid| mid.mask),
+            _ => self.leaf_mask(path.base),
+        }
+    }
+
+    pub fn iter(&self) -> SetIter<'_> {
+        SetIter::new(self, self, SetOp::Union)
+    }
+
+    /// Entities in `self` or `other`.
+    pub fn union<'a>(&'a self, other: &'a EntitySet) -> SetIter<'a> {
+        SetIter::new(self, other, SetOp::Union)
+    }
+
+    /// Entities in both sets; whole L1 and leaf blocks missing from either are skipped.
+    pub fn intersection<'a>(&'a self, other: &'a EntitySet) -> SetIter<'a> {
+        SetIter::new(self, other, SetOp::Intersection)
+    }
+
+    /// Entities in `self` but not in `other`.
+    pub fn difference<'a>(&'a self, other: &'a EntitySet) -> SetIter<'a> {
+        SetIter::new(self, other, SetOp::Difference)
+    }
+
+    /// Entities in exactly one of the sets.
+    pub fn symmetric_difference<'a>(&'a self, other: &'a EntitySet) -> SetIter<'a> {
+        SetIter::new(self, other, SetOp::SymmetricDifference)
+    }
+
+    /// Non-empty leaf blocks as `(base, mask)`, in index order.
+    pub fn blocks(&self) -> impl Iterator<Item = (u32, u128)> + '_ {
+        let mut iter = self.iter();
+        std::iter::from_fn(move || iter.next_block())
+    }
+
+    /// Serialize in the Roaring bitmap portable format.
+    pub fn to_roaring(&self) -> Vec<u8> {
+        roaring::encode(self.blocks())
+    }
+
+    pub fn from_roaring(bytes: &[u8]) -> Result<Self, RoaringError> {
+        let mut set = EntitySet::new();
+        for (base, mut mask) in roaring::decode(bytes)? {
+            while mask != 0 {
+                set.insert(Entity(base | mask.trailing_zeros()));
+                mask &= mask - 1;
+            }
+        }
+        Ok(set)
+    }
+
+    fn mid(&self, base: u32) -> Option<&Mid> {
+        self.mids.get(split_index(base).0).and_then(|mid| mid.as_deref())
+    }
+
+    fn leaf_mask(&self, base: u32) -> u128 {
+        self.mid(base).map_or(0, |mid| mid.leaves[split_index(base).1])
+    }
+}
+
+impl PartialEq for EntitySet {
+    fn eq(&self, other: &Self) -> bool {
+        if self.root != other.root || self.len != other.len {
+            return false;
+        }
+        let mut roots = self.root;
+        while roots != 0 {
+            let r = roots.trailing_zeros() as usize;
+            roots &= roots - 1;
+            if self.mids[r] != other.mids[r] {
+                return false;
+            }
+        }
+        true
+    }
+}
+
+impl Eq for EntitySet {}
+
+impl fmt::Debug for EntitySet {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_set().entries(self.iter()).finish()
+    }
+}
+
+impl Extend<Entity> for EntitySet {
+    fn extend<I: IntoIterator<Item = Entity>>(&mut self, iter: I) {
+        for entity in iter {
+            self.insert(entity);
+        }
+    }
+}
+
+impl FromIterator<Entity> for EntitySet {
+    fn from_iter<I: IntoIterator<Item = Entity>>(iter: I) -> Self {
+        let mut set = EntitySet::new();
+        set.extend(iter);
+        set
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+enum SetOp {
+    Union,
+    Intersection,
+    Difference,
+    SymmetricDifference,
+}
+
+impl SetOp {
+    /// Combine the masks of two blocks. Inner blocks may only be pruned by slots that
+    /// cannot produce a result below them, so difference keeps every slot of `a` and
+    /// symmetric difference every slot of either side.
+    fn apply(self, a: u128, b: u128, leaf: bool) -> u128 {
+        match (self, leaf) {
+            (SetOp::Union, _) => a | b,
+            (SetOp::Intersection, _) => a & b,
+            (SetOp::Difference, false) => a,
+            (SetOp::Difference, true) => a & !b,
+            (SetOp::SymmetricDifference, false) => a | b,
+            (SetOp::SymmetricDifference, true) => a ^ b,
+        }
+    }
+}
+
+/// Entities of a set operation in ascending order, computed one block at a time.
+pub struct SetIter<'a> {
+    a: &'a EntitySet,
+    b: &'a EntitySet,
+    op: SetOp,
+    roots: u128,
+    r: u32,
+    mids: u128,
+    base: u32,
+    leaf: u128,
+}
+
+impl<'a> SetIter<'a> {
+    fn new(a: &'a EntitySet, b: &'a EntitySet, op: SetOp) -> Self {
+        let roots = op.apply(a.root, b.root, false);
+        Self { a, b, op, roots, r: 0, mids: 0, base: 0, leaf: 0 }
+    }
+
+    /// Next non-empty leaf block of the result as `(base, mask)`.
+    pub fn next_block(&mut self) -> Option<(u32, u128)> {
+        loop {
+            if self.mids != 0 {
+                let m = self.mids.trailing_zeros();
+                self.mids &= self.mids - 1;
+                let base = (self.r << 14) | (m << 7);
+                let mask = self.op.apply(self.a.leaf_mask(base), self.b.leaf_mask(base), true);
+                if mask != 0 {
+                    return Some((base, mask));
+                }
+                continue;
+            }
+            if self.roots == 0 {
+                return None;
+            }
+            self.r = self.roots.trailing_zeros();
+            self.roots &= self.roots - 1;
+            let path = BlockPath { level: 1, base: self.r << 14 };
+            self.mids = self.op.apply(self.a.masks(path), self.b.masks(path), false);
+        }
+    }
+
+    /// Collect the result into a new set.
+    pub fn to_set(mut self) -> EntitySet {
+        let mut set = EntitySet::new();
+        while let Some((base, mut mask)) = self.next_block() {
+            while mask != 0 {
+                set.insert(Entity(base | mask.trailing_zeros()));
+                mask &= mask - 1;
+            }
+        }
+        set
+    }
+}
+
+impl Iterator for SetIter<'_> {
+    type Item = Entity;
+
+    fn next(&mut self) -> Option<Entity> {
+        while self.leaf == 0 {
+            let (base, mask) = self.next_block()?;
+            self.base = base;
+            self.leaf = mask;
+        }
+        let slot = self.leaf.trailing_zeros();
+        self.leaf &= self.leaf - 1;
+        Some(Entity(self.base | slot))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn set(indices: &[u32]) -> EntitySet {
+        indices.iter().map(|&i| Entity(i)).collect()
+    }
+
+    fn indices(iter: SetIter<'_>) -> Vec<u32> {
+        iter.map(|e| e.0).collect()
+    }
+
+    #[test]
+    fn insert_remove_frees_emptied_blocks() {
+        let mut s = EntitySet::new();
+        assert!(s.insert(Entity(5)));
+        assert!(!s.insert(Entity(5)));
+        assert!(s.insert(Entity(40_000)));
+        assert_eq!(s.len(), 2);
+        assert!(s.contains(Entity(40_000)) && !s.contains(Entity(40_001)));
+        assert_eq!(s.masks(BlockPath { level: 0, base: 0 }), 0b101);
+
+        assert!(s.remove(Entity(40_000)));
+        assert!(!s.remove(Entity(40_000)));
+        assert_eq!(s.masks(BlockPath { level: 0, base: 0 }), 0b001);
+        assert_eq!(s, set(&[5]));
+    }
+
+    #[test]
+    fn set_algebra_matches_naive_results() {
+        let a = set(&[1, 2, 3, 130, 20_000, 20_001]);
+        let b = set(&[2, 3, 4, 20_001, 50_000]);
+        assert_eq!(indices(a.union(&b)), vec![1, 2, 3, 4, 130, 20_000, 20_001, 50_000]);
+        assert_eq!(indices(a.intersection(&b)), vec![2, 3, 20_001]);
+        assert_eq!(indices(a.difference(&b)), vec![1, 130, 20_000]);
+        assert_eq!(indices(a.symmetric_difference(&b)), vec![1, 4, 130, 20_000, 50_000]);
+        assert_eq!(a.intersection(&b).to_set().len(), 3);
+    }
+
+    #[test]
+    fn roaring_round_trip() {
+        let s: EntitySet = (0..5000).map(|i| Entity(i * 7)).chain((100_000..100_500).map(Entity)).collect();
+        let bytes = s.to_roaring();
+        assert_eq!(EntitySet::from_roaring(&bytes).unwrap(), s);
+        assert_eq!(EntitySet::from_roaring(&EntitySet::new().to_roaring()).unwrap(), EntitySet::new());
+    }
+
+    #[test]
+    fn intersection_prunes_disjoint_subtrees() {
+        let a = set(&[0, 1]);
+        let b = set(&[16_384, 16_385]);
+        let mut it = a.intersection(&b);
+        assert_eq!(it.roots, 0);
+        assert_eq!(it.next_block(), None);
+    }
+}
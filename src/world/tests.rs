@@ -1,5 +1,6 @@
 use std::rc::Rc;
 use crate::world::world::World;
+use crate::world::EntityRange;
 use ercs_macros::Component;
 use std::alloc::Global;
 use crate::storage::storage::SparseStorage;
@@ -9,3 +10,657 @@ struct Foo { v: usize }
 
 #[derive(Default, Component)]
 struct Bar { name: &'static str }
+
+#[test]
+fn spawn_inserts_component_and_recycles_indices() {
+    let mut world = World::new();
+    let a = world.spawn(Foo { v: 1 });
+    let b = world.spawn(Foo { v: 2 });
+    assert_ne!(a, b);
+    world.insert(b, Bar { name: "b" });
+
+    let foos: Rc<_> = world.get::<Foo>();
+    assert_eq!(foos.borrow().get(b.0).map(|f| f.v), Some(2));
+    assert_eq!(world.get::<Bar>().borrow().get(b.0).map(|b| b.name), Some("b"));
+
+    assert_eq!(world.remove::<Foo>(a).map(|f| f.v), Some(1));
+    assert!(!foos.borrow().contains(a.0));
+}
+
+#[test]
+fn get_returns_shared_storage() {
+    let mut world = World::new();
+    let s1: Rc<std::cell::RefCell<SparseStorage<Foo, Global>>> = world.get::<Foo>();
+    let s2 = world.get::<Foo>();
+    assert!(Rc::ptr_eq(&s1, &s2));
+}
+
+#[test]
+fn try_spawn_succeeds_with_global_allocator() {
+    let mut world = World::new();
+    let e = world.try_spawn(Foo { v: 7 }).unwrap();
+    assert_eq!(world.get::<Foo>().borrow().get(e.0).map(|f| f.v), Some(7));
+}
+
+#[test]
+fn register_in_splits_leaf_and_inner_pools() {
+    use crate::storage::pool::{BlockPool, PoolHandle};
+
+    let mut world = World::with_pools(
+        BlockPool::for_sparse_inner::<PoolHandle>(),
+        BlockPool::for_sparse_leaf::<Bar, PoolHandle>(),
+    );
+    let inner = world.allocator();
+    let leaves = world.own_pool(BlockPool::for_sparse_leaf::<Foo, PoolHandle>());
+    world.register_in::<Foo>(inner, leaves);
+
+    for i in 0..4 {
+        let e = world.spawn(Foo { v: i });
+        assert_eq!(e.0, i as u32);
+    }
+    // one L1 block from the inner pool, one leaf block from the leaf pool
+    assert_eq!(inner.chunks(), 1);
+    assert_eq!(leaves.chunks(), 1);
+    let foos = world.get::<Foo>();
+    assert_eq!(foos.borrow().get(3).map(|f| f.v), Some(3));
+
+    // storages without an override use the world's leaf pool for their leaves
+    world.spawn(Bar { name: "bar" });
+    assert_eq!(leaves.chunks(), 1);
+    assert_eq!(world.leaf_allocator().chunks(), 1);
+}
+
+#[test]
+fn end_tick_clears_transient_storages() {
+    use std::cell::Cell;
+
+    thread_local! { static DROPS: Cell<usize> = const { Cell::new(0) }; }
+    struct Damage(u32);
+    impl crate::component::Component for Damage {}
+    impl Drop for Damage { fn drop(&mut self) { DROPS.with(|d| d.set(d.get() + 1)); } }
+
+    let mut world = World::new();
+    let contacts = world.transient::<Foo>();
+    let damage = world.transient::<Damage>();
+    for i in 0..300 {
+        contacts.borrow_mut().insert(i, Foo { v: i as usize });
+    }
+    damage.borrow_mut().insert(5, Damage(10));
+    damage.borrow_mut().insert(900, Damage(20));
+    assert!(Rc::ptr_eq(&contacts, &world.transient::<Foo>()));
+
+    let before = world.tick();
+    world.end_tick();
+    assert_eq!(world.tick() - before, crate::tick::TickDelta::new(1));
+    assert!(!contacts.borrow().contains(0));
+    assert!(!contacts.borrow().contains(299));
+    assert_eq!(DROPS.with(|d| d.get()), 2);
+
+    contacts.borrow_mut().insert(1, Foo { v: 1 });
+    assert_eq!(contacts.borrow().get(1).map(|f| f.v), Some(1));
+    world.end_tick();
+    assert!(!contacts.borrow().contains(1));
+}
+
+#[derive(Default, Component)]
+struct Baz(u32);
+
+#[derive(ercs_macros::Bundle)]
+struct Unit {
+    foo: Foo,
+    bar: Bar,
+}
+
+#[derive(ercs_macros::Bundle)]
+struct Pair(Foo, Baz);
+
+#[test]
+fn spawn_bundle_inserts_every_component() {
+    let mut world = World::new();
+    let e = world.spawn(Unit { foo: Foo { v: 3 }, bar: Bar { name: "unit" } });
+    assert_eq!(world.get::<Foo>().borrow().get(e.0).map(|f| f.v), Some(3));
+    assert_eq!(world.get::<Bar>().borrow().get(e.0).map(|b| b.name), Some("unit"));
+
+    let t = world.spawn((Foo { v: 4 }, Baz(5)));
+    assert_eq!(world.get::<Baz>().borrow().get(t.0).map(|b| b.0), Some(5));
+}
+
+#[test]
+#[should_panic(expected = "holds a component type twice")]
+fn bundles_reject_repeated_components() {
+    let mut world = World::new();
+    world.spawn((Foo { v: 1 }, Baz(2), Foo { v: 3 }));
+}
+
+#[test]
+fn insert_and_remove_bundle() {
+    let mut world = World::new();
+    let e = world.spawn(Bar { name: "e" });
+    world.insert_bundle(e, Pair(Foo { v: 1 }, Baz(2)));
+    world.insert_bundle(e, Pair(Foo { v: 10 }, Baz(20)));
+    assert_eq!(world.get::<Foo>().borrow().get(e.0).map(|f| f.v), Some(10));
+
+    let removed = world.remove_bundle::<Pair>(e).unwrap();
+    assert_eq!((removed.0.v, removed.1.0), (10, 20));
+    assert!(!world.get::<Foo>().borrow().contains(e.0));
+    assert!(!world.get::<Baz>().borrow().contains(e.0));
+    assert!(world.get::<Bar>().borrow().contains(e.0));
+    assert!(world.remove_bundle::<Pair>(e).is_none());
+    assert_eq!(world.get::<Foo>().borrow().root.presence_mask, 0);
+
+    // a partial bundle is left alone
+    world.insert(e, Baz(7));
+    assert!(world.remove_bundle::<Pair>(e).is_none());
+    assert_eq!(world.get::<Baz>().borrow().get(e.0).map(|b| b.0), Some(7));
+}
+
+#[test]
+fn spawn_batch_fills_whole_leaf_blocks() {
+    let mut world = World::new();
+    let first = world.spawn(Baz(0));
+    let entities: Vec<_> = world.spawn_batch((0..300u32).map(|i| (Foo { v: i as usize }, Baz(i)))).collect();
+    assert_eq!(entities.len(), 300);
+    assert_eq!(entities[0].0, 128);
+    assert_eq!(entities[299].0, 427);
+
+    let foos = world.get::<Foo>();
+    let foos = foos.borrow();
+    let mid = unsafe { foos.root.data[0].assume_init_ref() };
+    assert_eq!(mid.presence_mask, 0b1110);
+    let full = unsafe { mid.data[1].assume_init_ref() };
+    assert_eq!(full.presence_mask, u128::MAX);
+    let tail = unsafe { mid.data[3].assume_init_ref() };
+    assert_eq!(tail.presence_mask, (1u128 << 44) - 1);
+    assert_eq!(foos.get(427).map(|f| f.v), Some(299));
+    assert_eq!(world.get::<Baz>().borrow().get(200).map(|b| b.0), Some(72));
+    drop(foos);
+
+    // indices skipped by the alignment are recycled
+    assert_eq!(world.spawn_empty().0, first.0 + 1);
+}
+
+/// Reports more items than it yields.
+struct Overstated<I>(I, usize);
+
+impl<I: Iterator> Iterator for Overstated<I> {
+    type Item = I::Item;
+
+    fn next(&mut self) -> Option<I::Item> {
+        self.0.next()
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        (self.1, Some(self.1))
+    }
+}
+
+impl<I: Iterator> ExactSizeIterator for Overstated<I> {}
+
+#[test]
+fn spawn_batch_spawns_what_the_iterator_yields() {
+    let mut world = World::new();
+    let entities: Vec<_> = world.spawn_batch(Overstated((0..3u32).map(Baz), 200)).collect();
+    assert_eq!(entities.len(), 3);
+    assert_eq!(world.entities().len(), 3);
+    assert_eq!(world.get::<Baz>().borrow().len(), 3);
+    assert!(world.validate().is_empty());
+    // the unused indices went back to the allocator, and their reserved leaf with them
+    assert_eq!(world.spawn_empty().0, 3);
+    let baz = world.get::<Baz>();
+    assert_eq!(unsafe { baz.borrow().root.data[0].assume_init_ref().presence_mask }, 1);
+}
+
+#[test]
+fn spawn_batch_spans_l1_blocks() {
+    let mut world = World::new();
+    let batch = world.spawn_batch((0..40_000u32).map(Baz));
+    assert_eq!(batch.len(), 40_000);
+    assert_eq!(batch, EntityRange(0..40_000));
+    let baz = world.get::<Baz>();
+    let baz = baz.borrow();
+    assert_eq!(baz.len(), 40_000);
+    assert_eq!(baz.root.presence_mask, 0b111);
+    assert_eq!(baz.nth(16_384 + 5).map(|i| baz.get(i).unwrap().0), Some(16_389));
+    assert!(baz.validate().is_empty());
+}
+
+#[test]
+fn despawn_removes_every_component_and_recycles_the_index() {
+    let mut world = World::new();
+    let a = world.spawn(Unit { foo: Foo { v: 1 }, bar: Bar { name: "a" } });
+    let b = world.spawn(Foo { v: 2 });
+    world.transient::<Baz>().borrow_mut().insert(a.0, Baz(3));
+
+    assert!(world.despawn(a));
+    assert!(!world.despawn(a));
+    assert!(!world.get::<Foo>().borrow().contains(a.0));
+    assert!(!world.get::<Bar>().borrow().contains(a.0));
+    assert!(!world.transient::<Baz>().borrow().contains(a.0));
+    assert!(world.get::<Foo>().borrow().contains(b.0));
+    assert_eq!(world.spawn_empty(), a);
+}
+
+#[derive(Component)]
+#[component(init = Energy::full)]
+struct Energy(u32);
+
+impl Energy {
+    fn full(_index: u32) -> Self { Energy(100) }
+}
+
+#[test]
+fn recycled_indices_get_preinit_components_back() {
+    let mut world = World::new();
+    let a = world.spawn_empty();
+    let b = world.spawn_empty();
+    world.get::<Energy>().borrow_mut().get_or_init_mut(a.0).unwrap().0 = 5;
+    world.get::<Energy>().borrow_mut().remove(b.0);
+    assert!(world.despawn(b));
+    assert!(world.despawn(a));
+
+    // both slots are fresh again, whether the value was changed or removed
+    let again = [world.spawn_empty(), world.spawn_empty()];
+    assert!(again.contains(&a) && again.contains(&b));
+    let energy = world.get::<Energy>();
+    assert_eq!(energy.borrow().get(a.0).map(|e| e.0), Some(100));
+    assert_eq!(energy.borrow().get(b.0).map(|e| e.0), Some(100));
+    assert_eq!(energy.borrow().get(world.spawn_empty().0).map(|e| e.0), Some(100));
+    assert!(energy.borrow().validate().is_empty());
+}
+
+#[test]
+fn spawn_with_hints_places_related_entities_together() {
+    use crate::world::entity::{AllocHint, AllocPolicy};
+
+    let mut world = World::new();
+    world.entities_mut().set_policy(AllocPolicy::Compacting);
+    let squad = world.entities_mut().create_pool(1024..2048);
+    let ships: Vec<_> = (0..200).map(|i| world.spawn(Foo { v: i })).collect();
+    for ship in ships[..100].iter().chain(&ships[150..160]) {
+        world.despawn(*ship);
+    }
+    // Compacting refills the fuller leaf block (128..256) before the emptier one
+    assert_eq!(world.spawn(Foo { v: 0 }).0, 150);
+    let leader = world.spawn_with(AllocHint::Pool(squad), Foo { v: 1 });
+    assert_eq!(leader.0, 1024);
+    let wing = world.spawn_with(AllocHint::Near(leader), Foo { v: 2 });
+    assert_eq!(wing.0, 1025);
+}
+
+#[test]
+fn defragment_moves_components_and_frees_emptied_blocks() {
+    let mut world = World::new();
+    let all: Vec<_> = (0..1000).map(|i| world.spawn(Foo { v: i })).collect();
+    for e in all.iter().filter(|e| e.0 % 10 != 0) {
+        world.despawn(*e);
+    }
+    world.insert(all[990], Bar { name: "last" });
+    {
+        let foos = world.get::<Foo>();
+        let foos = foos.borrow();
+        let mid = unsafe { foos.root.data[0].assume_init_ref() };
+        assert_eq!(mid.presence_mask.count_ones(), 8);
+    }
+
+    let remap = world.defragment();
+    assert_eq!(remap.get(all[10]), crate::world::Entity(1));
+    let last = remap.get(all[990]);
+    assert_eq!(last.0, 99);
+
+    let foos = world.get::<Foo>();
+    let foos = foos.borrow();
+    for (i, e) in all.iter().enumerate().filter(|(i, _)| i % 10 == 0) {
+        assert_eq!(foos.get(remap.get(*e).0).map(|f| f.v), Some(i));
+    }
+    let mid = unsafe { foos.root.data[0].assume_init_ref() };
+    assert_eq!(mid.presence_mask, 1);
+    let leaf = unsafe { mid.data[0].assume_init_ref() };
+    assert_eq!(leaf.presence_mask, (1u128 << 100) - 1);
+    assert_eq!(world.get::<Bar>().borrow().get(last.0).map(|b| b.name), Some("last"));
+}
+
+#[test]
+fn flush_applies_commands_in_order_and_validates() {
+    let mut world = World::new();
+    world.set_validate_on_flush(true);
+    let a = world.spawn(Foo { v: 1 });
+    world.commands().insert(a, Bar { name: "a" });
+    world.commands().remove::<Foo>(a);
+    world.commands().push(move |world: &mut World| {
+        world.commands().despawn(a);
+    });
+    world.commands().spawn(Foo { v: 7 });
+    assert_eq!(world.commands().len(), 4);
+
+    world.flush();
+    assert!(world.commands().is_empty());
+    assert!(!world.entities().is_alive(a));
+    assert!(world.get::<Bar>().borrow().get(a.0).is_none());
+    assert!(world.validate().is_empty());
+}
+
+#[test]
+#[cfg(debug_assertions)]
+#[should_panic(expected = "storage invariants broken after flush")]
+fn flush_panics_on_corrupted_storage() {
+    let mut world = World::new();
+    world.set_validate_on_flush(true);
+    world.spawn(Foo { v: 1 });
+    world.get::<Foo>().borrow_mut().root.absence_mask = 1;
+    world.flush();
+}
+
+#[derive(Component)]
+struct Health(u32);
+
+#[derive(Component)]
+struct Enemy;
+
+#[derive(Component)]
+struct Dead;
+
+#[test]
+fn observers_report_net_enter_and_exit_per_flush() {
+    use std::cell::RefCell;
+    use crate::query::Query;
+    use crate::world::{Entity, Observer};
+
+    let mut world = World::new();
+    let baseline = world.spawn((Health(1), Enemy));
+    let log: Rc<RefCell<Vec<(&str, Vec<Entity>)>>> = Rc::default();
+    let (enter_log, exit_log) = (log.clone(), log.clone());
+    let query = Query::new().with::<Health>().with::<Enemy>().without::<Dead>();
+    let id = world.observe(
+        Observer::new(query)
+            .on_enter(move |_, entities, _| enter_log.borrow_mut().push(("enter", entities.to_vec())))
+            .on_exit(move |world, entities, commands| {
+                exit_log.borrow_mut().push(("exit", entities.to_vec()));
+                // clean up the corpses; the despawn is applied by the same flush
+                for &entity in entities {
+                    if world.entities().is_alive(entity) {
+                        commands.despawn(entity);
+                    }
+                }
+            }),
+    );
+
+    let a = world.spawn(Health(5));
+    world.commands().insert(a, Enemy);
+    let b = world.spawn((Health(2), Enemy));
+    world.commands().insert(b, Dead);
+    world.commands().remove::<Dead>(b);
+    world.flush();
+    // b entered and left the query within the flush but ends up matching again
+    assert_eq!(log.borrow().as_slice(), [("enter", vec![a, b])]);
+
+    log.borrow_mut().clear();
+    world.commands().insert(baseline, Dead);
+    world.flush();
+    assert_eq!(log.borrow().as_slice(), [("exit", vec![baseline])]);
+    assert!(!world.entities().is_alive(baseline));
+
+    log.borrow_mut().clear();
+    assert!(world.unobserve(id));
+    assert!(!world.unobserve(id));
+    world.despawn(a);
+    world.flush();
+    assert!(log.borrow().is_empty());
+}
+
+thread_local! {
+    static HOOK_LOG: std::cell::RefCell<Vec<String>> = const { std::cell::RefCell::new(Vec::new()) };
+}
+
+fn hook_log() -> Vec<String> {
+    HOOK_LOG.with(|log| std::mem::take(&mut *log.borrow_mut()))
+}
+
+#[derive(Component)]
+#[component(on_insert = label_inserted, on_replace = label_replaced, on_remove = label_removed)]
+struct Label(&'static str);
+
+fn label_inserted<A: std::alloc::Allocator + Copy + 'static, L: std::alloc::Allocator + Copy + 'static>(label: &mut Label, entity: crate::world::Entity, commands: &mut crate::world::Commands<A, L>) {
+    HOOK_LOG.with(|log| log.borrow_mut().push(format!("insert {} {}", label.0, entity.0)));
+    commands.insert(entity, Foo { v: label.0.len() });
+}
+
+fn label_replaced<A: std::alloc::Allocator + Copy + 'static, L: std::alloc::Allocator + Copy + 'static>(label: &mut Label, entity: crate::world::Entity, _: &mut crate::world::Commands<A, L>) {
+    HOOK_LOG.with(|log| log.borrow_mut().push(format!("replace {} {}", label.0, entity.0)));
+}
+
+fn label_removed<A: std::alloc::Allocator + Copy + 'static, L: std::alloc::Allocator + Copy + 'static>(label: &mut Label, entity: crate::world::Entity, commands: &mut crate::world::Commands<A, L>) {
+    HOOK_LOG.with(|log| log.borrow_mut().push(format!("remove {} {}", label.0, entity.0)));
+    commands.remove::<Foo>(entity);
+}
+
+#[test]
+fn component_hooks_queue_commands_for_flush() {
+    use crate::component::Component;
+    const { assert!(Label::HOOKS && !Foo::HOOKS) };
+
+    let mut world = World::new();
+    let e = world.spawn(Label("a"));
+    assert_eq!(hook_log(), ["insert a 0"]);
+    assert!(world.get::<Foo>().borrow().get(e.0).is_none());
+    world.flush();
+    assert_eq!(world.get::<Foo>().borrow().get(e.0).map(|f| f.v), Some(1));
+
+    world.insert(e, Label("bb"));
+    assert_eq!(hook_log(), ["replace a 0", "insert bb 0"]);
+    world.flush();
+    assert_eq!(world.get::<Foo>().borrow().get(e.0).map(|f| f.v), Some(2));
+
+    assert!(world.remove::<Label>(e).is_some());
+    assert_eq!(hook_log(), ["remove bb 0"]);
+    world.flush();
+    assert!(world.get::<Foo>().borrow().get(e.0).is_none());
+
+    // batches run the hooks when a leaf block is committed
+    let batch: Vec<_> = world.spawn_batch(["x", "y"].map(Label)).collect();
+    assert_eq!(hook_log(), [format!("insert x {}", batch[0].0), format!("insert y {}", batch[1].0)]);
+    world.despawn(batch[0]);
+    assert_eq!(hook_log(), [format!("remove x {}", batch[0].0)]);
+
+    // defragmenting moves values without running hooks
+    let moved = world.spawn(Label("z"));
+    world.despawn(e);
+    hook_log();
+    let remap = world.defragment();
+    assert_ne!(remap.get(moved), moved);
+    assert!(hook_log().is_empty());
+    world.despawn(remap.get(moved));
+    hook_log();
+
+    // values still stored when the storage goes away are removed too
+    drop(world);
+    assert_eq!(hook_log(), [format!("remove y {}", remap.get(batch[1]).0)]);
+}
+
+#[test]
+fn hierarchy_traversals_and_reparenting() {
+    use crate::world::{Children, Traversal};
+
+    //      root
+    //     /    \
+    //    a      b
+    //   / \     |
+    //  c   d    e
+    let mut world = World::new();
+    let [root, a, b, c, d, e] = std::array::from_fn(|_| world.spawn_empty());
+    for (child, parent) in [(a, root), (b, root), (c, a), (d, a), (e, b)] {
+        world.set_parent(child, parent);
+    }
+    assert_eq!(world.children(root), [a, b]);
+    assert_eq!(world.descendants(root, Traversal::DepthFirst), [a, c, d, b, e]);
+    assert_eq!(world.descendants(root, Traversal::BreadthFirst), [a, b, c, d, e]);
+    assert_eq!(world.ancestors(d), [a, root]);
+
+    world.set_parent(a, e);
+    assert_eq!(world.parent(a), Some(e));
+    assert_eq!(world.children(root), [b]);
+    assert_eq!(world.descendants(b, Traversal::DepthFirst), [e, a, c, d]);
+    assert_eq!(world.remove_parent(e), Some(b));
+    // empty lists are removed rather than left behind
+    assert!(world.get::<Children>().borrow().get(b.0).is_none());
+    assert_eq!(world.descendants(root, Traversal::BreadthFirst), [b]);
+}
+
+#[test]
+#[should_panic(expected = "cannot become a child of its descendant")]
+fn set_parent_rejects_cycles() {
+    let mut world = World::new();
+    let a = world.spawn_empty();
+    let b = world.spawn_empty();
+    world.set_parent(b, a);
+    world.set_parent(a, b);
+}
+
+#[test]
+fn despawn_cascades_to_descendants() {
+    use crate::world::Traversal;
+
+    let mut world = World::new();
+    let root = world.spawn(Foo { v: 0 });
+    let a = world.spawn(Foo { v: 1 });
+    let b = world.spawn(Foo { v: 2 });
+    let c = world.spawn(Foo { v: 3 });
+    world.set_parent(a, root);
+    world.set_parent(b, a);
+    world.set_parent(c, root);
+
+    assert!(world.despawn(a));
+    assert!(!world.entities().is_alive(a) && !world.entities().is_alive(b));
+    assert!(world.get::<Foo>().borrow().get(b.0).is_none());
+    assert_eq!(world.children(root), [c]);
+
+    assert!(world.despawn(root));
+    assert!(!world.entities().is_alive(c));
+    assert!(world.descendants(root, Traversal::DepthFirst).is_empty());
+    assert!(world.validate().is_empty());
+}
+
+#[test]
+fn despawn_skips_stale_hierarchy_entries() {
+    use crate::world::ChildOf;
+
+    let mut world = World::new();
+    let plain = world.spawn(Foo { v: 0 });
+    world.despawn(plain);
+    // worlds without a hierarchy do not get one from despawning
+    assert!(world.storage::<ChildOf>().is_none());
+
+    let [a, b, c, d] = std::array::from_fn(|_| world.spawn_empty());
+    world.set_parent(b, a);
+    // overwriting `ChildOf` directly leaves `b` in the children of `a`
+    world.insert(b, ChildOf(c));
+    world.set_parent(b, d);
+    world.set_parent(d, a);
+    assert!(world.despawn(a));
+    assert_eq!(world.entities().len(), 1);
+    assert!(world.entities().is_alive(c));
+}
+
+#[test]
+fn defragment_rewrites_hierarchy_handles() {
+    use crate::world::Traversal;
+
+    let mut world = World::new();
+    let filler: Vec<_> = (0..10).map(|_| world.spawn_empty()).collect();
+    let parent = world.spawn_empty();
+    let kids: Vec<_> = (0..3).map(|_| world.spawn_empty()).collect();
+    for &kid in &kids {
+        world.set_parent(kid, parent);
+    }
+    let grandchild = world.spawn_empty();
+    world.set_parent(grandchild, kids[1]);
+    for e in filler {
+        world.despawn(e);
+    }
+
+    let remap = world.defragment();
+    let parent = remap.get(parent);
+    let kids: Vec<_> = kids.into_iter().map(|k| remap.get(k)).collect();
+    let grandchild = remap.get(grandchild);
+    assert!(parent.0 < 10);
+    assert_eq!(world.children(parent), kids);
+    assert_eq!(world.parent(grandchild), Some(kids[1]));
+    assert_eq!(world.descendants(parent, Traversal::DepthFirst), [kids[0], kids[1], grandchild, kids[2]]);
+}
+
+struct Likes;
+impl crate::world::Relation for Likes {}
+
+struct Targets;
+impl crate::world::Relation for Targets {}
+
+#[test]
+fn relations_index_both_directions() {
+    use crate::query::Query;
+
+    let mut world = World::new();
+    let [a, b, c, x, y] = std::array::from_fn(|i| world.spawn(Foo { v: i }));
+    assert!(world.relate::<Targets>(a, x));
+    assert!(world.relate::<Targets>(b, x));
+    assert!(world.relate::<Targets>(b, y));
+    assert!(!world.relate::<Targets>(b, y));
+    world.relate::<Likes>(c, x);
+
+    let targeting_x = world.related_to::<Targets>(x);
+    assert_eq!(targeting_x.iter().collect::<Vec<_>>(), [a, b]);
+    assert_eq!(world.targets_of::<Targets>(b), [x, y]);
+    let targets = world.relations::<Targets>();
+    assert_eq!(targets.borrow().pairs().collect::<Vec<_>>(), [(a, x), (b, x), (b, y)]);
+    assert_eq!(targets.borrow().sources().len(), 2);
+    // relation sets filter queries like any other entity set
+    assert_eq!(Query::new().with::<Foo>().within(targeting_x.clone()).count(&world), 2);
+
+    assert!(world.unrelate::<Targets>(a, x));
+    assert!(!world.unrelate::<Targets>(a, x));
+    // sets handed out earlier are snapshots
+    assert_eq!(targeting_x.len(), 2);
+    assert_eq!(world.related_to::<Targets>(x).iter().collect::<Vec<_>>(), [b]);
+
+    // despawning a target drops the pairs pointing at it, in every relation kind
+    world.despawn(x);
+    assert!(targets.borrow().pairs().eq([(b, y)]));
+    assert!(world.relations::<Likes>().borrow().is_empty());
+    assert!(world.related_to::<Targets>(x).is_empty());
+}
+
+#[test]
+fn relation_lookups_take_a_shared_world() {
+    let mut world = World::new();
+    let a = world.spawn(Foo { v: 0 });
+    let world = &world;
+    assert!(world.related_to::<Likes>(a).is_empty());
+    assert!(world.targets_of::<Likes>(a).is_empty());
+    // looking up does not create the relation
+    assert!(world.find_relations::<Likes>().is_none());
+}
+
+#[test]
+fn defragment_rewrites_relation_pairs() {
+    let mut world = World::new();
+    let filler: Vec<_> = (0..8).map(|_| world.spawn_empty()).collect();
+    let ship = world.spawn_empty();
+    let station = world.spawn_empty();
+    world.relate::<Likes>(ship, station);
+    world.relate::<Likes>(station, filler[0]);
+    world.relate::<Likes>(filler[0], ship);
+    world.relate::<Likes>(filler[0], filler[1]);
+    world.relate::<Likes>(filler[0], station);
+    // everything above filler[2] shifts down by one, the station into the ship's old slot
+    world.despawn(filler[2]);
+    let untouched = world.related_to::<Likes>(filler[1]);
+
+    let remap = world.defragment();
+    let (ship, station) = (remap.get(ship), remap.get(station));
+    assert_eq!((ship.0, station.0), (7, 8));
+    assert_eq!(world.targets_of::<Likes>(ship), [station]);
+    assert_eq!(world.related_to::<Likes>(station).iter().collect::<Vec<_>>(), [filler[0], ship]);
+    assert_eq!(world.targets_of::<Likes>(station), [filler[0]]);
+    // targets keep their order, and pairs of entities that did not move are left alone
+    assert_eq!(world.targets_of::<Likes>(filler[0]), [ship, filler[1], station]);
+    assert!(Rc::ptr_eq(&untouched, &world.related_to::<Likes>(filler[1])));
+    assert_eq!(world.relations::<Likes>().borrow().len(), 5);
+}
@@ -1,28 +1,574 @@
+use std::alloc::{AllocError, Allocator, Layout, handle_alloc_error};
 use std::any::{Any, TypeId};
 use std::cell::RefCell;
 use std::collections::HashMap;
+use std::ptr::NonNull;
 use std::rc::Rc;
 
-use crate::component::Component;
-use crate::storage::storage::SparseStorage;
+use bumpalo::Bump;
+
+use crate::component::{Bundle, Component};
+use crate::query::stats::{Instrumentation, TickStats};
+use crate::storage::pool::{BlockPool, PoolHandle};
+use crate::storage::storage::{SparseStorage, Storage};
+use crate::storage::transient::{TickArena, Transient, TransientStorage};
+use crate::storage::validate::StorageViolation;
+use crate::tick::{Tick, TickDelta};
+use crate::world::{Commands, Relation, RelationIndex, Relations, Traversal, EventQueue, EventReader, EventWriter, Events, Observer, ObserverId};
+use crate::world::entity::{AllocHint, Entities, Entity, EntityRange, EntityRemap};
 use std::alloc::Global;
 
-pub struct World {
+/// Component storages keyed by type. Storages are created on first access with the
+/// world allocators, `A` for the inner blocks and `L` for the leaf blocks, unless
+/// `register_in` installed per-component allocators first.
+pub struct World<A: Allocator + Copy + 'static = Global, L: Allocator + Copy + 'static = A> {
     storages: HashMap<TypeId, Box<dyn Any>>,
+    entities: Entities,
+    alloc: A,
+    leaf_alloc: L,
+    /// Pools handed to `own_pool`, freed once no storage can reach them anymore.
+    pools: Vec<PoolHandle>,
+    tick: Tick,
+    /// Per-tick arena, owned through this pointer (from `Box::into_raw`) so the
+    /// `TickArena` handles copied from it stay valid across resets.
+    arena: NonNull<Bump>,
+    transient_storages: HashMap<TypeId, Box<dyn Any>>,
+    transients: Vec<Box<dyn Transient>>,
+    erased: Vec<Rc<RefCell<dyn Storage>>>,
+    by_type: HashMap<TypeId, Rc<RefCell<dyn Storage>>>,
+    commands: Commands<A, L>,
+    /// Filled by component lifecycle hooks, drained by `flush`.
+    hooks: Rc<RefCell<Commands<A, L>>>,
+    validate_on_flush: bool,
+    observers: Vec<(ObserverId, Observer<A, L>)>,
+    next_observer: u32,
+    events: HashMap<TypeId, Box<dyn Any>>,
+    event_queues: Vec<Box<dyn EventQueue>>,
+    relations: HashMap<TypeId, Box<dyn Any>>,
+    relation_indices: Vec<Box<dyn RelationIndex>>,
+    instrumentation: Rc<RefCell<Instrumentation>>,
 }
+
 impl World {
     pub fn new() -> Self {
-        Self { storages: HashMap::new() }
+        Self::with_allocator(Global)
+    }
+}
+
+impl<A: Allocator + Copy + 'static> World<A> {
+    pub fn with_allocator(alloc: A) -> Self {
+        Self::with_allocators(alloc, alloc)
+    }
+}
+
+impl World<PoolHandle> {
+    /// World whose inner blocks come from `inner` and leaf blocks from `leaves`. The world
+    /// owns both pools.
+    pub fn with_pools(inner: BlockPool, leaves: BlockPool) -> Self {
+        let (inner, leaves) = (PoolHandle::new(inner), PoolHandle::new(leaves));
+        let mut world = Self::with_allocators(inner, leaves);
+        world.pools.extend([inner, leaves]);
+        world
+    }
+}
+
+impl<A: Allocator + Copy + 'static, L: Allocator + Copy + 'static> World<A, L> {
+    /// World whose storages take their root and L1 blocks from `alloc` and their leaf
+    /// blocks from `leaf_alloc`.
+    pub fn with_allocators(alloc: A, leaf_alloc: L) -> Self {
+        Self {
+            storages: HashMap::new(),
+            entities: Entities::new(),
+            alloc,
+            leaf_alloc,
+            pools: Vec::new(),
+            tick: Tick::new(0),
+            arena: NonNull::new(Box::into_raw(Box::new(Bump::new()))).unwrap(),
+            transient_storages: HashMap::new(),
+            transients: Vec::new(),
+            erased: Vec::new(),
+            by_type: HashMap::new(),
+            commands: Commands::new(),
+            hooks: Rc::default(),
+            validate_on_flush: false,
+            observers: Vec::new(),
+            next_observer: 0,
+            events: HashMap::new(),
+            event_queues: Vec::new(),
+            relations: HashMap::new(),
+            relation_indices: Vec::new(),
+            instrumentation: Rc::default(),
+        }
+    }
+
+    pub fn tick(&self) -> Tick {
+        self.tick
     }
 
-    pub fn get<T: Component + Default + 'static>(&mut self) -> Rc<RefCell<SparseStorage<T, Global>>> {
+    pub fn allocator(&self) -> A {
+        self.alloc
+    }
+
+    pub fn leaf_allocator(&self) -> L {
+        self.leaf_alloc
+    }
+
+    /// Hand `pool` to the world, for `register_in`. The returned handle stays valid for
+    /// as long as the world or any of its storages is alive.
+    pub fn own_pool(&mut self, pool: BlockPool) -> PoolHandle {
+        let handle = PoolHandle::new(pool);
+        self.pools.push(handle);
+        handle
+    }
+
+    pub fn get<T: Component>(&mut self) -> Rc<RefCell<SparseStorage<T, A, L>>> {
+        if let Some(entry) = self.storages.get(&TypeId::of::<T>()) {
+            return entry
+                .downcast_ref::<Rc<RefCell<SparseStorage<T, A, L>>>>()
+                .expect("World storage has wrong type")
+                .clone();
+        }
+        let storage = Rc::new(RefCell::new(SparseStorage::<T, A, L>::new_in(self.alloc, self.leaf_alloc)));
+        storage.borrow_mut().set_hooks(self.hooks.clone());
+        self.storages.insert(TypeId::of::<T>(), Box::new(storage.clone()));
+        self.erased.push(storage.clone());
+        self.by_type.insert(TypeId::of::<T>(), storage.clone());
+        storage
+    }
+
+    /// Storage for `T` if it has been created; unlike `get` this never creates one.
+    pub fn storage<T: Component>(&self) -> Option<Rc<RefCell<SparseStorage<T, A, L>>>> {
+        self.storages.get(&TypeId::of::<T>()).map(|entry| {
+            entry
+                .downcast_ref::<Rc<RefCell<SparseStorage<T, A, L>>>>()
+                .expect("World storage has wrong type")
+                .clone()
+        })
+    }
+
+    /// Type-erased storage of the component with `type_id`, if it has been created.
+    /// Transient storages are not included.
+    pub fn storage_dyn(&self, type_id: TypeId) -> Option<Rc<RefCell<dyn Storage>>> {
+        self.by_type.get(&type_id).cloned()
+    }
+
+    pub fn entities(&self) -> &Entities {
+        &self.entities
+    }
+
+    /// Allocator state, e.g. to switch `AllocPolicy` or reserve pools.
+    pub fn entities_mut(&mut self) -> &mut Entities {
+        &mut self.entities
+    }
+
+    /// Per-tick storage for `T` whose blocks live in the world arena.
+    /// Everything in it is dropped by `end_tick`.
+    pub fn transient<T: Component>(&mut self) -> Rc<RefCell<TransientStorage<T>>> {
         let type_id = TypeId::of::<T>();
-        let entry = self.storages
-            .entry(type_id)
-            .or_insert_with(|| Box::new(Rc::new(RefCell::new(SparseStorage::<T, Global>::default()))));
-        let typed: &Rc<RefCell<SparseStorage<T, Global>>> = entry
-            .downcast_ref::<Rc<RefCell<SparseStorage<T, Global>>>>()
-            .expect("World storage has wrong type");
-        typed.clone()
+        if let Some(entry) = self.transient_storages.get(&type_id) {
+            return entry
+                .downcast_ref::<Rc<RefCell<TransientStorage<T>>>>()
+                .expect("World transient storage has wrong type")
+                .clone();
+        }
+        // the arena stays at one address until the world is dropped
+        let arena = unsafe { TickArena::new(self.arena) };
+        let storage = Rc::new(RefCell::new(TransientStorage::<T>::new(arena)));
+        storage.borrow_mut().set_hooks(self.hooks.clone());
+        self.transient_storages.insert(type_id, Box::new(storage.clone()));
+        self.transients.push(Box::new(storage.clone()));
+        self.erased.push(storage.clone());
+        storage
+    }
+
+    /// Event queue for `E`, created on first access.
+    pub fn events<E: 'static>(&mut self) -> Rc<RefCell<Events<E>>> {
+        let type_id = TypeId::of::<E>();
+        if let Some(entry) = self.events.get(&type_id) {
+            return entry
+                .downcast_ref::<Rc<RefCell<Events<E>>>>()
+                .expect("World event queue has wrong type")
+                .clone();
+        }
+        let events = Rc::new(RefCell::new(Events::<E>::new()));
+        self.events.insert(type_id, Box::new(events.clone()));
+        self.event_queues.push(Box::new(events.clone()));
+        events
+    }
+
+    pub fn send<E: 'static>(&mut self, event: E) {
+        self.events::<E>().borrow_mut().send(event);
+    }
+
+    pub fn event_writer<E: 'static>(&mut self) -> EventWriter<E> {
+        EventWriter::new(self.events::<E>())
+    }
+
+    /// Reader that starts with the events still buffered.
+    pub fn event_reader<E: 'static>(&mut self) -> EventReader<E> {
+        EventReader::new(self.events::<E>())
+    }
+
+    /// Pairs of relation kind `R`, created on first access. Despawning an entity drops
+    /// the pairs it takes part in and `defragment` rewrites them.
+    pub fn relations<R: Relation>(&mut self) -> Rc<RefCell<Relations<R, A, L>>> {
+        if let Some(relations) = self.find_relations::<R>() {
+            return relations;
+        }
+        let relations = Rc::new(RefCell::new(Relations::<R, A, L>::new_in(self.alloc, self.leaf_alloc)));
+        self.relations.insert(TypeId::of::<R>(), Box::new(relations.clone()));
+        self.relation_indices.push(Box::new(relations.clone()));
+        relations
+    }
+
+    /// Pairs of relation kind `R`, or `None` if nothing has related through `R` yet.
+    pub fn find_relations<R: Relation>(&self) -> Option<Rc<RefCell<Relations<R, A, L>>>> {
+        self.relations.get(&TypeId::of::<R>()).map(|entry| {
+            entry
+                .downcast_ref::<Rc<RefCell<Relations<R, A, L>>>>()
+                .expect("World relation has wrong type")
+                .clone()
+        })
+    }
+
+    /// Count the block walks of this world's `#[system]`s, per system; see `take_stats`.
+    pub fn instrument(&self, enabled: bool) {
+        self.instrumentation.borrow_mut().set_enabled(enabled);
+    }
+
+    /// Collector the world's systems record into.
+    pub fn instrumentation(&self) -> Rc<RefCell<Instrumentation>> {
+        self.instrumentation.clone()
+    }
+
+    /// Stats recorded by this world's systems since the last call, labeled with the
+    /// current tick. Called right before `end_tick` it reports one tick at a time.
+    pub fn take_stats(&self) -> TickStats {
+        self.instrumentation.borrow_mut().end_tick(self.tick)
+    }
+
+    /// Close the current tick: empty every transient storage, reset the arena
+    /// in one step, rotate the event queues and advance the world tick.
+    pub fn end_tick(&mut self) {
+        for queue in &self.event_queues {
+            queue.update();
+        }
+        for transient in &self.transients {
+            transient.release();
+        }
+        // every block in the arena was released above; reset through the owning pointer,
+        // never a fresh borrow that would retire the handles'
+        unsafe { (*self.arena.as_ptr()).reset() };
+        self.tick = self.tick + TickDelta::new(1);
+    }
+
+    /// Create the storage for `T` with its own allocators: `alloc` for the root and
+    /// L1 blocks, `leaf_alloc` for the leaf blocks holding the components.
+    /// Panics if the storage already exists.
+    pub fn register_in<T: Component>(&mut self, alloc: A, leaf_alloc: L) -> Rc<RefCell<SparseStorage<T, A, L>>> {
+        assert!(
+            !self.storages.contains_key(&TypeId::of::<T>()),
+            "storage for {} already exists",
+            std::any::type_name::<T>()
+        );
+        let storage = Rc::new(RefCell::new(SparseStorage::<T, A, L>::new_in(alloc, leaf_alloc)));
+        storage.borrow_mut().set_hooks(self.hooks.clone());
+        self.storages.insert(TypeId::of::<T>(), Box::new(storage.clone()));
+        self.erased.push(storage.clone());
+        self.by_type.insert(TypeId::of::<T>(), storage.clone());
+        storage
+    }
+
+    /// The world's own command queue, applied by `flush`.
+    pub fn commands(&mut self) -> &mut Commands<A, L> {
+        &mut self.commands
+    }
+
+    /// Apply queued commands in order, including ones pushed while flushing or queued by
+    /// component hooks, then run the observers. Commands they queue are applied and
+    /// observed in turn until none are left.
+    pub fn flush(&mut self) {
+        loop {
+            loop {
+                self.commands.append(&mut self.hooks.borrow_mut());
+                let Some(command) = self.commands.pop() else { break };
+                command(self);
+            }
+            let mut observers = std::mem::take(&mut self.observers);
+            let mut commands = std::mem::take(&mut self.commands);
+            for (_, observer) in &mut observers {
+                observer.notify(self, &mut commands);
+            }
+            self.observers = observers;
+            self.commands = commands;
+            if self.commands.is_empty() {
+                break;
+            }
+        }
+        if cfg!(debug_assertions) && self.validate_on_flush {
+            let violations = self.validate();
+            if !violations.is_empty() {
+                let report: Vec<String> = violations.iter().map(|v| v.to_string()).collect();
+                panic!("storage invariants broken after flush:\n{}", report.join("\n"));
+            }
+        }
+    }
+
+    /// Register an observer. Entities matching its query now are the baseline: only
+    /// changes from here on are reported, at the next `flush`.
+    pub fn observe(&mut self, mut observer: Observer<A, L>) -> ObserverId {
+        observer.prime(self);
+        let id = ObserverId(self.next_observer);
+        self.next_observer += 1;
+        self.observers.push((id, observer));
+        id
+    }
+
+    /// Returns false if there is no such observer.
+    pub fn unobserve(&mut self, id: ObserverId) -> bool {
+        let before = self.observers.len();
+        self.observers.retain(|(other, _)| *other != id);
+        self.observers.len() != before
+    }
+
+    /// Queue `commands` behind the world's own and `flush`.
+    pub fn apply(&mut self, commands: &mut Commands<A, L>) {
+        self.commands.append(commands);
+        self.flush();
+    }
+
+    /// Run `validate` after every `flush` and panic on the first violation.
+    /// Only honoured in debug builds.
+    pub fn set_validate_on_flush(&mut self, on: bool) {
+        self.validate_on_flush = on;
+    }
+
+    /// Walk every storage tree, including transient ones, and collect broken invariants.
+    pub fn validate(&self) -> Vec<StorageViolation> {
+        let mut out = Vec::new();
+        for storage in &self.erased {
+            let storage = storage.borrow();
+            out.extend(storage.validate().into_iter().map(|violation| StorageViolation { storage: storage.name(), violation }));
+        }
+        out
+    }
+
+    /// Allocate an entity without any components.
+    pub fn spawn_empty(&mut self) -> Entity {
+        self.entities.alloc()
+    }
+
+    /// Remove every component of `entity` and release its index, detaching it from its
+    /// parent and despawning its descendants, deepest first.
+    /// Returns false if the entity was not alive.
+    pub fn despawn(&mut self, entity: Entity) -> bool {
+        if !self.entities.is_alive(entity) {
+            return false;
+        }
+        self.remove_parent(entity);
+        let mut doomed = self.descendants(entity, Traversal::DepthFirst);
+        doomed.insert(0, entity);
+        for &entity in doomed.iter().rev() {
+            // a stale `ChildOf` can list an entity twice or one that is already gone
+            if !self.entities.is_alive(entity) {
+                continue;
+            }
+            for storage in &self.erased {
+                storage.borrow_mut().discard(entity.0);
+            }
+            for relations in &self.relation_indices {
+                relations.forget(entity);
+            }
+            self.entities.free(entity);
+        }
+        true
+    }
+
+    /// Keep the entity and its components but move it into the absence mask of every
+    /// storage, so queries pass over it unless they include disabled entities.
+    /// Returns false if the entity is dead or already disabled.
+    pub fn disable(&mut self, entity: Entity) -> bool {
+        if !self.entities.disable(entity) {
+            return false;
+        }
+        self.skip_everywhere(entity);
+        true
+    }
+
+    /// Undo `disable`. Returns false if the entity was not disabled.
+    pub fn enable(&mut self, entity: Entity) -> bool {
+        if !self.entities.enable(entity) {
+            return false;
+        }
+        for storage in &self.erased {
+            storage.borrow_mut().unskip(entity.0);
+        }
+        true
+    }
+
+    /// Move every live entity, with all of its components in every storage, into dense
+    /// index ranges so views see long runs again. Blocks emptied by the moves are freed.
+    /// The returned remap translates handles kept outside the world.
+    pub fn defragment(&mut self) -> EntityRemap {
+        let remap = self.entities.defragment();
+        for storage in &self.erased {
+            let mut storage = storage.borrow_mut();
+            for &(old, new) in remap.moves() {
+                storage.relocate(old.0, new.0);
+            }
+        }
+        self.remap_hierarchy(&remap);
+        for relations in &self.relation_indices {
+            relations.remap(&remap);
+        }
+        remap
+    }
+
+    /// Spawn an entity with every component of `bundle`.
+    pub fn spawn<B: Bundle>(&mut self, bundle: B) -> Entity {
+        match self.try_spawn(bundle) {
+            Ok(entity) => entity,
+            Err(AllocError) => handle_alloc_error(Layout::new::<B>()),
+        }
+    }
+
+    /// Fallible `spawn`: on `AllocError` the entity index is released again
+    /// and no storage mask is modified.
+    pub fn try_spawn<B: Bundle>(&mut self, bundle: B) -> Result<Entity, AllocError> {
+        self.try_spawn_with(AllocHint::Any, bundle)
+    }
+
+    /// Spawn on an index chosen by `hint`, e.g. next to a related entity or inside a pool.
+    pub fn spawn_with<B: Bundle>(&mut self, hint: AllocHint, bundle: B) -> Entity {
+        match self.try_spawn_with(hint, bundle) {
+            Ok(entity) => entity,
+            Err(AllocError) => handle_alloc_error(Layout::new::<B>()),
+        }
+    }
+
+    pub fn try_spawn_with<B: Bundle>(&mut self, hint: AllocHint, bundle: B) -> Result<Entity, AllocError> {
+        let entity = self.entities.alloc_with(hint);
+        let storages = B::fetch(self);
+        if let Err(err) = B::try_reserve(&storages, entity.0) {
+            self.entities.free(entity);
+            return Err(err);
+        }
+        bundle.write(&storages, entity.0);
+        unsafe { B::commit(&storages, entity.0, 1u128 << (entity.0 & 127)) };
+        Ok(entity)
+    }
+
+    /// Spawn one entity per item on fresh indices aligned to leaf block boundaries.
+    /// Leaf blocks are filled in bulk and every L1 block gets a single presence update per
+    /// storage. Indices are allocated up front from `len()`; items past it are left in the
+    /// iterator, and indices an iterator ending early did not use are released.
+    pub fn spawn_batch<B, I>(&mut self, iter: I) -> EntityRange
+    where
+        B: Bundle,
+        I: IntoIterator<Item = B>,
+        I::IntoIter: ExactSizeIterator,
+    {
+        let mut items = iter.into_iter();
+        let count = u32::try_from(items.len()).expect("spawn_batch of more than u32::MAX entities");
+        let range = self.entities.alloc_aligned(count);
+        let storages = B::fetch(self);
+        B::reserve_range(&storages, range.clone());
+
+        let mut end = range.start;
+        while end < range.end {
+            let count = (range.end - end).min(128) as usize;
+            let written = B::write_block(&storages, end, 0..count, &mut items);
+            end += written as u32;
+            if written < count {
+                break;
+            }
+        }
+        unsafe { B::commit_range(&storages, range.start..end) };
+        if end < range.end {
+            let mut base = end & !127;
+            while base < range.end {
+                B::trim(&storages, base);
+                base += 128;
+            }
+            self.entities.free_range(end..range.end);
+        }
+        EntityRange(range.start..end)
+    }
+
+    /// Insert every component of `bundle`, overwriting components the entity already has.
+    pub fn insert_bundle<B: Bundle>(&mut self, entity: Entity, bundle: B) {
+        let storages = B::fetch(self);
+        if B::try_reserve(&storages, entity.0).is_err() {
+            handle_alloc_error(Layout::new::<B>());
+        }
+        bundle.write(&storages, entity.0);
+        unsafe { B::commit(&storages, entity.0, 1u128 << (entity.0 & 127)) };
+        if self.entities.is_disabled(entity) {
+            self.skip_everywhere(entity);
+        }
+    }
+
+    /// Remove every component of `B`; returns the bundle if the entity had all of them.
+    pub fn remove_bundle<B: Bundle>(&mut self, entity: Entity) -> Option<B> {
+        let storages = B::fetch(self);
+        B::take(&storages, entity.0)
+    }
+
+    /// Components inserted on a disabled entity are disabled with it.
+    pub fn insert<T: Component>(&mut self, entity: Entity, value: T) -> Option<T> {
+        let storage = self.get::<T>();
+        let mut storage = storage.borrow_mut();
+        let prev = storage.insert(entity.0, value);
+        if self.entities.is_disabled(entity) {
+            storage.skip(entity.0);
+        }
+        prev
+    }
+
+    pub fn try_insert<T: Component>(&mut self, entity: Entity, value: T) -> Result<Option<T>, AllocError> {
+        let storage = self.get::<T>();
+        let mut storage = storage.borrow_mut();
+        let prev = storage.try_insert(entity.0, value)?;
+        if self.entities.is_disabled(entity) {
+            storage.skip(entity.0);
+        }
+        Ok(prev)
+    }
+
+    pub fn remove<T: Component>(&mut self, entity: Entity) -> Option<T> {
+        self.get::<T>().borrow_mut().remove(entity.0)
+    }
+
+    fn skip_everywhere(&mut self, entity: Entity) {
+        for storage in &self.erased {
+            storage.borrow_mut().skip(entity.0);
+        }
+    }
+}
+
+impl<A: Allocator + Copy + 'static, L: Allocator + Copy + 'static> Drop for World<A, L> {
+    fn drop(&mut self) {
+        for transient in &self.transients {
+            transient.release();
+        }
+        // a transient storage that outlives the world still points into the arena
+        if !self.transients.iter().any(|t| t.is_shared()) {
+            drop(unsafe { Box::from_raw(self.arena.as_ptr()) });
+        }
+        if self.pools.is_empty() {
+            return;
+        }
+        // drop the storages before the pools they allocate from; one that outlives the
+        // world keeps the pools alive for good
+        let erased: Vec<_> = self.erased.drain(..).map(|storage| Rc::downgrade(&storage)).collect();
+        self.storages.clear();
+        self.by_type.clear();
+        self.relations.clear();
+        self.relation_indices.clear();
+        if erased.iter().all(|storage| storage.strong_count() == 0) {
+            for pool in self.pools.drain(..) {
+                unsafe { pool.free() };
+            }
+        }
     }
 }